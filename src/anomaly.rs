@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use tracing::debug;
+use crate::config::AnomalyConfig;
+use crate::diff::TreeChanges;
+
+/// Bytes read from the start of a file to estimate its entropy
+const ENTROPY_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Files with less sampled content are too small to judge
+const MIN_ENTROPY_SAMPLE: usize = 4096;
+
+/// Entropy (bits per byte) above which content looks encrypted
+const HIGH_ENTROPY: f64 = 7.5;
+
+/// Extensions of formats that are compressed or encrypted by design
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "bz2", "cab", "deb", "docx", "flac", "gif", "gpg", "gz", "heic",
+    "iso", "jar", "jpeg", "jpg", "lz4", "m4a", "mkv", "mov", "mp3", "mp4", "odp", "ods",
+    "odt", "ogg", "pdf", "png", "pptx", "rar", "rpm", "tgz", "webm", "webp", "xlsx", "xz",
+    "zip", "zst",
+];
+
+/// Compiles ransom note file name patterns; matching ignores case
+pub fn build_note_patterns(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(GlobBuilder::new(pattern).case_insensitive(true).build()?);
+    }
+    Ok(builder.build()?)
+}
+
+/// What the checks found in the changes of one run
+#[derive(Debug, Default)]
+pub struct AnomalyReport {
+    /// Files of the source before this run
+    pub previous_files: u64,
+    /// Modified, deleted and renamed files
+    pub changed: u64,
+    /// New and modified files whose entropy was measured, and how many looked encrypted
+    pub sampled: u64,
+    pub high_entropy: u64,
+    pub ransom_notes: Vec<PathBuf>,
+    /// (old path, new path) of files replaced by a file with another extension
+    pub extension_changes: Vec<(PathBuf, PathBuf)>,
+    /// Checks over their threshold; the snapshot is suspicious if this is not empty
+    pub findings: Vec<String>,
+}
+
+impl AnomalyReport {
+    pub fn is_suspicious(&self) -> bool {
+        !self.findings.is_empty()
+    }
+}
+
+/// Looks for the footprint of ransomware in the changes since the previous snapshot:
+/// many files changed at once, new content that looks encrypted, ransom notes and
+/// files reappearing under another extension
+pub struct AnomalyDetector<'a> {
+    config: &'a AnomalyConfig,
+    notes: GlobSet,
+}
+
+impl<'a> AnomalyDetector<'a> {
+    pub fn new(config: &'a AnomalyConfig) -> Result<Self> {
+        Ok(Self { config, notes: build_note_patterns(&config.ransom_note_patterns)? })
+    }
+
+    /// Checks `changes`, relative to `root`, against a previous snapshot of `previous_files` files
+    pub fn check(&self, root: &Path, changes: &TreeChanges, previous_files: u64) -> AnomalyReport {
+        let mut report = AnomalyReport {
+            previous_files,
+            changed: (changes.modified.len() + changes.deleted.len() + changes.renamed.len()) as u64,
+            ..AnomalyReport::default()
+        };
+
+        let new_paths = changes.added.iter().chain(changes.renamed.iter().map(|(_, to)| to));
+        report.ransom_notes = new_paths
+            .filter(|path| path.file_name().is_some_and(|name| self.notes.is_match(name)))
+            .cloned()
+            .collect();
+        report.extension_changes = extension_changes(changes);
+
+        let samples = changes.added.iter()
+            .chain(&changes.modified)
+            .filter(|path| !is_compressed_type(path))
+            .take(self.config.entropy_sample_files);
+        for path in samples {
+            match sample_entropy(&root.join(path)) {
+                Ok(Some(entropy)) => {
+                    report.sampled += 1;
+                    if entropy >= HIGH_ENTROPY {
+                        report.high_entropy += 1;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Cannot sample {:?}: {}", path, e),
+            }
+        }
+
+        if !report.ransom_notes.is_empty() {
+            report.findings.push(format!(
+                "{} file(s) named like ransom notes appeared, e.g. {}",
+                report.ransom_notes.len(),
+                report.ransom_notes[0].display()
+            ));
+        }
+        // Ratios of small sources swing too much to mean anything
+        if previous_files >= self.config.min_files.max(1) {
+            let changed_ratio = report.changed as f64 / previous_files as f64;
+            if changed_ratio > self.config.max_changed_ratio {
+                report.findings.push(format!(
+                    "{:.0}% of the files changed (limit {:.0}%)",
+                    changed_ratio * 100.0,
+                    self.config.max_changed_ratio * 100.0
+                ));
+            }
+            let churn_ratio = report.extension_changes.len() as f64 / previous_files as f64;
+            if churn_ratio > self.config.max_extension_churn_ratio {
+                let (from, to) = &report.extension_changes[0];
+                report.findings.push(format!(
+                    "{:.0}% of the files reappeared with another extension (limit {:.0}%), e.g. {} -> {}",
+                    churn_ratio * 100.0,
+                    self.config.max_extension_churn_ratio * 100.0,
+                    from.display(),
+                    to.display()
+                ));
+            }
+            if report.sampled > 0 {
+                let entropy_ratio = report.high_entropy as f64 / report.sampled as f64;
+                if entropy_ratio > self.config.max_high_entropy_ratio {
+                    report.findings.push(format!(
+                        "{} of {} sampled new or modified files look encrypted (limit {:.0}%)",
+                        report.high_entropy,
+                        report.sampled,
+                        self.config.max_high_entropy_ratio * 100.0
+                    ));
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Deleted (or renamed) files replaced in the same directory by a file of the same
+/// name with an extension appended (`a.docx` -> `a.docx.locked`) or swapped (`a.docx` -> `a.enc`)
+fn extension_changes(changes: &TreeChanges) -> Vec<(PathBuf, PathBuf)> {
+    let mut found: Vec<(PathBuf, PathBuf)> = changes.renamed.iter()
+        .filter(|(from, to)| from.parent() == to.parent() && from.extension() != to.extension())
+        .cloned()
+        .collect();
+
+    let mut deleted: HashMap<(&Path, &OsStr), &PathBuf> = HashMap::new();
+    for path in &changes.deleted {
+        if let (Some(parent), Some(name), Some(stem)) = (path.parent(), path.file_name(), path.file_stem()) {
+            deleted.insert((parent, name), path);
+            deleted.entry((parent, stem)).or_insert(path);
+        }
+    }
+    for path in &changes.added {
+        let (Some(parent), Some(stem)) = (path.parent(), path.file_stem()) else { continue };
+        if path.extension().is_none() {
+            continue;
+        }
+        if let Some(old) = deleted.get(&(parent, stem)) {
+            if old.extension() != path.extension() {
+                found.push(((*old).clone(), path.clone()));
+            }
+        }
+    }
+    found
+}
+
+fn is_compressed_type(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSED_EXTENSIONS.iter().any(|c| c.eq_ignore_ascii_case(ext)))
+}
+
+/// Entropy of the start of the file, None if it is too small to judge
+fn sample_entropy(path: &Path) -> std::io::Result<Option<f64>> {
+    let mut sample = Vec::with_capacity(ENTROPY_SAMPLE_SIZE);
+    File::open(path)?.take(ENTROPY_SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+    Ok((sample.len() >= MIN_ENTROPY_SAMPLE).then(|| shannon_entropy(&sample)))
+}
+
+/// Shannon entropy of `data` in bits per byte (0 to 8)
+pub fn shannon_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0u64; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts.iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// The run was stopped before the snapshot became the latest one of its source(s)
+#[derive(Debug)]
+pub struct AnomalyDetected {
+    pub sources: Vec<String>,
+}
+
+impl fmt::Display for AnomalyDetected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Suspicious mass changes in source(s) {}; the snapshot was kept but not made the latest. \
+             Review the changes and rerun with --accept-anomalies if they are legitimate",
+            self.sources.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for AnomalyDetected {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_shannon_entropy() {
+        assert_eq!(shannon_entropy(&[7u8; 1000]), 0.0);
+        let uniform: Vec<u8> = (0..=255u8).cycle().take(256 * 64).collect();
+        assert!((shannon_entropy(&uniform) - 8.0).abs() < 1e-9);
+        assert!(shannon_entropy(b"just some ordinary english text, nothing random in here") < 5.0);
+    }
+
+    #[test]
+    fn test_encrypted_share_is_suspicious() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let mut changes = TreeChanges::default();
+        // Half of 200 documents were replaced by random-looking `.locked` files
+        let mut state = 0x2545f4914f6cdd1du64;
+        for i in 0..100 {
+            let name = format!("doc{}.txt", i);
+            let content: Vec<u8> = (0..8192)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            std::fs::write(root.join(format!("{}.locked", name)), content)?;
+            changes.deleted.push(PathBuf::from(&name));
+            changes.added.push(PathBuf::from(format!("{}.locked", name)));
+        }
+        std::fs::write(root.join("HOW_TO_DECRYPT.txt"), "pay up")?;
+        changes.added.push(PathBuf::from("HOW_TO_DECRYPT.txt"));
+
+        let config = AnomalyConfig::default();
+        let report = AnomalyDetector::new(&config)?.check(root, &changes, 200);
+        assert_eq!(report.ransom_notes, vec![PathBuf::from("HOW_TO_DECRYPT.txt")]);
+        assert_eq!(report.extension_changes.len(), 100);
+        assert_eq!((report.sampled, report.high_entropy), (100, 100));
+        assert_eq!(report.findings.len(), 3);
+
+        // An ordinary day: a few edits of a large share
+        let quiet = TreeChanges { modified: vec![PathBuf::from("notes.txt")], ..TreeChanges::default() };
+        assert!(!AnomalyDetector::new(&config)?.check(root, &quiet, 200).is_suspicious());
+        Ok(())
+    }
+}
@@ -2,7 +2,6 @@ use anyhow::Result;
 use clap::{Parser, Subcommand};
 use rumba::{models, db};
 use redb::ReadableTable;
-use rkyv::Deserialize;
 
 /// Database inspection tool for Rumba backup
 #[derive(Parser, Debug)]
@@ -31,9 +30,16 @@ enum Commands {
     },
     /// Show a specific index entry
     ShowIndex {
-        /// Path to show
+        /// Path relative to the source root
         path: String,
+        /// Source name
+        #[arg(short, long, default_value = "default")]
+        source: String,
     },
+    /// List registered sources
+    ListSources,
+    /// List blobs protected from retention (content replaced without a metadata change)
+    ListProtected,
 }
 
 fn main() -> Result<()> {
@@ -45,7 +51,9 @@ fn main() -> Result<()> {
         Commands::Stats => show_stats(&db)?,
         Commands::ListBlobs => list_blobs(&db)?,
         Commands::ListIndex { filter } => list_index(&db, filter.as_deref())?,
-        Commands::ShowIndex { path } => show_index(&db, &path)?,
+        Commands::ShowIndex { path, source } => show_index(&db, &source, &path)?,
+        Commands::ListSources => list_sources(&db)?,
+        Commands::ListProtected => list_protected(&db)?,
     }
     
     Ok(())
@@ -61,12 +69,18 @@ fn show_stats(db: &db::BackupDb) -> Result<()> {
     // Count index entries
     let index_table = txn.open_table(db::INDEX_TABLE)?;
     let index_count = index_table.iter()?.count();
+
+    // Count extended attribute objects
+    let xattrs_table = txn.open_table(db::XATTRS_TABLE)?;
+    let xattrs_count = xattrs_table.iter()?.count();
     
     println!("Database Statistics");
     println!("==================");
     println!("Database path: {}", db.path().display());
     println!("Blobs count:   {}", blob_count);
     println!("Index entries: {}", index_count);
+    println!("Xattr objects: {}", xattrs_count);
+    println!("Sources:       {}", db.list_sources()?.len());
     
     Ok(())
 }
@@ -76,24 +90,23 @@ fn list_blobs(db: &db::BackupDb) -> Result<()> {
     let table = txn.open_table(db::BLOBS_TABLE)?;
     
     println!("Blobs in database:");
-    println!("{:<66} {:>10} {:>10}", "Hash", "Tape ID", "Offset");
-    println!("{}", "=".repeat(90));
+    println!("{:<66} {:>10} {:>10} {:>14}", "Hash", "Tape ID", "Offset", "Block");
+    println!("{}", "=".repeat(105));
     
     for result in table.iter()? {
         let (hash_bytes, location_bytes) = result?;
         let hash = hash_bytes.value();
-        let location_value = location_bytes.value();
+        let location = db::decode_blob_location(location_bytes.value());
+        let block = match location.block_position() {
+            Some((block, within)) => format!("{}+{}", block, within),
+            None => "-".to_string(),
+        };
         
-        // Copy to owned Vec for proper alignment
-        let bytes = location_value.to_vec();
-        let archived = unsafe { rkyv::archived_root::<models::BlobLocation>(&bytes) };
-        let location: models::BlobLocation = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new())
-            .map_err(|e| anyhow::anyhow!("Failed to deserialize blob location: {}", e))?;
-        
-        println!("{} {:>10} {:>10}", 
+        println!("{} {:>10} {:>10} {:>14}", 
             hex::encode(hash), 
             location.tape_id, 
-            location.offset
+            location.offset,
+            block
         );
     }
     
@@ -105,12 +118,16 @@ fn list_index(db: &db::BackupDb, filter: Option<&str>) -> Result<()> {
     let table = txn.open_table(db::INDEX_TABLE)?;
     
     println!("Index entries:");
-    println!("{:<50} {:>12} {:>15} {}", "Path", "Size", "Mtime", "Hash (first 16 bytes)");
+    println!("{:<50} {:>12} {:>15} Hash (first 16 bytes)", "Path", "Size", "Mtime");
     println!("{}", "=".repeat(120));
     
     for result in table.iter()? {
         let (path_bytes, entry_bytes) = result?;
-        let path = path_bytes.value();
+        // Keys are a source id and raw relative path bytes; decode lossily for display only
+        let path = match models::split_index_key(path_bytes.value()) {
+            Some((source_id, relative)) => format!("[{}] {}", source_id, String::from_utf8_lossy(relative)),
+            None => String::from_utf8_lossy(path_bytes.value()).to_string(),
+        };
         
         // Apply filter if specified
         if let Some(filter_str) = filter {
@@ -119,14 +136,8 @@ fn list_index(db: &db::BackupDb, filter: Option<&str>) -> Result<()> {
             }
         }
         
-        let entry_value = entry_bytes.value();
-        
-        // Copy to owned Vec to ensure proper alignment
-        // redb returns 4-byte aligned data, but rkyv needs proper alignment
-        let bytes = entry_value.to_vec();
-        let archived = unsafe { rkyv::archived_root::<models::IndexEntry>(&bytes) };
-        let entry: models::IndexEntry = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new())
-            .map_err(|e| anyhow::anyhow!("Failed to deserialize index entry: {}", e))?;
+        let entry = db::decode_index_entry(entry_bytes.value())
+            .ok_or_else(|| anyhow::anyhow!("Failed to deserialize index entry of {}", path))?;
         
         println!("{:<50} {:>12} {:>15} {}", 
             if path.len() > 50 { path[..47].to_string() + "..." } else { path.to_string() },
@@ -139,8 +150,28 @@ fn list_index(db: &db::BackupDb, filter: Option<&str>) -> Result<()> {
     Ok(())
 }
 
-fn show_index(db: &db::BackupDb, path: &str) -> Result<()> {
-    let entry = db.get_index_entry(path)?;
+fn list_sources(db: &db::BackupDb) -> Result<()> {
+    println!("{:>4}  {:<20} {:<40} Root", "ID", "Name", "URL");
+    println!("{}", "=".repeat(90));
+    for source in db.list_sources()? {
+        println!("{:>4}  {:<20} {:<40} {}", source.id, source.name, source.url, String::from_utf8_lossy(&source.root));
+    }
+    Ok(())
+}
+
+fn list_protected(db: &db::BackupDb) -> Result<()> {
+    println!("{:<66} {:>6} {:>12}  Path", "Hash", "Source", "Detected");
+    println!("{}", "=".repeat(110));
+    for (hash, protected) in db.list_protected()? {
+        println!("{} {:>6} {:>12}  {}", hex::encode(hash), protected.source_id, protected.detected, String::from_utf8_lossy(&protected.path));
+    }
+    Ok(())
+}
+
+fn show_index(db: &db::BackupDb, source: &str, path: &str) -> Result<()> {
+    let source = db.get_source(source)?
+        .ok_or_else(|| anyhow::anyhow!("No source named '{}'", source))?;
+    let entry = db.get_index_entry(&models::index_key(source.id, path.trim_start_matches('/').as_bytes()))?;
     
     match entry {
         Some(e) => {
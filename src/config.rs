@@ -1,25 +1,44 @@
 use anyhow::{Context, Result, bail};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Largest tape block size accepted in `block_size`
+const MAX_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
 
 /// Main configuration structure for the Rumba backup tool
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
-    pub source: SourceConfig,
+    /// Shares to back up: a single `[source]` table or several `[[source]]` entries
+    #[serde(rename = "source", deserialize_with = "one_or_many")]
+    pub sources: Vec<SourceConfig>,
     pub target: TargetConfig,
     #[serde(default)]
     pub backup: BackupConfig,
+    #[serde(default)]
+    pub anomaly: AnomalyConfig,
 }
 
 /// SMB source configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SourceConfig {
+    /// Stable name of the source in the database; its index survives changes of `url`
+    #[serde(default = "default_source_name")]
+    pub name: String,
     /// SMB share URL, e.g., "\\\\server\\share\\path"
     pub url: String,
     /// Username for SMB authentication
     pub username: String,
     /// Password (can be plain text or base64 encoded with "base64:" prefix)
     pub password: String,
+    /// Glob patterns of paths to leave out, matched against the path relative
+    /// to the share root and against the bare file name
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// How often `rumba backup --all` backs this source up: "hourly", "daily",
+    /// "weekly" or an interval such as "30m", "6h", "2d". Unset means every run.
+    #[serde(default)]
+    pub schedule: Option<String>,
 }
 
 /// Backup target configuration
@@ -42,6 +61,27 @@ pub struct TargetConfig {
     /// Path to the metadata database
     #[serde(default = "default_db_path")]
     pub db_path: String,
+
+    /// Size in bytes of the fixed blocks written to the tape (a multiple of 512)
+    #[serde(default = "default_block_size")]
+    pub block_size: u64,
+
+    /// Local directory for a staging buffer between the source and the tape,
+    /// which keeps the drive streaming when the source is slow (disabled if unset)
+    #[serde(default)]
+    pub staging_dir: Option<String>,
+
+    /// Maximum size of the staging buffer in MiB
+    #[serde(default = "default_staging_size_mb")]
+    pub staging_size_mb: u64,
+
+    /// Buffer fill level (percent) at which the drive is started
+    #[serde(default = "default_staging_high_watermark")]
+    pub staging_high_watermark: u8,
+
+    /// Buffer fill level (percent) at which the drive is paused until the high watermark
+    #[serde(default = "default_staging_low_watermark")]
+    pub staging_low_watermark: u8,
 }
 
 /// Backup behavior configuration
@@ -53,9 +93,95 @@ pub struct BackupConfig {
     /// Compression level for zstd (0-22)
     #[serde(default = "default_compression_level")]
     pub compression_level: i32,
+    /// What to do with FIFOs, sockets and device nodes: "record" (metadata only) or "skip"
+    #[serde(default = "default_special_files")]
+    pub special_files: String,
+    /// Capture extended attributes and ACLs (POSIX ACLs, CIFS security descriptors)
+    #[serde(default = "default_true")]
+    pub xattrs: bool,
+    /// Unicode normalization used to compare names: "none", "nfc" or "nfd"
+    #[serde(default = "default_name_normalization")]
+    pub name_normalization: String,
+    /// Treat names that differ only in case as the same file (SMB semantics)
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// Directory for the temporary files of a run (planned blobs, tape locations);
+    /// defaults to the system temporary directory
+    #[serde(default)]
+    pub spill_dir: Option<String>,
+    /// Rehash the files the index considers unchanged in a rotation of this many days,
+    /// 1/N of them per run, to catch content that changed without a metadata change
+    /// (silent corruption on the source). 0 disables it.
+    #[serde(default)]
+    pub rehash_days: u32,
+    /// Write a JSON report of every run to this path; `{source}` and `{time}`
+    /// are replaced by the source name and the start time of the run
+    #[serde(default)]
+    pub report_path: Option<String>,
+    /// Seconds between progress lines in the log; 0 turns them off
+    #[serde(default = "default_progress_interval_secs")]
+    pub progress_interval_secs: u64,
+    /// Tries of one open, stat or read of the source before a transient error
+    /// (EAGAIN, EIO, connection reset) is given up on
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+    /// Milliseconds to wait before the first retry, doubled for every further one
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub retry_max_backoff_ms: u64,
+    /// Failed tries of one file after which it is skipped and recorded as unreadable;
+    /// 0 only limits the tries of each operation
+    #[serde(default = "default_skip_after_failures")]
+    pub skip_after_failures: u32,
+}
+
+/// Checks for ransomware-like mass changes, run before a snapshot becomes the latest one.
+/// The ratios compare against the files of the previous snapshot of the source.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnomalyConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// The ratio checks only apply to sources with at least this many files
+    #[serde(default = "default_anomaly_min_files")]
+    pub min_files: u64,
+    /// Largest share of files that may be modified, deleted or renamed in one run
+    #[serde(default = "default_max_changed_ratio")]
+    pub max_changed_ratio: f64,
+    /// Largest share of the sampled new and modified files whose content may look
+    /// encrypted (file types that are compressed anyway are not sampled)
+    #[serde(default = "default_max_high_entropy_ratio")]
+    pub max_high_entropy_ratio: f64,
+    /// Number of new and modified files sampled for the entropy check
+    #[serde(default = "default_entropy_sample_files")]
+    pub entropy_sample_files: usize,
+    /// Largest share of files that may be replaced by a file with another extension
+    #[serde(default = "default_max_extension_churn_ratio")]
+    pub max_extension_churn_ratio: f64,
+    /// File name patterns of ransom notes (case-insensitive); any new match is an anomaly
+    #[serde(default = "default_ransom_note_patterns")]
+    pub ransom_note_patterns: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SourceConfig>, D::Error> {
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(source) => vec![source],
+        OneOrMany::Many(sources) => sources,
+    })
 }
 
 // Default values
+fn default_source_name() -> String {
+    "default".to_string()
+}
+
 fn default_output_mode() -> String {
     "rustltfs".to_string()
 }
@@ -72,6 +198,22 @@ fn default_db_path() -> String {
     "backup_meta.redb".to_string()
 }
 
+fn default_block_size() -> u64 {
+    256 * 1024
+}
+
+fn default_staging_size_mb() -> u64 {
+    4096
+}
+
+fn default_staging_high_watermark() -> u8 {
+    75
+}
+
+fn default_staging_low_watermark() -> u8 {
+    10
+}
+
 fn default_parallel_threads() -> usize {
     num_cpus::get()
 }
@@ -80,11 +222,104 @@ fn default_compression_level() -> i32 {
     3
 }
 
+fn default_progress_interval_secs() -> u64 {
+    60
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    30_000
+}
+
+fn default_skip_after_failures() -> u32 {
+    10
+}
+
+fn default_special_files() -> String {
+    "record".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_name_normalization() -> String {
+    "none".to_string()
+}
+
+fn default_anomaly_min_files() -> u64 {
+    100
+}
+
+fn default_max_changed_ratio() -> f64 {
+    0.5
+}
+
+fn default_max_high_entropy_ratio() -> f64 {
+    0.5
+}
+
+fn default_entropy_sample_files() -> usize {
+    1000
+}
+
+fn default_max_extension_churn_ratio() -> f64 {
+    0.1
+}
+
+fn default_ransom_note_patterns() -> Vec<String> {
+    [
+        "*decrypt*",
+        "*recover*files*",
+        "*restore*files*",
+        "how_to_*",
+        "*ransom*",
+        "!!!*",
+        "_readme.txt",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
 impl Default for BackupConfig {
     fn default() -> Self {
         Self {
             parallel_threads: default_parallel_threads(),
             compression_level: default_compression_level(),
+            special_files: default_special_files(),
+            xattrs: default_true(),
+            name_normalization: default_name_normalization(),
+            case_insensitive: false,
+            spill_dir: None,
+            rehash_days: 0,
+            report_path: None,
+            progress_interval_secs: default_progress_interval_secs(),
+            retry_attempts: default_retry_attempts(),
+            retry_backoff_ms: default_retry_backoff_ms(),
+            retry_max_backoff_ms: default_retry_max_backoff_ms(),
+            skip_after_failures: default_skip_after_failures(),
+        }
+    }
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_true(),
+            min_files: default_anomaly_min_files(),
+            max_changed_ratio: default_max_changed_ratio(),
+            max_high_entropy_ratio: default_max_high_entropy_ratio(),
+            entropy_sample_files: default_entropy_sample_files(),
+            max_extension_churn_ratio: default_max_extension_churn_ratio(),
+            ransom_note_patterns: default_ransom_note_patterns(),
         }
     }
 }
@@ -99,8 +334,10 @@ impl Config {
         let mut config: Config = toml::from_str(&content)
             .with_context(|| format!("Failed to parse TOML config: {}", path.display()))?;
         
-        // Decode password if it's base64 encoded
-        config.source.password = decode_password(&config.source.password)?;
+        // Decode passwords if they're base64 encoded
+        for source in &mut config.sources {
+            source.password = decode_password(&source.password)?;
+        }
         
         // Validate configuration
         config.validate()?;
@@ -110,16 +347,15 @@ impl Config {
     
     /// Validate configuration values
     fn validate(&self) -> Result<()> {
-        if self.source.url.is_empty() {
-            bail!("Source URL cannot be empty");
+        if self.sources.is_empty() {
+            bail!("At least one [[source]] must be configured");
         }
-        
-        if self.source.username.is_empty() {
-            bail!("Source username cannot be empty");
-        }
-        
-        if self.source.password.is_empty() {
-            bail!("Source password cannot be empty");
+
+        for (i, source) in self.sources.iter().enumerate() {
+            source.validate()?;
+            if self.sources[..i].iter().any(|s| s.name == source.name) {
+                bail!("Source names must be unique, '{}' is used more than once", source.name);
+            }
         }
         
         // Validate output mode
@@ -127,6 +363,24 @@ impl Config {
             bail!("Output mode must be either 'rustltfs' or 'tar', got: {}", self.target.output_mode);
         }
         
+        let block_size = self.target.block_size;
+        if block_size == 0 || !block_size.is_multiple_of(512) || block_size > MAX_BLOCK_SIZE {
+            bail!("Block size must be a multiple of 512 bytes and at most {} bytes, got {}", MAX_BLOCK_SIZE, block_size);
+        }
+
+        if self.target.staging_dir.is_some() {
+            if self.target.staging_size_mb == 0 {
+                bail!("Staging buffer size must be at least 1 MiB");
+            }
+            if self.target.staging_size_mb.checked_mul(1024 * 1024).is_none() {
+                bail!("Staging buffer size is too large: {} MiB", self.target.staging_size_mb);
+            }
+            let (low, high) = (self.target.staging_low_watermark, self.target.staging_high_watermark);
+            if low >= high || high > 100 {
+                bail!("Staging watermarks must satisfy low < high <= 100, got low {} and high {}", low, high);
+            }
+        }
+        
         if self.backup.compression_level < 0 || self.backup.compression_level > 22 {
             bail!("Compression level must be between 0 and 22");
         }
@@ -134,17 +388,100 @@ impl Config {
         if self.backup.parallel_threads == 0 {
             bail!("Parallel threads must be at least 1");
         }
+
+        if self.backup.special_files != "record" && self.backup.special_files != "skip" {
+            bail!("Special files policy must be either 'record' or 'skip', got: {}", self.backup.special_files);
+        }
+
+        crate::normalize::NamePolicy::from_config(&self.backup)?;
+
+        let anomaly = &self.anomaly;
+        for (name, ratio) in [
+            ("max_changed_ratio", anomaly.max_changed_ratio),
+            ("max_high_entropy_ratio", anomaly.max_high_entropy_ratio),
+            ("max_extension_churn_ratio", anomaly.max_extension_churn_ratio),
+        ] {
+            if !(0.0..=1.0).contains(&ratio) {
+                bail!("Anomaly {} must be between 0 and 1, got {}", name, ratio);
+            }
+        }
+        crate::anomaly::build_note_patterns(&anomaly.ransom_note_patterns)
+            .context("Invalid ransom note pattern")?;
         
         Ok(())
     }
     
+    /// Returns the source called `name`
+    pub fn source(&self, name: &str) -> Result<&SourceConfig> {
+        self.sources.iter()
+            .find(|s| s.name == name)
+            .with_context(|| format!("No source named '{}' in the configuration", name))
+    }
+}
+
+impl SourceConfig {
+    fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            bail!("Source name cannot be empty");
+        }
+
+        if self.url.is_empty() {
+            bail!("Source URL cannot be empty");
+        }
+        
+        if self.username.is_empty() {
+            bail!("Source username cannot be empty");
+        }
+        
+        if self.password.is_empty() {
+            bail!("Source password cannot be empty");
+        }
+
+        crate::scanner::build_excludes(&self.exclude)
+            .with_context(|| format!("Invalid exclude pattern in source '{}'", self.name))?;
+        self.schedule_interval()?;
+
+        Ok(())
+    }
+
     /// Get the backup root path from the SMB URL
     pub fn get_backup_root(&self) -> Result<PathBuf> {
-        // For Windows UNC paths, we can use them directly
-        // For now, we'll just convert the URL to a PathBuf
-        // In a real implementation, this would mount the SMB share
-        Ok(PathBuf::from(&self.source.url))
+        Ok(root_for_url(&self.url))
     }
+
+    /// Minimum time between two scheduled backups of this source
+    pub fn schedule_interval(&self) -> Result<Option<Duration>> {
+        let Some(schedule) = &self.schedule else {
+            return Ok(None);
+        };
+        let interval = match schedule.as_str() {
+            "hourly" => Duration::from_secs(3600),
+            "daily" => Duration::from_secs(24 * 3600),
+            "weekly" => Duration::from_secs(7 * 24 * 3600),
+            other => {
+                let unit = match other.chars().last() {
+                    Some('m') => 60,
+                    Some('h') => 3600,
+                    Some('d') => 24 * 3600,
+                    _ => bail!("Schedule of source '{}' must be hourly, daily, weekly or like '6h', got: {}", self.name, other),
+                };
+                let count: u64 = other[..other.len() - 1].parse()
+                    .with_context(|| format!("Invalid schedule of source '{}': {}", self.name, other))?;
+                let secs = count.checked_mul(unit)
+                    .with_context(|| format!("Schedule of source '{}' is too long: {}", self.name, other))?;
+                Duration::from_secs(secs)
+            }
+        };
+        Ok(Some(interval))
+    }
+}
+
+/// Local path at which the share `url` is backed up
+pub fn root_for_url(url: &str) -> PathBuf {
+    // For Windows UNC paths, we can use them directly
+    // For now, we'll just convert the URL to a PathBuf
+    // In a real implementation, this would mount the SMB share
+    PathBuf::from(url)
 }
 
 /// Encode a password to base64 for storage in config file
@@ -191,39 +528,105 @@ mod tests {
     #[test]
     fn test_config_validation() {
         let config = Config {
-            source: SourceConfig {
+            sources: vec![SourceConfig {
+                name: default_source_name(),
                 url: "\\\\server\\share".to_string(),
                 username: "user".to_string(),
                 password: "pass".to_string(),
-            },
+                exclude: vec!["*.tmp".to_string()],
+                schedule: Some("daily".to_string()),
+            }],
             target: TargetConfig {
+                output_mode: "tar".to_string(),
+                rustltfs_path: default_rustltfs_path(),
                 tape_path: "tape.tar".to_string(),
                 db_path: "db.redb".to_string(),
+                block_size: default_block_size(),
+                staging_dir: None,
+                staging_size_mb: default_staging_size_mb(),
+                staging_high_watermark: default_staging_high_watermark(),
+                staging_low_watermark: default_staging_low_watermark(),
             },
             backup: BackupConfig {
                 parallel_threads: 4,
                 compression_level: 3,
+                ..BackupConfig::default()
             },
+            anomaly: AnomalyConfig::default(),
         };
         
         assert!(config.validate().is_ok());
+
+        let mut staged = config.clone();
+        staged.target.staging_dir = Some("/var/spool/rumba".to_string());
+        assert!(staged.validate().is_ok());
+        staged.target.staging_size_mb = u64::MAX / 1024;
+        assert!(staged.validate().is_err());
     }
 
     #[test]
     fn test_config_validation_empty_url() {
         let config = Config {
-            source: SourceConfig {
+            sources: vec![SourceConfig {
+                name: default_source_name(),
                 url: "".to_string(),
                 username: "user".to_string(),
                 password: "pass".to_string(),
-            },
+                exclude: Vec::new(),
+                schedule: None,
+            }],
             target: TargetConfig {
+                output_mode: "tar".to_string(),
+                rustltfs_path: default_rustltfs_path(),
                 tape_path: "tape.tar".to_string(),
                 db_path: "db.redb".to_string(),
+                block_size: default_block_size(),
+                staging_dir: None,
+                staging_size_mb: default_staging_size_mb(),
+                staging_high_watermark: default_staging_high_watermark(),
+                staging_low_watermark: default_staging_low_watermark(),
             },
             backup: BackupConfig::default(),
+            anomaly: AnomalyConfig::default(),
         };
         
         assert!(config.validate().is_err());
     }
+
+    #[test]
+    fn test_multiple_sources() {
+        let config: Config = toml::from_str(r#"
+            [[source]]
+            name = "finance"
+            url = "/mnt/finance"
+            username = "user"
+            password = "pass"
+            exclude = ["~$*", "*.tmp"]
+            schedule = "6h"
+
+            [[source]]
+            name = "design"
+            url = "/mnt/design"
+            username = "user"
+            password = "pass"
+
+            [target]
+            output_mode = "tar"
+        "#).unwrap();
+
+        assert!(config.validate().is_ok());
+        assert_eq!(config.sources.len(), 2);
+        let finance = config.source("finance").unwrap();
+        assert_eq!(finance.schedule_interval().unwrap(), Some(Duration::from_secs(6 * 3600)));
+        assert_eq!(config.source("design").unwrap().schedule_interval().unwrap(), None);
+        assert!(config.source("hr").is_err());
+        let mut endless = finance.clone();
+        endless.schedule = Some("99999999999999999d".to_string());
+        assert!(endless.schedule_interval().is_err());
+
+        // Duplicate (default) names are rejected
+        let mut duplicate = config.clone();
+        duplicate.sources.iter_mut().for_each(|s| s.name = default_source_name());
+        assert!(duplicate.validate().is_err());
+    }
 }
@@ -1,18 +1,102 @@
-use redb::{Database, TableDefinition, WriteTransaction};
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
 use std::path::Path;
 use anyhow::Result;
-use crate::models::{Hash, BlobLocation};
+use crate::normalize::NamePolicy;
+use crate::models::{self, Hash, BlobLocation, Commit, ExtendedAttributes, IndexEntry, PartialSnapshot, ProtectedBlob, RunSummary, Source, TreeEntry};
 use rkyv::Deserialize;
+use tracing::info;
 
 // Table Definitions
 pub const BLOBS_TABLE: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("blobs");
 pub const TREES_TABLE: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("trees");
 pub const COMMITS_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("commits");
-pub const INDEX_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("index");
+pub const INDEX_TABLE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("index");
+/// The `index` table as written before keys were raw path bytes
+const LEGACY_INDEX_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("index");
+pub const XATTRS_TABLE: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("xattrs");
+pub const SOURCES_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("sources");
+/// Latest commit of each source: source name -> key in `commits`
+pub const REFS_TABLE: TableDefinition<&str, u64> = TableDefinition::new("refs");
+/// Blobs kept regardless of retention: hash -> ProtectedBlob
+pub const PROTECTED_TABLE: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("protected");
+/// Summaries of backup runs: UNIX start time -> RunSummary
+pub const RUNS_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("runs");
+/// Snapshots that miss part of their source: key in `commits` -> PartialSnapshot
+pub const PARTIAL_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("partial");
 
 use std::sync::Arc;
 use std::path::PathBuf;
 
+/// Blob locations written before tape block sizes were recorded
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+#[archive(check_bytes)]
+#[repr(C)]
+struct LegacyBlobLocation {
+    tape_id: u64,
+    offset: u64,
+}
+
+/// Decodes a value of the `blobs` table
+pub fn decode_blob_location(bytes: &[u8]) -> BlobLocation {
+    // Copy to owned Vec to fix alignment issues
+    let bytes = bytes.to_vec();
+    let deserializer = &mut rkyv::de::deserializers::SharedDeserializeMap::new();
+    if bytes.len() == std::mem::size_of::<rkyv::Archived<LegacyBlobLocation>>() {
+        let archived = unsafe { rkyv::archived_root::<LegacyBlobLocation>(&bytes) };
+        let legacy: LegacyBlobLocation = archived.deserialize(deserializer).unwrap();
+        return BlobLocation { tape_id: legacy.tape_id, offset: legacy.offset, block_size: 0 };
+    }
+    let archived = unsafe { rkyv::archived_root::<BlobLocation>(&bytes) };
+    archived.deserialize(deserializer).unwrap()
+}
+
+/// Index entries written before ctime, nanoseconds and inodes were recorded
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+#[archive(check_bytes)]
+#[repr(C)]
+struct LegacyIndexEntry {
+    mtime: i64,
+    size: u64,
+    hash: Hash,
+}
+
+/// Decodes a value of the `index` table. Entries of the old layout have no ctime or
+/// inode, so they never match and their files are rehashed; values of neither
+/// layout count as not indexed.
+pub fn decode_index_entry(bytes: &[u8]) -> Option<IndexEntry> {
+    // Copy to an aligned buffer, redb values are not aligned for rkyv
+    let mut aligned = rkyv::AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+    let deserializer = &mut rkyv::de::deserializers::SharedDeserializeMap::new();
+    if aligned.len() == std::mem::size_of::<rkyv::Archived<LegacyIndexEntry>>() {
+        let archived = rkyv::check_archived_root::<LegacyIndexEntry>(&aligned).ok()?;
+        let legacy: LegacyIndexEntry = archived.deserialize(deserializer).ok()?;
+        return Some(IndexEntry { mtime: legacy.mtime, mtime_nsec: 0, ctime: 0, ctime_nsec: 0, ino: 0, size: legacy.size, hash: legacy.hash });
+    }
+    let archived = rkyv::check_archived_root::<IndexEntry>(&aligned).ok()?;
+    archived.deserialize(deserializer).ok()
+}
+
+/// Rewrites an `index` table keyed by UTF-8 strings into one keyed by raw bytes.
+/// The keys keep their bytes, so `register_source` can move them into their source.
+fn migrate_legacy_index(txn: &WriteTransaction) -> Result<()> {
+    let mut rows = Vec::new();
+    {
+        let legacy = txn.open_table(LEGACY_INDEX_TABLE)?;
+        for result in legacy.iter()? {
+            let (key, value) = result?;
+            rows.push((key.value().as_bytes().to_vec(), value.value().to_vec()));
+        }
+    }
+    txn.delete_table(LEGACY_INDEX_TABLE)?;
+    let mut table = txn.open_table(INDEX_TABLE)?;
+    for (key, value) in &rows {
+        table.insert(key.as_slice(), value.as_slice())?;
+    }
+    info!("Migrated {} index entries to raw path keys", rows.len());
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct BackupDb {
     db: Arc<Database>,
@@ -29,12 +113,57 @@ impl BackupDb {
             write_txn.open_table(BLOBS_TABLE)?;
             write_txn.open_table(TREES_TABLE)?;
             write_txn.open_table(COMMITS_TABLE)?;
-            write_txn.open_table(INDEX_TABLE)?;
+            match write_txn.open_table(INDEX_TABLE) {
+                Ok(_) => {}
+                Err(redb::TableError::TableTypeMismatch { .. }) => migrate_legacy_index(&write_txn)?,
+                Err(e) => return Err(e.into()),
+            }
+            write_txn.open_table(XATTRS_TABLE)?;
+            write_txn.open_table(SOURCES_TABLE)?;
+            write_txn.open_table(REFS_TABLE)?;
+            write_txn.open_table(PROTECTED_TABLE)?;
+            write_txn.open_table(RUNS_TABLE)?;
+            write_txn.open_table(PARTIAL_TABLE)?;
         }
         write_txn.commit()?;
         
         Ok(Self { db: Arc::new(db), path: path_buf })
     }
+
+    /// Opens an existing database without creating it or touching its tables, for runs
+    /// that must not write. A database that `new` would still have to upgrade is refused.
+    pub fn open_existing(path: impl AsRef<Path>) -> Result<Self> {
+        let path_buf = path.as_ref().to_path_buf();
+        if !path_buf.exists() {
+            anyhow::bail!("No database at {}; run a backup first", path_buf.display());
+        }
+        let db = Database::open(&path_buf)?;
+        let read_txn = db.begin_read()?;
+        let current = (|| -> Result<(), redb::TableError> {
+            read_txn.open_table(BLOBS_TABLE)?;
+            read_txn.open_table(TREES_TABLE)?;
+            read_txn.open_table(COMMITS_TABLE)?;
+            read_txn.open_table(INDEX_TABLE)?;
+            read_txn.open_table(XATTRS_TABLE)?;
+            read_txn.open_table(SOURCES_TABLE)?;
+            read_txn.open_table(REFS_TABLE)?;
+            read_txn.open_table(PROTECTED_TABLE)?;
+            read_txn.open_table(RUNS_TABLE)?;
+            read_txn.open_table(PARTIAL_TABLE)?;
+            Ok(())
+        })();
+        match current {
+            Ok(()) => {}
+            Err(redb::TableError::TableDoesNotExist(_) | redb::TableError::TableTypeMismatch { .. }) => anyhow::bail!(
+                "The database at {} was written by an older version; run a backup without --dry-run to upgrade it",
+                path_buf.display()
+            ),
+            Err(e) => return Err(e.into()),
+        }
+        drop(read_txn);
+
+        Ok(Self { db: Arc::new(db), path: path_buf })
+    }
     
     pub fn path(&self) -> &Path {
         &self.path
@@ -52,33 +181,212 @@ impl BackupDb {
     pub fn get_blob(&self, hash: &Hash) -> Result<Option<BlobLocation>> {
         let read_txn = self.db.begin_read()?;
         let table = read_txn.open_table(BLOBS_TABLE)?;
+        if let Some(value) = table.get(hash)? {
+            Ok(Some(decode_blob_location(value.value())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// `path` is the raw path bytes (see `models::os_to_bytes`)
+    pub fn get_index_entry(&self, path: &[u8]) -> Result<Option<IndexEntry>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(INDEX_TABLE)?;
+        Ok(table.get(path)?.and_then(|value| decode_index_entry(value.value())))
+    }
+
+    /// Number of files in the index of source `source_id`
+    pub fn count_index(&self, source_id: u64) -> Result<u64> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(INDEX_TABLE)?;
+        let start = crate::models::index_key(source_id, b"");
+        let mut count = 0;
+        match source_id.checked_add(1) {
+            Some(next) => {
+                let end = crate::models::index_key(next, b"");
+                for entry in table.range(start.as_slice()..end.as_slice())? {
+                    entry?;
+                    count += 1;
+                }
+            }
+            None => {
+                for entry in table.range(start.as_slice()..)? {
+                    entry?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn get_tree(&self, hash: &Hash) -> Result<Option<Vec<TreeEntry>>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TREES_TABLE)?;
         if let Some(value) = table.get(hash)? {
             let value = value.value();
             // Copy to owned Vec to fix alignment issues
             let bytes = value.to_vec();
-            let archived = unsafe { rkyv::archived_root::<BlobLocation>(&bytes) };
-            let deserialized: BlobLocation = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            let archived = unsafe { rkyv::archived_root::<Vec<TreeEntry>>(&bytes) };
+            let deserialized: Vec<TreeEntry> = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
             Ok(Some(deserialized))
         } else {
             Ok(None)
         }
     }
 
-    pub fn get_index_entry(&self, path: &str) -> Result<Option<crate::models::IndexEntry>> {
+    pub fn get_xattrs(&self, hash: &Hash) -> Result<Option<ExtendedAttributes>> {
         let read_txn = self.db.begin_read()?;
-        let table = read_txn.open_table(INDEX_TABLE)?;
-        if let Some(value) = table.get(path)? {
+        let table = read_txn.open_table(XATTRS_TABLE)?;
+        if let Some(value) = table.get(hash)? {
             let value = value.value();
             // Copy to owned Vec to fix alignment issues
             let bytes = value.to_vec();
-            let archived = unsafe { rkyv::archived_root::<crate::models::IndexEntry>(&bytes) };
-            let deserialized: crate::models::IndexEntry = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            let archived = unsafe { rkyv::archived_root::<ExtendedAttributes>(&bytes) };
+            let deserialized: ExtendedAttributes = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
             Ok(Some(deserialized))
         } else {
             Ok(None)
         }
     }
 
+    pub fn get_source(&self, name: &str) -> Result<Option<Source>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(SOURCES_TABLE)?;
+        if let Some(value) = table.get(name)? {
+            let bytes = value.value().to_vec();
+            let archived = unsafe { rkyv::archived_root::<Source>(&bytes) };
+            let source: Source = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            Ok(Some(source))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns all sources ordered by name
+    pub fn list_sources(&self) -> Result<Vec<Source>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(SOURCES_TABLE)?;
+        let mut sources = Vec::new();
+        for result in table.iter()? {
+            let (_, value) = result?;
+            let bytes = value.value().to_vec();
+            let archived = unsafe { rkyv::archived_root::<Source>(&bytes) };
+            sources.push(archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap());
+        }
+        Ok(sources)
+    }
+
+    /// Returns the source called `name`, registering it at `url`/`root` with a new id
+    /// if it does not exist yet. Index entries written under the raw absolute paths below
+    /// `root` (before sources existed) are moved into the new source, keyed by `policy`.
+    pub fn register_source(&self, name: &str, url: &str, root: &[u8], policy: &NamePolicy) -> Result<Source> {
+        if let Some(source) = self.get_source(name)? {
+            return Ok(source);
+        }
+
+        let id = self.list_sources()?.iter().map(|s| s.id).max().unwrap_or(0) + 1;
+        let source = Source { id, name: name.to_string(), url: url.to_string(), root: root.to_vec() };
+
+        let txn = self.begin_write()?;
+        self.insert_source(&txn, &source)?;
+        {
+            let mut table = txn.open_table(INDEX_TABLE)?;
+            let mut legacy = Vec::new();
+            for result in table.iter()? {
+                let (key, value) = result?;
+                let key = key.value();
+                if models::split_index_key(key).is_none() {
+                    let relative = key.strip_prefix(root).and_then(|r| r.strip_prefix(b"/").or_else(|| r.strip_prefix(b"\\")));
+                    if let Some(relative) = relative {
+                        legacy.push((key.to_vec(), policy.key(relative).into_owned(), value.value().to_vec()));
+                    }
+                }
+            }
+            for (old_key, relative, value) in legacy {
+                table.remove(old_key.as_slice())?;
+                table.insert(models::index_key(id, &relative).as_slice(), value.as_slice())?;
+            }
+        }
+        txn.commit()?;
+        Ok(source)
+    }
+
+    /// Returns all commits ordered by timestamp (oldest first)
+    pub fn list_commits(&self) -> Result<Vec<(u64, Commit)>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(COMMITS_TABLE)?;
+        let mut commits = Vec::new();
+        for result in table.iter()? {
+            let (key, value) = result?;
+            let bytes = value.value().to_vec();
+            let archived = unsafe { rkyv::archived_root::<Commit>(&bytes) };
+            let commit: Commit = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            commits.push((key.value(), commit));
+        }
+        Ok(commits)
+    }
+
+    pub fn get_commit(&self, key: u64) -> Result<Option<Commit>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(COMMITS_TABLE)?;
+        if let Some(value) = table.get(&key)? {
+            let bytes = value.value().to_vec();
+            let archived = unsafe { rkyv::archived_root::<Commit>(&bytes) };
+            let commit: Commit = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            Ok(Some(commit))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the commit key the ref of `source` points to
+    pub fn get_ref(&self, source: &str) -> Result<Option<u64>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(REFS_TABLE)?;
+        let key = table.get(source)?.map(|v| v.value());
+        Ok(key)
+    }
+
+    /// Returns the latest commit of `source`
+    pub fn get_ref_commit(&self, source: &str) -> Result<Option<Commit>> {
+        match self.get_ref(source)? {
+            Some(key) => self.get_commit(key),
+            None => Ok(None),
+        }
+    }
+
+    pub fn has_refs(&self) -> Result<bool> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(REFS_TABLE)?;
+        Ok(!table.is_empty()?)
+    }
+
+    /// First unused key in `commits` at or after `timestamp`, so that sources
+    /// backed up within the same second do not overwrite each other's commits
+    pub fn free_commit_key(&self, timestamp: u64) -> Result<u64> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(COMMITS_TABLE)?;
+        let mut key = timestamp;
+        while table.get(&key)?.is_some() {
+            key += 1;
+        }
+        Ok(key)
+    }
+
+    pub fn get_latest_commit(&self) -> Result<Option<Commit>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(COMMITS_TABLE)?;
+        let last = table.last()?;
+        if let Some((_, value)) = last {
+            let bytes = value.value().to_vec();
+            let archived = unsafe { rkyv::archived_root::<Commit>(&bytes) };
+            let commit: Commit = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            Ok(Some(commit))
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn insert_blob(&self, txn: &WriteTransaction, hash: &Hash, location: &BlobLocation) -> Result<()> {
         use rkyv::ser::Serializer;
         let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
@@ -101,6 +409,28 @@ impl BackupDb {
         Ok(())
     }
 
+    pub fn insert_xattrs(&self, txn: &WriteTransaction, hash: &Hash, xattrs: &ExtendedAttributes) -> Result<()> {
+        use rkyv::ser::Serializer;
+        let mut serializer = rkyv::ser::serializers::AllocSerializer::<1024>::default();
+        serializer.serialize_value(xattrs).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+        
+        let mut table = txn.open_table(XATTRS_TABLE)?;
+        table.insert(hash, bytes.as_slice())?;
+        Ok(())
+    }
+
+    pub fn insert_source(&self, txn: &WriteTransaction, source: &Source) -> Result<()> {
+        use rkyv::ser::Serializer;
+        let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
+        serializer.serialize_value(source).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+
+        let mut table = txn.open_table(SOURCES_TABLE)?;
+        table.insert(source.name.as_str(), bytes.as_slice())?;
+        Ok(())
+    }
+
     pub fn insert_commit(&self, txn: &WriteTransaction, timestamp: u64, commit: &crate::models::Commit) -> Result<()> {
         use rkyv::ser::Serializer;
         let mut serializer = rkyv::ser::serializers::AllocSerializer::<1024>::default();
@@ -112,7 +442,13 @@ impl BackupDb {
         Ok(())
     }
 
-    pub fn insert_index(&self, txn: &WriteTransaction, path: &str, entry: &crate::models::IndexEntry) -> Result<()> {
+    pub fn set_ref(&self, txn: &WriteTransaction, source: &str, commit_key: u64) -> Result<()> {
+        let mut table = txn.open_table(REFS_TABLE)?;
+        table.insert(source, commit_key)?;
+        Ok(())
+    }
+
+    pub fn insert_index(&self, txn: &WriteTransaction, path: &[u8], entry: &crate::models::IndexEntry) -> Result<()> {
         use rkyv::ser::Serializer;
         let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
         serializer.serialize_value(entry).unwrap();
@@ -122,6 +458,108 @@ impl BackupDb {
         table.insert(path, bytes.as_slice())?;
         Ok(())
     }
+
+    /// Marks blob `hash` as one that must never be dropped
+    pub fn protect_blob(&self, txn: &WriteTransaction, hash: &Hash, protected: &ProtectedBlob) -> Result<()> {
+        use rkyv::ser::Serializer;
+        let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
+        serializer.serialize_value(protected).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+
+        let mut table = txn.open_table(PROTECTED_TABLE)?;
+        table.insert(hash, bytes.as_slice())?;
+        Ok(())
+    }
+
+    /// Returns all protected blobs, ordered by hash
+    pub fn list_protected(&self) -> Result<Vec<(Hash, ProtectedBlob)>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PROTECTED_TABLE)?;
+        let mut protected = Vec::new();
+        for result in table.iter()? {
+            let (key, value) = result?;
+            let bytes = value.value().to_vec();
+            let archived = unsafe { rkyv::archived_root::<ProtectedBlob>(&bytes) };
+            let blob: ProtectedBlob = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            protected.push((*key.value(), blob));
+        }
+        Ok(protected)
+    }
+
+    /// Marks the commit under `commit_key` as a partial snapshot
+    pub fn mark_partial(&self, txn: &WriteTransaction, commit_key: u64, partial: &PartialSnapshot) -> Result<()> {
+        use rkyv::ser::Serializer;
+        let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
+        serializer.serialize_value(partial).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+
+        let mut table = txn.open_table(PARTIAL_TABLE)?;
+        table.insert(&commit_key, bytes.as_slice())?;
+        Ok(())
+    }
+
+    /// Whether any commit is partial; databases from before refs existed hold none
+    pub fn has_partials(&self) -> Result<bool> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PARTIAL_TABLE)?;
+        Ok(!table.is_empty()?)
+    }
+
+    /// Returns why the commit under `commit_key` is partial, None if it is complete
+    pub fn get_partial(&self, commit_key: u64) -> Result<Option<PartialSnapshot>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PARTIAL_TABLE)?;
+        if let Some(value) = table.get(&commit_key)? {
+            let bytes = value.value().to_vec();
+            let archived = unsafe { rkyv::archived_root::<PartialSnapshot>(&bytes) };
+            let partial: PartialSnapshot = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            Ok(Some(partial))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Records a finished run under the first free key at or after its start time
+    pub fn record_run(&self, run: &RunSummary) -> Result<()> {
+        use rkyv::ser::Serializer;
+        let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
+        serializer.serialize_value(run).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(RUNS_TABLE)?;
+            let mut key = run.started;
+            while table.get(&key)?.is_some() {
+                key += 1;
+            }
+            table.insert(&key, bytes.as_slice())?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns all recorded runs, oldest first
+    pub fn list_runs(&self) -> Result<Vec<RunSummary>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(RUNS_TABLE)?;
+        let mut runs = Vec::new();
+        for result in table.iter()? {
+            let (_, value) = result?;
+            let bytes = value.value().to_vec();
+            let archived = unsafe { rkyv::archived_root::<RunSummary>(&bytes) };
+            let run: RunSummary = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+            runs.push(run);
+        }
+        Ok(runs)
+    }
+
+    /// Drops the index entry of a file that no longer exists
+    pub fn remove_index(&self, txn: &WriteTransaction, path: &[u8]) -> Result<()> {
+        let mut table = txn.open_table(INDEX_TABLE)?;
+        table.remove(path)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -135,7 +573,7 @@ mod tests {
 
         // Test Blob Insert
         let hash = [1u8; 32];
-        let location = BlobLocation { tape_id: 100, offset: 200 };
+        let location = BlobLocation { tape_id: 100, offset: 200, block_size: 512 };
 
         let write_txn = db.begin_write()?;
         db.insert_blob(&write_txn, &hash, &location)?;
@@ -147,4 +585,126 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_legacy_blob_location() {
+        use rkyv::ser::Serializer;
+        let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
+        serializer.serialize_value(&LegacyBlobLocation { tape_id: 1, offset: 4096 }).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+
+        let location = decode_blob_location(&bytes);
+        assert_eq!(location, BlobLocation { tape_id: 1, offset: 4096, block_size: 0 });
+        assert_eq!(location.block_position(), None);
+    }
+
+    #[test]
+    fn test_legacy_index_entry_is_dirty() {
+        use rkyv::ser::Serializer;
+        let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
+        serializer.serialize_value(&LegacyIndexEntry { mtime: 1_700_000_000, size: 42, hash: [5u8; 32] }).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+
+        let entry = decode_index_entry(&bytes).unwrap();
+        assert_eq!((entry.mtime, entry.size, entry.hash), (1_700_000_000, 42, [5u8; 32]));
+        let meta = models::FileMetadata {
+            size: 42, mtime: 1_700_000_000, mtime_nsec: 0, mode: models::S_IFREG | 0o644, uid: 0, gid: 0,
+            ctime: 1_700_000_000, ctime_nsec: 0, dev: 1, ino: 7, nlink: 1, rdev: 0, content_hash: [5u8; 32],
+        };
+        assert!(!entry.matches(&meta));
+        // Values of an unknown layout are not trusted
+        assert_eq!(decode_index_entry(&[0u8; 13]), None);
+    }
+
+    #[test]
+    fn test_opens_database_with_string_index_keys() -> Result<()> {
+        use rkyv::ser::Serializer;
+        let temp_file = tempfile::NamedTempFile::new()?;
+        let mut serializer = rkyv::ser::serializers::AllocSerializer::<256>::default();
+        serializer.serialize_value(&LegacyIndexEntry { mtime: 1, size: 2, hash: [4u8; 32] }).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+        {
+            // The schema of the first releases
+            let db = Database::create(temp_file.path())?;
+            let txn = db.begin_write()?;
+            txn.open_table(LEGACY_INDEX_TABLE)?.insert("/mnt/share/a.txt", bytes.as_slice())?;
+            txn.commit()?;
+        }
+
+        let db = BackupDb::new(temp_file.path())?;
+        assert_eq!(db.get_index_entry(b"/mnt/share/a.txt")?.map(|e| e.hash), Some([4u8; 32]));
+        db.register_source("office", "/mnt/share", b"/mnt/share", &NamePolicy::default())?;
+        assert_eq!(db.get_index_entry(&models::index_key(1, b"a.txt"))?.map(|e| e.size), Some(2));
+        // Opening again finds the migrated table
+        drop(db);
+        assert_eq!(BackupDb::new(temp_file.path())?.count_index(1)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_existing_changes_nothing() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("backup.redb");
+        assert!(BackupDb::open_existing(&path).is_err());
+        assert!(!path.exists());
+
+        {
+            let db = Database::create(&path)?;
+            let txn = db.begin_write()?;
+            txn.open_table(LEGACY_INDEX_TABLE)?.insert("/mnt/share/a.txt", [0u8; 8].as_slice())?;
+            txn.commit()?;
+        }
+        // An old database is refused rather than migrated
+        assert!(BackupDb::open_existing(&path).is_err());
+        {
+            let db = Database::open(&path)?;
+            let txn = db.begin_read()?;
+            assert_eq!(txn.open_table(LEGACY_INDEX_TABLE)?.len()?, 1);
+        }
+
+        BackupDb::new(&path)?;
+        let db = BackupDb::open_existing(&path)?;
+        assert!(db.list_commits()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_source_migrates_absolute_keys() -> Result<()> {
+        let temp_file = tempfile::NamedTempFile::new()?;
+        let db = BackupDb::new(temp_file.path())?;
+        let entry = models::IndexEntry { mtime: 1, mtime_nsec: 0, ctime: 1, ctime_nsec: 0, ino: 1, size: 1, hash: [3u8; 32] };
+
+        let write_txn = db.begin_write()?;
+        db.insert_index(&write_txn, b"/mnt/share/docs/a.txt", &entry)?;
+        db.insert_index(&write_txn, b"/mnt/other/b.txt", &entry)?;
+        db.insert_index(&write_txn, br"\\nas\scans\2023\c.pdf", &entry)?;
+        write_txn.commit()?;
+
+        let source = db.register_source("office", "/mnt/share", b"/mnt/share", &NamePolicy::default())?;
+        assert_eq!(source.id, 1);
+        assert_eq!(db.get_index_entry(&models::index_key(1, b"docs/a.txt"))?, Some(entry));
+        assert_eq!(db.get_index_entry(b"/mnt/share/docs/a.txt")?, None);
+        // Paths of other shares are left alone
+        assert_eq!(db.get_index_entry(b"/mnt/other/b.txt")?, Some(entry));
+
+        // Registering again returns the same source
+        assert_eq!(db.register_source("office", "/srv/share", b"/srv/share", &NamePolicy::default())?, source);
+        assert_eq!(db.register_source("archive", "/mnt/other", b"/mnt/other", &NamePolicy::default())?.id, 2);
+
+        // Keys of Windows shares are not mistaken for keys of a source id
+        assert_eq!(models::split_index_key(br"C:\Users\a.txt"), None);
+        assert_eq!(models::split_index_key(&models::index_key(u64::MAX, b"a.txt")), Some((u64::MAX, &b"a.txt"[..])));
+        let scans = db.register_source("scans", r"\\nas\scans", br"\\nas\scans", &NamePolicy::default())?;
+        assert_eq!(db.get_index_entry(&models::index_key(scans.id, br"2023\c.pdf"))?, Some(entry));
+
+        // With case folding the raw root still matches, and the keys are the folded ones the diff engine looks up
+        let write_txn = db.begin_write()?;
+        db.insert_index(&write_txn, "/mnt/Finance/Q1/Report.XLSX".as_bytes(), &entry)?;
+        write_txn.commit()?;
+        let folding = NamePolicy { form: crate::normalize::NormalizationForm::Nfc, case_insensitive: true };
+        let finance = db.register_source("finance", "/mnt/Finance", b"/mnt/Finance", &folding)?;
+        assert_eq!(db.get_index_entry(&models::index_key(finance.id, b"q1/report.xlsx"))?, Some(entry));
+
+        Ok(())
+    }
 }
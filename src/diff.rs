@@ -1,36 +1,362 @@
 use crate::db::BackupDb;
-use crate::models::Hash;
-use anyhow::Result;
-use std::path::Path;
+use crate::models::{self, FileMetadata, Hash, TreeEntry};
+use crate::normalize::NamePolicy;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Files that differ between two snapshots, as paths relative to the source root.
+/// Directories are not listed themselves, only the files, symlinks and special files in them.
+#[derive(Debug, Default, PartialEq)]
+pub struct TreeChanges {
+    pub added: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+    /// (old path, new path) of files that moved with their content unchanged
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+impl TreeChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.deleted.is_empty() && self.renamed.is_empty()
+    }
+
+    /// Forgets the deletions of entries that are only missing from the new snapshot because
+    /// they, or a directory above them, could not be read. A file "renamed" from such an
+    /// entry counts as added.
+    pub fn ignore_unreadable<'p>(&mut self, unreadable: impl IntoIterator<Item = &'p Path>) {
+        let unreadable: HashSet<&Path> = unreadable.into_iter().collect();
+        if unreadable.is_empty() {
+            return;
+        }
+        let missing = |path: &Path| path.ancestors().any(|ancestor| unreadable.contains(ancestor));
+        self.deleted.retain(|path| !missing(path));
+        let (moved, readded): (Vec<_>, Vec<_>) = std::mem::take(&mut self.renamed)
+            .into_iter()
+            .partition(|(from, _)| !missing(from));
+        self.renamed = moved;
+        self.added.extend(readded.into_iter().map(|(_, to)| to));
+    }
+}
+
+/// An added or deleted entry; regular files with content can be matched up as renames
+struct Change {
+    path: PathBuf,
+    content: Option<Hash>,
+}
+
+#[derive(Default)]
+struct RawChanges {
+    added: Vec<Change>,
+    modified: Vec<PathBuf>,
+    deleted: Vec<Change>,
+}
 
 pub struct DiffEngine<'a> {
     db: &'a BackupDb,
+    policy: NamePolicy,
+    source_id: u64,
+    root: PathBuf,
+    rehash_days: u64,
+    rehash_day: u64,
 }
 
 impl<'a> DiffEngine<'a> {
     pub fn new(db: &'a BackupDb) -> Self {
-        Self { db }
+        Self { db, policy: NamePolicy::default(), source_id: 0, root: PathBuf::from("/"), rehash_days: 0, rehash_day: 0 }
+    }
+
+    /// Select clean files for rehashing in a rotation of `days` days, `day` being the
+    /// number of days since the UNIX epoch (0 days disables rehashing)
+    pub fn with_rehash(mut self, days: u32, day: u64) -> Self {
+        self.rehash_days = days as u64;
+        self.rehash_day = day;
+        self
+    }
+
+    /// Key index entries relative to `root`, in the namespace of source `source_id`
+    pub fn with_source(mut self, source_id: u64, root: &Path) -> Self {
+        self.source_id = source_id;
+        self.root = root.to_path_buf();
+        self
+    }
+
+    /// Derive index keys with the given normalization policy
+    pub fn with_name_policy(mut self, policy: NamePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Key of `path` in the `index` table
+    pub fn index_key(&self, path: &Path) -> Vec<u8> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        models::index_key(self.source_id, &self.policy.path_key(relative))
     }
 
     /// Checks if the file is unchanged based on the index.
+    /// Compares size, nanosecond mtime, ctime and inode of `meta` (its content hash is ignored).
     /// Returns Some(Hash) if the file is clean (unchanged).
     /// Returns None if the file is dirty (needs hashing).
-    pub fn check_index(&self, path: &Path, mtime: i64, size: u64) -> Result<Option<Hash>> {
-        let path_str = path.to_string_lossy();
-        // Note: We use the string representation of the path as the key.
-        // This assumes consistent path normalization.
-        if let Some(entry) = self.db.get_index_entry(&path_str)? {
-            if entry.mtime == mtime && entry.size == size {
+    pub fn check_index(&self, path: &Path, meta: &FileMetadata) -> Result<Option<Hash>> {
+        // Note: We use the bytes of the path relative to the source root as the key,
+        // so remounting the share elsewhere keeps the index valid, and names that are
+        // not valid UTF-8 neither get mangled nor collide. The name policy decides
+        // whether NFC/NFD or case variants of a name share an entry.
+        if let Some(entry) = self.db.get_index_entry(&self.index_key(path))? {
+            if entry.matches(meta) {
                 return Ok(Some(entry.hash));
             }
         }
         Ok(None)
     }
 
+    /// True if the clean file at `path` is due to be rehashed today. Every file falls
+    /// on one fixed day of the rotation, so all of them are verified once per rotation.
+    pub fn due_for_rehash(&self, path: &Path) -> bool {
+        if self.rehash_days == 0 {
+            return false;
+        }
+        let digest = blake3::hash(&self.index_key(path));
+        let slot = u64::from_le_bytes(digest.as_bytes()[..8].try_into().unwrap()) % self.rehash_days;
+        slot == self.rehash_day % self.rehash_days
+    }
+
     /// Checks if the blob with the given hash already exists in the backup (Deduplication).
     /// Returns true if the blob should be backed up (it's new).
     /// Returns false if the blob already exists.
     pub fn should_backup_blob(&self, hash: &Hash) -> Result<bool> {
         Ok(self.db.get_blob(hash)?.is_none())
     }
+
+    /// Compares the snapshot tree `new` with the previous one, `old` (None for a first backup).
+    /// Subtrees with equal hashes are skipped, so the cost follows the amount of change.
+    /// A deleted file whose content shows up under an added path is reported as renamed.
+    pub fn diff_trees(&self, old: Option<&Hash>, new: &Hash) -> Result<TreeChanges> {
+        let mut raw = RawChanges::default();
+        self.diff_dir(old, Some(new), Path::new(""), &mut raw)?;
+
+        // Pair deleted and added files with the same content, first come first served
+        let mut added_by_content: HashMap<Hash, Vec<usize>> = HashMap::new();
+        for (i, change) in raw.added.iter().enumerate().rev() {
+            if let Some(hash) = change.content {
+                added_by_content.entry(hash).or_default().push(i);
+            }
+        }
+        let mut moved = vec![false; raw.added.len()];
+        let mut changes = TreeChanges { modified: raw.modified, ..TreeChanges::default() };
+        for change in raw.deleted {
+            let target = change.content
+                .and_then(|hash| added_by_content.get_mut(&hash))
+                .and_then(|candidates| candidates.pop());
+            match target {
+                Some(i) => {
+                    moved[i] = true;
+                    changes.renamed.push((change.path, raw.added[i].path.clone()));
+                }
+                None => changes.deleted.push(change.path),
+            }
+        }
+        changes.added = raw.added.into_iter()
+            .zip(moved)
+            .filter(|(_, moved)| !moved)
+            .map(|(change, _)| change.path)
+            .collect();
+        Ok(changes)
+    }
+
+    fn diff_dir(&self, old: Option<&Hash>, new: Option<&Hash>, dir: &Path, raw: &mut RawChanges) -> Result<()> {
+        if old == new {
+            return Ok(());
+        }
+        let old_entries = match old {
+            Some(hash) => self.db.get_tree(hash)?.unwrap_or_else(|| {
+                // Snapshots taken before trees were stored: everything counts as added
+                warn!("Previous tree {} not found, comparing against an empty tree", hex::encode(hash));
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let new_entries = match new {
+            Some(hash) => self.db.get_tree(hash)?
+                .with_context(|| format!("Tree {} not found in database", hex::encode(hash)))?,
+            None => Vec::new(),
+        };
+
+        let mut previous: HashMap<Vec<u8>, &TreeEntry> = old_entries.iter()
+            .map(|entry| (self.policy.key(&entry.name).into_owned(), entry))
+            .collect();
+        for entry in &new_entries {
+            let path = dir.join(models::bytes_to_os(&entry.name));
+            match previous.remove(self.policy.key(&entry.name).as_ref()) {
+                Some(prev) if prev.is_dir() && entry.is_dir() => {
+                    self.diff_dir(Some(&prev.hash), Some(&entry.hash), &path, raw)?;
+                }
+                Some(prev) if !prev.is_dir() && !entry.is_dir() && same_kind(prev, entry) => {
+                    if prev.hash != entry.hash || prev.link_target != entry.link_target || prev.rdev != entry.rdev {
+                        raw.modified.push(path);
+                    }
+                }
+                Some(prev) => {
+                    // Replaced by an entry of another kind
+                    self.record(prev, dir.join(models::bytes_to_os(&prev.name)), false, raw)?;
+                    self.record(entry, path, true, raw)?;
+                }
+                None => self.record(entry, path, true, raw)?,
+            }
+        }
+        for prev in old_entries.iter().filter(|e| previous.contains_key(self.policy.key(&e.name).as_ref())) {
+            self.record(prev, dir.join(models::bytes_to_os(&prev.name)), false, raw)?;
+        }
+        Ok(())
+    }
+
+    /// Records an added (or deleted) entry, with everything below it if it is a directory
+    fn record(&self, entry: &TreeEntry, path: PathBuf, added: bool, raw: &mut RawChanges) -> Result<()> {
+        if entry.is_dir() {
+            let (old, new) = if added { (None, Some(&entry.hash)) } else { (Some(&entry.hash), None) };
+            return self.diff_dir(old, new, &path, raw);
+        }
+        // Empty files all share one hash, so they say nothing about where a file went
+        let content = (entry.is_file() && entry.size > 0).then_some(entry.hash);
+        let change = Change { path, content };
+        if added {
+            raw.added.push(change);
+        } else {
+            raw.deleted.push(change);
+        }
+        Ok(())
+    }
+}
+
+fn same_kind(a: &TreeEntry, b: &TreeEntry) -> bool {
+    a.is_file() == b.is_file() && a.is_symlink() == b.is_symlink()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::IndexEntry;
+
+    #[test]
+    fn test_same_second_edit_is_dirty() -> Result<()> {
+        let temp_file = tempfile::NamedTempFile::new()?;
+        let db = BackupDb::new(temp_file.path())?;
+        let path = Path::new("/share/report.txt");
+
+        let meta = FileMetadata {
+            size: 10,
+            mtime: 1_700_000_000,
+            mtime_nsec: 100,
+            mode: 0o100644,
+            uid: 1000,
+            gid: 1000,
+            ctime: 1_700_000_000,
+            ctime_nsec: 100,
+            dev: 1,
+            ino: 42,
+            nlink: 1,
+            rdev: 0,
+            content_hash: [7u8; 32],
+        };
+
+        let engine = DiffEngine::new(&db).with_source(1, Path::new("/share"));
+        let write_txn = db.begin_write()?;
+        db.insert_index(&write_txn, &engine.index_key(path), &IndexEntry::from_metadata(&meta))?;
+        write_txn.commit()?;
+
+        assert_eq!(engine.check_index(path, &meta)?, Some([7u8; 32]));
+
+        // Edited within the same second: only the nanoseconds differ
+        let edited = FileMetadata { mtime_nsec: 900, ctime_nsec: 900, ..meta };
+        assert_eq!(engine.check_index(path, &edited)?, None);
+
+        // mtime put back by the editing tool, but ctime still moved
+        let touched = FileMetadata { ctime: meta.ctime + 1, ..meta };
+        assert_eq!(engine.check_index(path, &touched)?, None);
+
+        // The same share mounted elsewhere finds the entry
+        let moved = DiffEngine::new(&db).with_source(1, Path::new("/mnt/share"));
+        assert_eq!(moved.check_index(Path::new("/mnt/share/report.txt"), &meta)?, Some([7u8; 32]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_trees_finds_deletions_and_renames() -> Result<()> {
+        let temp_file = tempfile::NamedTempFile::new()?;
+        let db = BackupDb::new(temp_file.path())?;
+
+        let file = |name: &str, content: &str| {
+            let meta = FileMetadata {
+                size: content.len() as u64,
+                mtime: 0,
+                mtime_nsec: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                ctime: 0,
+                ctime_nsec: 0,
+                dev: 0,
+                ino: 0,
+                nlink: 1,
+                rdev: 0,
+                content_hash: crate::object::hash_blob(content.as_bytes()),
+            };
+            TreeEntry::from_metadata(name.as_bytes().to_vec(), &meta)
+        };
+        let store = |entries: Vec<TreeEntry>| -> Result<Hash> {
+            let hashes: Vec<Hash> = entries.iter().map(TreeEntry::compute_hash).collect();
+            let hash = crate::object::hash_tree(&hashes);
+            let txn = db.begin_write()?;
+            db.insert_tree(&txn, &hash, &entries)?;
+            txn.commit()?;
+            Ok(hash)
+        };
+        let dir = |name: &str, hash: Hash| {
+            let mut entry = file(name, "");
+            entry.mode = 0o040755;
+            entry.hash = hash;
+            entry
+        };
+
+        let unchanged = store(vec![file("big.iso", "never touched")])?;
+        let old_docs = store(vec![file("a.txt", "alpha"), file("b.txt", "beta"), file("gone.txt", "old")])?;
+        let old_root = store(vec![dir("archive", unchanged), dir("docs", old_docs), file("todo.txt", "v1")])?;
+
+        // b.txt moved to the root, gone.txt deleted, todo.txt edited, new.txt created
+        let new_docs = store(vec![file("a.txt", "alpha")])?;
+        let new_root = store(vec![
+            dir("archive", unchanged),
+            file("b-moved.txt", "beta"),
+            dir("docs", new_docs),
+            file("new.txt", "fresh"),
+            file("todo.txt", "v2"),
+        ])?;
+
+        let changes = DiffEngine::new(&db).diff_trees(Some(&old_root), &new_root)?;
+        assert_eq!(changes, TreeChanges {
+            added: vec![PathBuf::from("new.txt")],
+            modified: vec![PathBuf::from("todo.txt")],
+            deleted: vec![PathBuf::from("docs/gone.txt")],
+            renamed: vec![(PathBuf::from("docs/b.txt"), PathBuf::from("b-moved.txt"))],
+        });
+
+        // A first backup adds everything
+        let first = DiffEngine::new(&db).diff_trees(None, &old_root)?;
+        assert_eq!(first.added.len(), 5);
+        assert!(DiffEngine::new(&db).diff_trees(Some(&new_root), &new_root)?.is_empty());
+
+        // If docs could not be listed, nothing below it was deleted or moved away
+        let mut unreadable = changes;
+        unreadable.ignore_unreadable([Path::new("docs")]);
+        assert_eq!(unreadable, TreeChanges {
+            added: vec![PathBuf::from("new.txt"), PathBuf::from("b-moved.txt")],
+            modified: vec![PathBuf::from("todo.txt")],
+            deleted: vec![],
+            renamed: vec![],
+        });
+
+        Ok(())
+    }
 }
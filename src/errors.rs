@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// What was being done when an entry could not be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Listing a directory; nothing below it is in the snapshot
+    List,
+    /// Reading the metadata of an entry; the entry is not in the snapshot
+    Stat,
+    ReadLink,
+    /// Reading extended attributes; the entry is stored without them
+    ReadXattrs,
+    /// Opening or reading a file to hash it; the file is not in the snapshot
+    Open,
+    Read,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::List => "list directory",
+            Operation::Stat => "stat",
+            Operation::ReadLink => "read link",
+            Operation::ReadXattrs => "read extended attributes",
+            Operation::Open => "open",
+            Operation::Read => "read",
+        })
+    }
+}
+
+/// An entry of the source that is missing from the snapshot, or stored incompletely,
+/// because it could not be read
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub operation: Operation,
+    pub kind: io::ErrorKind,
+    pub message: String,
+}
+
+impl ScanError {
+    /// Name of the error kind, e.g. "PermissionDenied"
+    pub fn kind_name(&self) -> String {
+        format!("{:?}", self.kind)
+    }
+}
+
+/// Collects the errors of a run from the scanner and pipeline threads
+#[derive(Debug, Clone, Default)]
+pub struct ErrorCollector(Arc<Mutex<Vec<ScanError>>>);
+
+impl ErrorCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, path: &Path, operation: Operation, error: &io::Error) {
+        warn!("Cannot {} {:?}: {}", operation, path, error);
+        self.0.lock().unwrap().push(ScanError {
+            path: path.to_path_buf(),
+            operation,
+            kind: error.kind(),
+            message: error.to_string(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes the errors collected so far, sorted by path
+    pub fn take(&self) -> Vec<ScanError> {
+        let mut errors = std::mem::take(&mut *self.0.lock().unwrap());
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        errors
+    }
+}
+
+/// Number of errors of each kind, by kind name
+pub fn count_by_kind(errors: &[ScanError]) -> BTreeMap<String, u64> {
+    let mut counts = BTreeMap::new();
+    for error in errors {
+        *counts.entry(error.kind_name()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// The run recorded its snapshot, but some entries of the source could not be read,
+/// so the snapshot is partial
+#[derive(Debug)]
+pub struct CompletedWithWarnings {
+    pub sources: Vec<String>,
+    /// Unreadable entries over all sources
+    pub errors: u64,
+}
+
+impl fmt::Display for CompletedWithWarnings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Backup of source(s) {} completed with warnings: {} entr{} could not be read, so the snapshot is partial",
+            self.sources.join(", "),
+            self.errors,
+            if self.errors == 1 { "y" } else { "ies" }
+        )
+    }
+}
+
+impl std::error::Error for CompletedWithWarnings {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collector_sorts_and_counts() {
+        let errors = ErrorCollector::new();
+        let clone = errors.clone();
+        std::thread::spawn(move || {
+            clone.record(Path::new("/share/b"), Operation::Stat, &io::Error::from(io::ErrorKind::NotFound));
+        })
+        .join()
+        .unwrap();
+        errors.record(Path::new("/share/a"), Operation::List, &io::Error::from(io::ErrorKind::PermissionDenied));
+        errors.record(Path::new("/share/c"), Operation::List, &io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(errors.len(), 3);
+
+        let taken = errors.take();
+        assert!(errors.is_empty());
+        let paths: Vec<&Path> = taken.iter().map(|e| e.path.as_path()).collect();
+        assert_eq!(paths, vec![Path::new("/share/a"), Path::new("/share/b"), Path::new("/share/c")]);
+        let counts = count_by_kind(&taken);
+        assert_eq!(counts["PermissionDenied"], 2);
+        assert_eq!(counts["NotFound"], 1);
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+
+/// Why a run stopped before it was done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// SIGINT (Ctrl-C) or SIGTERM (e.g. `systemctl stop`)
+    Signal,
+    /// The backup window set with `--max-duration` ended
+    Deadline,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StopReason::Signal => "interrupted by a signal",
+            StopReason::Deadline => "the backup window ended",
+        })
+    }
+}
+
+/// Tells the stages of a run to wind down: the pipeline stops listing and hashing,
+/// the tape writer stops after the entry it is writing and finishes the archive.
+/// Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct StopSignal {
+    signalled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl StopSignal {
+    /// A signal that only stops when asked to
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also stops once `max_duration` has passed from now; one too long to represent never passes
+    pub fn with_max_duration(mut self, max_duration: Option<Duration>) -> Self {
+        self.deadline = max_duration.and_then(|d| Instant::now().checked_add(d));
+        self
+    }
+
+    /// Stops on the first SIGINT or SIGTERM; a second one ends the process at once
+    #[cfg(unix)]
+    pub fn install_handlers(&self) -> Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        for signal in [SIGINT, SIGTERM] {
+            // Registered first, so it only sees the flag set by an earlier signal
+            signal_hook::flag::register_conditional_shutdown(signal, 130, self.signalled.clone())?;
+            signal_hook::flag::register(signal, self.signalled.clone())?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn install_handlers(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stops as if a signal had arrived
+    pub fn stop(&self) {
+        self.signalled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reason(&self) -> Option<StopReason> {
+        if self.signalled.load(Ordering::Relaxed) {
+            Some(StopReason::Signal)
+        } else if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Some(StopReason::Deadline)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.reason().is_some()
+    }
+}
+
+/// Parses a duration like "90m", "6h", "45s" or "1d"
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let unit = match text.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 24 * 3600,
+        _ => return Err(format!("expected a number with a unit (s, m, h or d), like '6h', got '{}'", text)),
+    };
+    let count: u64 = text[..text.len() - 1].parse().map_err(|_| format!("invalid duration '{}'", text))?;
+    let secs = count.checked_mul(unit).ok_or_else(|| format!("duration '{}' is too long", text))?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// The run stopped early; what was fully written is recorded in partial snapshots
+#[derive(Debug)]
+pub struct Interrupted {
+    pub sources: Vec<String>,
+    pub reason: StopReason,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Backup of source(s) {} stopped early ({}); the files written so far are recorded in a partial snapshot",
+            self.sources.join(", "),
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_reasons() {
+        let signal = StopSignal::new();
+        assert_eq!(signal.reason(), None);
+        let clone = signal.clone();
+        clone.stop();
+        assert_eq!(signal.reason(), Some(StopReason::Signal));
+
+        let window = StopSignal::new().with_max_duration(Some(Duration::ZERO));
+        assert_eq!(window.reason(), Some(StopReason::Deadline));
+        assert!(!StopSignal::new().with_max_duration(Some(Duration::from_secs(3600))).is_stopped());
+
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("6h"), Ok(Duration::from_secs(6 * 3600)));
+        assert!(parse_duration("6").is_err());
+        assert!(parse_duration("xh").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
+        let far = parse_duration("18446744073709551615s").unwrap();
+        assert!(!StopSignal::new().with_max_duration(Some(far)).is_stopped());
+    }
+}
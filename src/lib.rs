@@ -1,8 +1,22 @@
 // Library interface for rust-ltfs-git
 pub mod models;
+pub mod object;
 pub mod db;
 pub mod scanner;
 pub mod pipeline;
 pub mod diff;
 pub mod tape;
 pub mod config;
+pub mod restore;
+pub mod xattrs;
+pub mod sparse;
+pub mod normalize;
+pub mod spill;
+pub mod staging;
+pub mod anomaly;
+pub mod plan;
+pub mod report;
+pub mod progress;
+pub mod errors;
+pub mod retry;
+pub mod interrupt;
@@ -1,14 +1,22 @@
-mod models;
-mod db;
-mod scanner;
-mod pipeline;
-mod diff;
-mod tape;
-mod config;
-
-use anyhow::Result;
+use rumba::{anomaly, config, db, diff, errors, interrupt, models, retry, pipeline, plan, progress, report, restore, spill, staging, tape};
+use rumba::normalize::NamePolicy;
+
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
-use tracing::info;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Exit status of a run that recorded partial snapshots because entries could not be read
+const EXIT_WARNINGS: i32 = 2;
+
+/// Exit status of a run whose snapshot was held back by the anomaly checks
+const EXIT_ANOMALY: i32 = 3;
+
+/// Exit status of a run stopped by SIGINT/SIGTERM or the end of its backup window
+const EXIT_INTERRUPTED: i32 = 4;
 
 /// Rumba Backup Tool - High-performance incremental backup for LTO tape
 #[derive(Parser, Debug)]
@@ -30,6 +38,87 @@ enum Commands {
         /// Password to encode
         password: String,
     },
+    /// Back up configured sources (the default when no command is given)
+    Backup {
+        /// Source to back up
+        #[arg(short, long, conflicts_with = "all")]
+        source: Option<String>,
+        /// Back up every source that is due according to its schedule
+        #[arg(long)]
+        all: bool,
+        /// Make the snapshot the latest one even if the ransomware checks flag it
+        #[arg(long)]
+        accept_anomalies: bool,
+        /// Only report what would be written; touch neither the tape nor the database
+        #[arg(long)]
+        dry_run: bool,
+        /// Stop after this long (e.g. "6h") and record what was written as a partial snapshot
+        #[arg(long, value_parser = interrupt::parse_duration)]
+        max_duration: Option<Duration>,
+    },
+    /// Plan a backup of one source and save the plan for review, like `backup --dry-run`
+    Plan {
+        /// File to write the plan to (JSON)
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Source to plan; required if several are configured
+        #[arg(short, long)]
+        source: Option<String>,
+    },
+    /// Execute a plan saved by `rumba plan`, provided none of its files changed since
+    Apply {
+        /// Plan file written by `rumba plan`
+        plan: PathBuf,
+        /// Make the snapshot the latest one even if the ransomware checks flag it
+        #[arg(long)]
+        accept_anomalies: bool,
+        /// Stop after this long (e.g. "6h") and record what was written as a partial snapshot
+        #[arg(long, value_parser = interrupt::parse_duration)]
+        max_duration: Option<Duration>,
+    },
+    /// Restore a snapshot from tar archives
+    Restore {
+        /// Directory to restore into
+        #[arg(short, long)]
+        target: PathBuf,
+        /// Tar archive(s) written by previous backup runs
+        #[arg(short, long = "archive", required = true)]
+        archives: Vec<PathBuf>,
+        /// Commit hash (or unique prefix) to restore; defaults to the latest commit of the source
+        #[arg(long)]
+        commit: Option<String>,
+        /// Source whose latest commit is restored; required if several are configured
+        #[arg(short, long)]
+        source: Option<String>,
+    },
+    /// Show the recorded backup runs, newest first
+    Log {
+        /// Only show runs of this source
+        #[arg(short, long)]
+        source: Option<String>,
+        /// Number of runs to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Manage the backup sources recorded in the database
+    Source {
+        #[command(subcommand)]
+        command: SourceCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SourceCommands {
+    /// List registered sources
+    List,
+    /// Rebind a source to a new share URL or mount point, keeping its index
+    Relocate {
+        /// New share URL
+        url: String,
+        /// Source to relocate; defaults to the configured source
+        #[arg(long)]
+        name: Option<String>,
+    },
 }
 
 fn main() -> Result<()> {
@@ -37,16 +126,12 @@ fn main() -> Result<()> {
     
     let cli = Cli::parse();
     
-    // Handle subcommands
-    if let Some(command) = cli.command {
-        match command {
-            Commands::EncodePassword { password } => {
-                let encoded = config::encode_password(&password);
-                println!("Encoded password for config file:");
-                println!("{}", encoded);
-                return Ok(());
-            }
-        }
+    // Handle subcommands that need no configuration
+    if let Some(Commands::EncodePassword { password }) = &cli.command {
+        let encoded = config::encode_password(password);
+        println!("Encoded password for config file:");
+        println!("{}", encoded);
+        return Ok(());
     }
     
     info!("Rumba Backup Tool Initialized");
@@ -54,30 +139,698 @@ fn main() -> Result<()> {
     // Load configuration
     let config = config::Config::from_file(&cli.config)?;
     info!("Configuration loaded from: {}", cli.config);
-    info!("Source: {}", config.source.url);
-    info!("Username: {}", config.source.username);
 
-    
-    // 1. Initialize Infrastructure
-    let db = db::BackupDb::new(&config.target.db_path)?;
+    let result = match cli.command {
+        Some(Commands::Restore { target, archives, commit, source }) => {
+            run_restore(&config, &target, archives, commit.as_deref(), source.as_deref())
+        }
+        Some(Commands::Source { command }) => run_source(&config, &cli.config, command),
+        Some(Commands::Log { source, limit }) => run_log(&config, source.as_deref(), limit),
+        Some(Commands::Backup { source, all, accept_anomalies, dry_run, max_duration }) => {
+            let options = BackupOptions { accept_anomalies, dry_run, max_duration, ..BackupOptions::default() };
+            run_backups(&config, source.as_deref(), all, options)
+        }
+        Some(Commands::Plan { output, source }) => {
+            let options = BackupOptions { dry_run: true, plan_output: Some(&output), ..BackupOptions::default() };
+            run_backups(&config, source.as_deref(), false, options)
+        }
+        Some(Commands::Apply { plan, accept_anomalies, max_duration }) => {
+            let plan = plan::PlanFile::load(&plan)?;
+            let options = BackupOptions { accept_anomalies, apply: Some(&plan), max_duration, ..BackupOptions::default() };
+            run_backups(&config, Some(&plan.source), false, options)
+        }
+        _ => run_backups(&config, None, false, BackupOptions::default()),
+    };
+    // Alerting tells held-back, interrupted and partial snapshots apart from a failed run by the exit status
+    if let Err(e) = &result {
+        if let Some(anomaly) = e.downcast_ref::<anomaly::AnomalyDetected>() {
+            tracing::error!("{}", anomaly);
+            std::process::exit(EXIT_ANOMALY);
+        }
+        if let Some(interrupted) = e.downcast_ref::<interrupt::Interrupted>() {
+            warn!("{}", interrupted);
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        if let Some(warnings) = e.downcast_ref::<errors::CompletedWithWarnings>() {
+            warn!("{}", warnings);
+            std::process::exit(EXIT_WARNINGS);
+        }
+    }
+    result
+}
+
+/// Per-run switches of `rumba backup`, `plan` and `apply`
+#[derive(Debug, Clone, Copy, Default)]
+struct BackupOptions<'a> {
+    /// Advance the ref even if the anomaly checks flag the snapshot
+    accept_anomalies: bool,
+    /// Report what would be written instead of writing it
+    dry_run: bool,
+    /// Save the plan of a dry run to this file
+    plan_output: Option<&'a Path>,
+    /// Write exactly this reviewed plan
+    apply: Option<&'a plan::PlanFile>,
+    /// Backup window: stop this long after the start
+    max_duration: Option<Duration>,
+}
+
+/// Backs up the source called `name`, every due source (`all`), or the only configured one
+fn run_backups(config: &config::Config, name: Option<&str>, all: bool, options: BackupOptions<'_>) -> Result<()> {
+    // 1. Initialize Infrastructure (a dry run only reads an existing database)
+    let db = if options.dry_run {
+        db::BackupDb::open_existing(&config.target.db_path)?
+    } else {
+        db::BackupDb::new(&config.target.db_path)?
+    };
     info!("Database initialized at {}", config.target.db_path);
 
+    let selected: Vec<&config::SourceConfig> = match name {
+        Some(name) => vec![config.source(name)?],
+        None if all => {
+            let mut due = Vec::new();
+            for source in &config.sources {
+                if is_due(&db, source)? {
+                    due.push(source);
+                } else {
+                    info!("Source '{}' is not due yet (schedule: {}), skipping", source.name, source.schedule.as_deref().unwrap_or_default());
+                }
+            }
+            due
+        }
+        None if config.sources.len() == 1 => vec![&config.sources[0]],
+        None => bail!(
+            "{} sources are configured; choose one with --source NAME or back up all of them with --all",
+            config.sources.len()
+        ),
+    };
+
+    // The window covers all sources of this run; a signal ends the run cleanly
+    let stop = interrupt::StopSignal::new().with_max_duration(options.max_duration);
+    stop.install_handlers()?;
+
+    if selected.len() == 1 {
+        return run_reported_backup(config, &db, selected[0], options, &stop);
+    }
+
+    // One failing share must not keep the others from being backed up
+    let mut failed = Vec::new();
+    let mut held = Vec::new();
+    let mut interrupted = Vec::new();
+    let mut partial = errors::CompletedWithWarnings { sources: Vec::new(), errors: 0 };
+    for source in selected {
+        if let Some(reason) = stop.reason() {
+            warn!("Not starting the backup of source '{}' ({})", source.name, reason);
+            interrupted.push(source.name.clone());
+            continue;
+        }
+        match run_reported_backup(config, &db, source, options, &stop) {
+            Ok(()) => {}
+            Err(e) if e.is::<anomaly::AnomalyDetected>() => held.push(source.name.clone()),
+            Err(e) if e.is::<interrupt::Interrupted>() => interrupted.push(source.name.clone()),
+            Err(e) if e.is::<errors::CompletedWithWarnings>() => {
+                let warnings = e.downcast::<errors::CompletedWithWarnings>().unwrap();
+                partial.sources.extend(warnings.sources);
+                partial.errors += warnings.errors;
+            }
+            Err(e) => {
+                tracing::error!("Backup of source '{}' failed: {:#}", source.name, e);
+                failed.push(source.name.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        bail!("Backup failed for source(s): {}", failed.join(", "));
+    }
+    if !held.is_empty() {
+        return Err(anomaly::AnomalyDetected { sources: held }.into());
+    }
+    if let Some(reason) = stop.reason().filter(|_| !interrupted.is_empty()) {
+        return Err(interrupt::Interrupted { sources: interrupted, reason }.into());
+    }
+    if !partial.sources.is_empty() {
+        return Err(partial.into());
+    }
+    Ok(())
+}
+
+/// True if the schedule of `source` has elapsed since its latest commit
+fn is_due(db: &db::BackupDb, source: &config::SourceConfig) -> Result<bool> {
+    let Some(interval) = source.schedule_interval()? else {
+        return Ok(true);
+    };
+    let Some(last) = db.get_ref_commit(&source.name)? else {
+        return Ok(true);
+    };
+    let now = chrono::Utc::now().timestamp() as u64;
+    Ok(now.saturating_sub(last.timestamp) >= interval.as_secs())
+}
+
+/// Backs up one source and records how the run went: a JSON report at `backup.report_path`
+/// and a summary in the database for `rumba log`. Dry runs are not recorded.
+fn run_reported_backup(
+    config: &config::Config,
+    db: &db::BackupDb,
+    source_config: &config::SourceConfig,
+    options: BackupOptions<'_>,
+    stop: &interrupt::StopSignal,
+) -> Result<()> {
+    let mut report = report::RunReport::start(&source_config.name);
+    let result = run_backup(config, db, source_config, options, stop, &mut report);
+    if options.dry_run {
+        return result;
+    }
+    let status = match &result {
+        Ok(()) => report::RunStatus::Completed,
+        Err(e) if e.is::<anomaly::AnomalyDetected>() => report::RunStatus::Held,
+        Err(e) if e.is::<errors::CompletedWithWarnings>() => report::RunStatus::Partial,
+        Err(e) if e.is::<interrupt::Interrupted>() => report::RunStatus::Interrupted,
+        Err(e) => {
+            report.error(format!("{:#}", e));
+            report::RunStatus::Failed
+        }
+    };
+    report.finish(status);
+    // The backup itself is done (or failed for its own reasons) either way
+    if let Err(e) = save_report(config, db, &report) {
+        warn!("Cannot record the report of the run of source '{}': {:#}", source_config.name, e);
+    }
+    result
+}
+
+fn save_report(config: &config::Config, db: &db::BackupDb, report: &report::RunReport) -> Result<()> {
+    db.record_run(&report.summary())?;
+    if let Some(template) = &config.backup.report_path {
+        let path = report.output_path(template);
+        report.save(&path)?;
+        info!("Run report written to {}", path.display());
+    }
+    Ok(())
+}
+
+fn run_backup(
+    config: &config::Config,
+    db: &db::BackupDb,
+    source_config: &config::SourceConfig,
+    options: BackupOptions<'_>,
+    stop: &interrupt::StopSignal,
+    report: &mut report::RunReport,
+) -> Result<()> {
+    info!("Source: {} ({})", source_config.name, source_config.url);
+    info!("Username: {}", source_config.username);
+
     // 2. Run Pipeline (Scan -> Diff -> Plan)
-    let root_path = config.get_backup_root()?;
+    let root_path = source_config.get_backup_root()?;
     info!("Starting backup for root: {:?}", root_path);
+
+    // Index keys are relative to the root of the source, so the source must still be bound there
+    let name_policy = NamePolicy::from_config(&config.backup)?;
+    let root_bytes = models::os_to_bytes(root_path.as_os_str());
+    let source = match db.get_source(&source_config.name)? {
+        // A dry run registers nothing; a new source gets an id no index entry uses
+        None if options.dry_run => models::Source {
+            id: u64::MAX,
+            name: source_config.name.clone(),
+            url: source_config.url.clone(),
+            root: root_bytes.clone(),
+        },
+        _ => db.register_source(&source_config.name, &source_config.url, &root_bytes, &name_policy)?,
+    };
+    if source.root != root_bytes {
+        bail!(
+            "Source '{}' is bound to {}, but the configuration points to {}. If the share has moved, run `rumba source relocate --name {} {}`",
+            source.name, source.url, source_config.url, source.name, source_config.url
+        );
+    }
+    info!("Source: {} (id {})", source.name, source.id);
     
-    let pipeline = pipeline::Pipeline::new(db.clone(), root_path.clone());
-    let plan = pipeline.run()?;
+    // The parent is the previous snapshot of this source; databases from before
+    // refs existed hold a single source, whose parent is the latest commit. An
+    // interrupted first run leaves a partial commit but no ref, and is no parent.
+    let parent = match db.get_ref_commit(&source.name)? {
+        Some(parent) => Some(parent),
+        None if !db.has_refs()? && !db.has_partials()? => db.get_latest_commit()?,
+        None => None,
+    };
+    let parent_hash = parent.as_ref().map(|c| c.compute_hash());
+
+    // A reviewed plan is only valid for the snapshot and the files it was made from
+    if let Some(reviewed) = options.apply {
+        if reviewed.parent != parent_hash.map(hex::encode) {
+            bail!("Source '{}' was backed up after the plan was made; make a new plan", source.name);
+        }
+        let changed = reviewed.changed_files(&root_path);
+        if let Some(first) = changed.first() {
+            bail!("{} planned file(s) changed since the plan was made, e.g. {}; make a new plan", changed.len(), first.display());
+        }
+    }
+
+    let interval = config.backup.progress_interval_secs;
+    let progress = progress::ProgressDisplay::start(&source.name, (interval > 0).then(|| Duration::from_secs(interval)));
+    let pipeline = pipeline::Pipeline::new(db.clone(), root_path.clone())
+        .with_progress(progress.observer())
+        .with_config(&config.backup)
+        .with_source(source.id)
+        .with_excludes(&source_config.exclude)
+        .with_previous_tree(parent.as_ref().map(|c| c.tree_hash))
+        .with_dry_run(options.dry_run)
+        .with_stop(stop.clone());
+
+    if options.dry_run {
+        let plan = pipeline.run()?;
+        progress.finish();
+        if let Some(reason) = stop.reason() {
+            bail!("The dry run of source '{}' was stopped ({}) before it was done", source.name, reason);
+        }
+        return report_dry_run(config, source_config, &root_path, parent_hash, plan, options.plan_output);
+    }
+
+    let scan_started = Instant::now();
+    let (mut plan, mut written) = match options.apply {
+        // Nothing reaches the tape unless the run matches the reviewed plan
+        Some(reviewed) => {
+            let mut plan = pipeline.run()?;
+            report.phase("scan", scan_started.elapsed());
+            reviewed.check_blobs(&mut plan.new_files)?;
+            let written = write_planned(config, &source.name, &mut plan.new_files, progress.observer(), stop)?;
+            (plan, written)
+        }
+        // The tape writer runs alongside the pipeline and starts with the first new blob
+        None => {
+            let (blob_tx, blob_rx) = std::sync::mpsc::sync_channel(pipeline::BLOB_QUEUE_SIZE);
+            std::thread::scope(|s| -> Result<_> {
+                let writer = s.spawn(|| write_to_tape(config, &source.name, blob_rx, progress.observer(), stop));
+                let plan = pipeline.run_streaming(blob_tx);
+                report.phase("scan", scan_started.elapsed());
+                // A failed writer makes the pipeline fail too; report the writer's error
+                let written = writer.join().expect("tape writer panicked")?;
+                Ok((plan?, written))
+            })?
+        }
+    };
+    progress.finish();
+    // Files that could no longer be opened for the tape write are missing like unreadable ones
+    if let Some(written) = &mut written {
+        if !written.errors.is_empty() {
+            plan.errors.append(&mut written.errors);
+            plan.errors.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+    }
+    report.add_plan(&plan);
+    if let Some(written) = &written {
+        report.phase("tape", written.duration);
+        report.add_tape(written.usage.clone());
+    }
     
     info!("Backup Plan Generated:");
     info!("  New Files: {}", plan.new_files.len());
     info!("  Total Size: {} bytes", plan.total_size);
 
-    if plan.new_files.is_empty() {
+    if written.is_none() {
         info!("Nothing to backup.");
-        return Ok(());
     }
 
+    // A stopped run keeps what reached the tape: files whose content did not are left out
+    // of the snapshot, which is recorded as partial but not made the latest one.
+    // Files skipped by the tape writer are left out the same way.
+    let skipped: HashSet<models::Hash> = written.iter().flat_map(|w| w.skipped.iter().copied()).collect();
+    let mut unwritten = HashSet::new();
+    if stop.is_stopped() || !skipped.is_empty() {
+        let mut stored = HashSet::new();
+        if let Some(written) = &mut written {
+            for location in written.locations.locations()? {
+                stored.insert(location?.0);
+            }
+        }
+        for blob in plan.new_files.iter()? {
+            let blob = blob?;
+            if !stored.contains(&blob.hash) {
+                report.files.new -= 1;
+                report.bytes.new -= blob.size;
+                unwritten.insert(blob.hash);
+            }
+        }
+        plan.root_tree = pipeline::drop_unwritten(db, plan.root_tree, &unwritten)?;
+    }
+    // Stopping after everything was planned and written cut nothing short
+    let not_reached = unwritten.len() - skipped.len();
+    let interrupted = stop.reason().filter(|_| plan.stopped || not_reached > 0);
+    if let Some(reason) = interrupted {
+        warn!("The run was stopped ({}); recording what was written as a partial snapshot", reason);
+        if not_reached > 0 {
+            warn!("  {} file(s) planned but not written are left out", not_reached);
+        }
+    }
+
+    // What changed since the parent snapshot (a partial tree would show everything it lacks as deleted)
+    let diff_started = Instant::now();
+    let diff_engine = diff::DiffEngine::new(db)
+        .with_name_policy(name_policy)
+        .with_source(source.id, &root_path);
+    let mut changes = match interrupted {
+        Some(_) => diff::TreeChanges::default(),
+        None => diff_engine.diff_trees(parent.as_ref().map(|c| &c.tree_hash), &plan.root_tree)?,
+    };
+    // Entries that could not be read are missing from the tree, not deleted from the share:
+    // they are neither reported nor counted by the anomaly checks, and keep their index rows
+    changes.ignore_unreadable(plan.errors.iter()
+        .filter(|error| error.operation != errors::Operation::ReadXattrs)
+        .filter_map(|error| error.path.strip_prefix(&root_path).ok()));
+    report.phase("diff", diff_started.elapsed());
+
+    // A snapshot that looks like the share was encrypted is stored but not made the latest
+    // one, so the next run still compares against the last good snapshot
+    let mut hold = false;
+    if config.anomaly.enabled && parent.is_some() && interrupted.is_none() {
+        let checks_started = Instant::now();
+        let detector = anomaly::AnomalyDetector::new(&config.anomaly)?;
+        let findings = detector.check(&root_path, &changes, db.count_index(source.id)?);
+        report.phase("anomaly_checks", checks_started.elapsed());
+        for finding in &findings.findings {
+            warn!("Anomaly: {}", finding);
+        }
+        if findings.is_suspicious() {
+            if options.accept_anomalies {
+                warn!("Anomalies accepted, the snapshot becomes the latest one");
+            } else {
+                hold = true;
+            }
+        }
+    }
+    let commit_started = Instant::now();
+    let timestamp = chrono::Utc::now().timestamp() as u64;
+    let commit_key = db.free_commit_key(timestamp)?;
+
+    // 5. Commit Metadata (Phase 3: Commit Index)
+    let write_txn = db.begin_write()?;
+    
+    // 5.1 Update Blobs
+    if let Some(written) = &mut written {
+        for location in written.locations.locations()? {
+            let (hash, location) = location?;
+            db.insert_blob(&write_txn, &hash, &location)?;
+        }
+    }
+
+    // Keep the last good content of files that changed silently (possible corruption)
+    for change in &plan.silent_changes {
+        let relative = change.path.strip_prefix(&root_path).unwrap_or(&change.path);
+        let protected = models::ProtectedBlob {
+            path: models::os_to_bytes(relative.as_os_str()),
+            source_id: source.id,
+            detected: timestamp,
+            replaced_by: change.current,
+        };
+        db.protect_blob(&write_txn, &change.previous, &protected)?;
+    }
+
+    // 5.2 Record the snapshot (its trees were stored by the pipeline)
+
+    let commit = models::Commit {
+        tree_hash: plan.root_tree,
+        parent_hash,
+        author: source_config.username.clone(),
+        message: format!("Backup of {}", source_config.url),
+        timestamp,
+    };
+    db.insert_commit(&write_txn, commit_key, &commit)?;
+    report.set_commit(&commit.compute_hash());
+    // Entries that could not be read, or were not reached, are missing from the snapshot; restores say so
+    if !plan.errors.is_empty() || interrupted.is_some() {
+        let mut reasons = Vec::new();
+        if let Some(reason) = interrupted {
+            reasons.push(format!("stopped early: {}", reason));
+        }
+        if !plan.errors.is_empty() {
+            let n = plan.errors.len();
+            reasons.push(format!("{} entr{} could not be read", n, if n == 1 { "y" } else { "ies" }));
+        }
+        let partial = models::PartialSnapshot {
+            errors: plan.errors.len() as u64,
+            reason: reasons.join("; "),
+        };
+        db.mark_partial(&write_txn, commit_key, &partial)?;
+    }
+    if hold {
+        // Blobs on tape stay recorded; the ref and the index keep describing the last good snapshot
+        write_txn.commit()?;
+        report.phase("commit", commit_started.elapsed());
+        warn!("Snapshot {} of source '{}' was held back", hex::encode(commit.compute_hash()), source.name);
+        return Err(anomaly::AnomalyDetected { sources: vec![source.name.clone()] }.into());
+    }
+    if let Some(reason) = interrupted {
+        // The files that reached the tape are indexed, so the next run does not hash them again;
+        // the ref keeps pointing at the last complete snapshot
+        for update in plan.new_files.index_entries()? {
+            let (key, entry) = update?;
+            if !unwritten.contains(&entry.hash) {
+                db.insert_index(&write_txn, &key, &entry)?;
+            }
+        }
+        write_txn.commit()?;
+        report.phase("commit", commit_started.elapsed());
+        warn!(
+            "Partial snapshot {} of source '{}' was recorded; restore it with --commit",
+            hex::encode(commit.compute_hash()),
+            source.name
+        );
+        return Err(interrupt::Interrupted { sources: vec![source.name.clone()], reason }.into());
+    }
+    db.set_ref(&write_txn, &source.name, commit_key)?;
+
+    // 5.3 Update the Index for every file hashed in this run, so next time they are skipped.
+    // This includes copies of content that was already stored, but not files left out of the snapshot.
+    let mut indexed = 0;
+    for update in plan.new_files.index_entries()? {
+        let (key, entry) = update?;
+        if !unwritten.contains(&entry.hash) {
+            db.insert_index(&write_txn, &key, &entry)?;
+            indexed += 1;
+        }
+    }
+    // and prune the entries of files that are gone
+    for path in changes.deleted.iter().chain(changes.renamed.iter().map(|(from, _)| from)) {
+        db.remove_index(&write_txn, &diff_engine.index_key(&root_path.join(path)))?;
+    }
+
+    write_txn.commit()?;
+    report.phase("commit", commit_started.elapsed());
+    info!("Database commit successful.");
+    info!("Commit: {}", hex::encode(commit.compute_hash()));
+    
+    // Print backup summary
+    info!("");
+    info!("========================================");
+    if plan.errors.is_empty() {
+        info!("BACKUP COMPLETED SUCCESSFULLY");
+    } else {
+        warn!("BACKUP COMPLETED WITH WARNINGS (partial snapshot)");
+    }
+    info!("========================================");
+    info!("Configuration:");
+    info!("  Source: {} ({})", source.name, source_config.url);
+    info!("  Output mode: {}", config.target.output_mode);
+    if config.target.output_mode == "rustltfs" {
+        info!("  Rustltfs: {}", config.target.rustltfs_path);
+        info!("  Device: {}", config.target.tape_path);
+    } else {
+        info!("  Tar file: {}", config.target.tape_path);
+    }
+    info!("");
+    info!("Backup Summary:");
+    info!("  Total files backed up: {}", report.files.new);
+    info!("  Total data size: {} bytes ({:.2} MB)", 
+        report.bytes.new,
+        report.bytes.new as f64 / 1024.0 / 1024.0
+    );
+    info!("  Unique blobs stored: {}", report.files.new); // TODO: count unique hashes
+    info!("  Files deduplicated: {}", plan.deduplicated);
+    info!("  Index entries updated: {}", indexed);
+    info!("  Unchanged directories reused: {}", plan.reused_trees);
+    if plan.excluded > 0 {
+        info!("  Entries excluded: {}", plan.excluded);
+    }
+    if !plan.errors.is_empty() {
+        let kinds: Vec<String> = errors::count_by_kind(&plan.errors).into_iter()
+            .map(|(kind, count)| format!("{} {}", count, kind))
+            .collect();
+        warn!("  Entries that could not be read: {} ({})", plan.errors.len(), kinds.join(", "));
+        for error in &plan.errors {
+            warn!("    ! {} (cannot {}: {})", error.path.display(), error.operation, error.message);
+        }
+    }
+    if config.backup.rehash_days > 0 {
+        info!("  Unchanged files verified by rehashing: {}", plan.rehashed);
+    }
+    if !plan.silent_changes.is_empty() {
+        warn!("  Content changed without a metadata change (possible corruption): {}", plan.silent_changes.len());
+        for change in &plan.silent_changes {
+            warn!("    ! {} (previous content {} is protected)", change.path.display(), &hex::encode(change.previous)[..16]);
+        }
+    }
+    info!("  Changes since the previous snapshot: {} added, {} modified, {} deleted, {} renamed",
+        changes.added.len(),
+        changes.modified.len(),
+        changes.deleted.len(),
+        changes.renamed.len()
+    );
+    for path in &changes.added {
+        info!("    + {}", path.display());
+    }
+    for path in &changes.modified {
+        info!("    ~ {}", path.display());
+    }
+    for path in &changes.deleted {
+        info!("    - {}", path.display());
+    }
+    for (from, to) in &changes.renamed {
+        info!("    > {} -> {}", from.display(), to.display());
+    }
+    if !plan.name_collisions.is_empty() {
+        info!("  Name collisions after normalization: {}", plan.name_collisions.len());
+        for (first, second) in &plan.name_collisions {
+            info!("    ! {} <-> {}", first.display(), second.display());
+        }
+    }
+    info!("");
+    info!("Files backed up:");
+    for blob in plan.new_files.iter()? {
+        let blob = blob?;
+        if unwritten.contains(&blob.hash) {
+            continue;
+        }
+        info!("  • {} ({} bytes, hash: {}...)", 
+            blob.path.display(),
+            blob.size,
+            &hex::encode(blob.hash)[..16]
+        );
+    }
+    info!("========================================");
+
+    if !plan.errors.is_empty() {
+        return Err(errors::CompletedWithWarnings {
+            sources: vec![source.name.clone()],
+            errors: plan.errors.len() as u64,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Prints what a backup would write and optionally saves it as a plan for `rumba apply`
+fn report_dry_run(
+    config: &config::Config,
+    source_config: &config::SourceConfig,
+    root_path: &Path,
+    parent_hash: Option<models::Hash>,
+    mut plan: pipeline::BackupPlan,
+    output: Option<&Path>,
+) -> Result<()> {
+    let mut files = Vec::new();
+    for blob in plan.new_files.iter()? {
+        files.push(plan::PlannedFile::from_blob(root_path, &blob?)?);
+    }
+    let estimated = tape::estimate_stream_size(files.iter().map(|f| f.size), config.target.block_size);
+
+    info!("");
+    info!("========================================");
+    info!("DRY RUN: nothing was written");
+    info!("========================================");
+    info!("  Source: {} ({})", source_config.name, source_config.url);
+    info!("  Files to write: {}", files.len());
+    info!("  Data size: {} bytes ({:.2} MB)", plan.total_size, plan.total_size as f64 / 1024.0 / 1024.0);
+    info!("  Estimated on tape: {} bytes ({:.2} MB)", estimated, estimated as f64 / 1024.0 / 1024.0);
+    info!("  Files deduplicated: {}", plan.deduplicated);
+    info!("  Index entries to update: {}", plan.new_files.index_len());
+    if config.backup.rehash_days > 0 {
+        info!("  Unchanged files verified by rehashing: {}", plan.rehashed);
+    }
+    for change in &plan.silent_changes {
+        warn!("    ! {} changed without a metadata change", change.path.display());
+    }
+    if !plan.errors.is_empty() {
+        warn!("  Entries that could not be read: {}", plan.errors.len());
+        for error in &plan.errors {
+            warn!("    ! {} (cannot {}: {})", error.path.display(), error.operation, error.message);
+        }
+    }
+    for file in &files {
+        info!("  • {} ({} bytes, hash: {}...)", file.path.display(), file.size, &file.hash[..16]);
+    }
+
+    if let Some(output) = output {
+        let saved = plan::PlanFile {
+            version: plan::PLAN_VERSION,
+            source: source_config.name.clone(),
+            url: source_config.url.clone(),
+            created: chrono::Utc::now().timestamp() as u64,
+            parent: parent_hash.map(hex::encode),
+            total_size: plan.total_size,
+            estimated_tape_bytes: estimated,
+            deduplicated: plan.deduplicated,
+            files,
+        };
+        saved.save(output)?;
+        info!("Plan written to {}; run `rumba apply {}` to execute it", output.display(), output.display());
+    }
+    info!("========================================");
+    Ok(())
+}
+
+/// Blobs a run wrote to tape
+struct Written {
+    locations: spill::SpillStore,
+    usage: report::TapeUsage,
+    /// Blobs left out because their file could no longer be opened
+    skipped: Vec<models::Hash>,
+    errors: Vec<errors::ScanError>,
+    /// From the first blob until the tape was finished
+    duration: Duration,
+}
+
+/// Writes the blobs of a finished plan to tape and returns their locations
+fn write_planned(
+    config: &config::Config,
+    source: &str,
+    blobs: &mut spill::SpillStore,
+    progress: Arc<dyn progress::ProgressObserver>,
+    stop: &interrupt::StopSignal,
+) -> Result<Option<Written>> {
+    let (blob_tx, blob_rx) = std::sync::mpsc::sync_channel(pipeline::BLOB_QUEUE_SIZE);
+    std::thread::scope(|s| {
+        let writer = s.spawn(|| write_to_tape(config, source, blob_rx, progress, stop));
+        let sent = (|| -> Result<()> {
+            for blob in blobs.iter()? {
+                // A closed channel means the writer failed; it reports why
+                if blob_tx.send(blob?).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        })();
+        drop(blob_tx);
+        let written = writer.join().expect("tape writer panicked")?;
+        sent?;
+        Ok(written)
+    })
+}
+
+/// Streams new blobs to tape/file as the pipeline finds them and returns their locations.
+/// The tape is only opened once the first blob arrives; returns None if there was none.
+fn write_to_tape(
+    config: &config::Config,
+    source: &str,
+    blobs: std::sync::mpsc::Receiver<spill::PlannedBlob>,
+    progress: Arc<dyn progress::ProgressObserver>,
+    stop: &interrupt::StopSignal,
+) -> Result<Option<Written>> {
+    let mut blobs = blobs.into_iter().peekable();
+    if blobs.peek().is_none() {
+        return Ok(None);
+    }
+    let started = Instant::now();
+    let errors = errors::ErrorCollector::new();
+    let mut locations = spill::SpillStore::new(config.backup.spill_dir.as_deref().map(std::path::Path::new))?;
+
     // 3. Initialize Tape Writer based on output mode
     let mut tape_writer = match config.target.output_mode.as_str() {
         "rustltfs" => {
@@ -90,12 +843,17 @@ fn main() -> Result<()> {
             )?
         }
         "tar" => {
-            // Generate timestamped tar filename
+            // Generate timestamped tar filename, per source if there are several
             let timestamp = chrono::Local::now().format("%Y%m%d_%H%M%S");
+            let suffix = if config.sources.len() > 1 {
+                format!("{}_{}", source, timestamp)
+            } else {
+                timestamp.to_string()
+            };
             let tar_path = if config.target.tape_path.ends_with(".tar") {
-                config.target.tape_path.replace(".tar", &format!("_{}.tar", timestamp))
+                config.target.tape_path.replace(".tar", &format!("_{}.tar", suffix))
             } else {
-                format!("{}_{}.tar", config.target.tape_path, timestamp)
+                format!("{}_{}.tar", config.target.tape_path, suffix)
             };
             
             info!("Output mode: tar file (writing to {})", tar_path);
@@ -104,7 +862,13 @@ fn main() -> Result<()> {
         _ => {
             anyhow::bail!("Invalid output mode: {}", config.target.output_mode);
         }
-    };
+    }
+    .with_block_size(config.target.block_size)
+    .with_staging(staging::StagingConfig::from_target(&config.target))
+    .with_retry(retry::RetryPolicy::from_config(&config.backup))
+    .with_stop(stop.clone())
+    .with_errors(errors.clone())
+    .with_progress(progress);
 
     // 4. Write to Tape/File (Phase 1: Prepare & Write)
     // Note: We are not handling 2PC strictly here yet (no rollback on failure), 
@@ -114,96 +878,146 @@ fn main() -> Result<()> {
     info!("Starting tape write operation");
     info!("========================================");
     
-    // Log each file being backed up
-    for (idx, (path, hash)) in plan.new_files.iter().enumerate() {
-        let file_size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
-        info!("[{}/{}] Backing up: {}", 
-            idx + 1, 
-            plan.new_files.len(),
-            path.display()
+    // Each file is logged as it is written; the progress display shows the totals
+    let mut idx = 0;
+    let start_offset = tape_writer.offset();
+    let written = tape_writer.write_blobs(blobs.map(Ok), |blob, location| {
+        idx += 1;
+        debug!("[{}] Backing up: {}", 
+            idx, 
+            blob.path.display()
         );
-        info!("  Hash: {}", hex::encode(hash));
-        info!("  Size: {} bytes", file_size);
+        debug!("  Hash: {}", hex::encode(blob.hash));
+        debug!("  Size: {} bytes", blob.size);
+        locations.record_location(blob.hash, location)
+    })?;
+    info!("Successfully wrote {} blobs", written.count);
+    if !written.skipped.is_empty() {
+        warn!("{} file(s) could not be opened any more and are left out", written.skipped.len());
     }
-    
-    let blob_locations = tape_writer.write_plan(&plan)?;
-    info!("Successfully wrote {} blobs", blob_locations.len());
+    let usage = report::TapeUsage {
+        tape_id: tape_writer.tape_id(),
+        start_offset,
+        end_offset: tape_writer.offset(),
+    };
 
     // Finish tape writing (wait for rustltfs if in that mode)
     tape_writer.finish()?;
     info!("Tape/file writing completed successfully");
 
-    // 5. Commit Metadata (Phase 3: Commit Index)
-    let write_txn = db.begin_write()?;
-    
-    // 5.1 Update Blobs
-    for (hash, location) in blob_locations {
-        db.insert_blob(&write_txn, &hash, &location)?;
-    }
+    Ok(Some(Written { locations, usage, skipped: written.skipped, errors: errors.take(), duration: started.elapsed() }))
+}
 
-    // 5.2 Update Trees (and Index)
-    // Wait, Pipeline didn't return the trees! 
-    // We need to modify Pipeline to return the computed trees so we can persist them.
-    // For now, let's assume we just persist the blobs and index for the files we backed up.
-    // TODO: Refactor Pipeline to return full Tree structure or persist Trees during pipeline run (but that breaks 2PC).
-    // Let's stick to the plan: Pipeline should probably return the Tree entries to be committed.
-    
-    // For this step, I'll just update the Index for the files we backed up, 
-    // so next time they are skipped.
-    for (path, hash) in &plan.new_files {
-        if let Ok(metadata) = std::fs::metadata(path) {
-             let mtime = metadata.modified()
-                .map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs() as i64)
-                .unwrap_or(0);
-            let size = metadata.len();
-            
-            // Normalize path to string key
-            let path_str = path.to_string_lossy();
-            let entry = models::IndexEntry {
-                mtime,
-                size,
-                hash: *hash,
+fn run_restore(config: &config::Config, target: &std::path::Path, archives: Vec<PathBuf>, commit: Option<&str>, source: Option<&str>) -> Result<()> {
+    let db = db::BackupDb::new(&config.target.db_path)?;
+    let commits = db.list_commits()?;
+
+    let (key, commit) = match commit {
+        Some(prefix) => {
+            let prefix = prefix.to_lowercase();
+            let mut matches = commits.iter()
+                .filter(|(_, c)| hex::encode(c.compute_hash()).starts_with(&prefix));
+            let found = matches.next().with_context(|| format!("No commit matches {}", prefix))?;
+            if matches.next().is_some() {
+                anyhow::bail!("Commit prefix {} is ambiguous", prefix);
+            }
+            found
+        }
+        None => {
+            let name = match source {
+                Some(name) => name,
+                None if config.sources.len() == 1 => config.sources[0].name.as_str(),
+                None => bail!("{} sources are configured; choose one with --source NAME", config.sources.len()),
             };
-            db.insert_index(&write_txn, &path_str, &entry)?;
+            match db.get_ref(name)? {
+                Some(key) => commits.iter()
+                    .find(|(k, _)| *k == key)
+                    .with_context(|| format!("Ref of source '{}' points to a missing commit", name))?,
+                // Database from before refs existed
+                None if !db.has_refs()? => commits.last().context("No commits in database")?,
+                None => bail!("Source '{}' has no commits", name),
+            }
         }
-    }
+    };
 
-    write_txn.commit()?;
-    info!("Database commit successful.");
-    
-    // Print backup summary
-    info!("");
-    info!("========================================");
-    info!("BACKUP COMPLETED SUCCESSFULLY");
-    info!("========================================");
-    info!("Configuration:");
-    info!("  Source: {}", config.source.url);
-    info!("  Output mode: {}", config.target.output_mode);
-    if config.target.output_mode == "rustltfs" {
-        info!("  Rustltfs: {}", config.target.rustltfs_path);
-        info!("  Device: {}", config.target.tape_path);
-    } else {
-        info!("  Tar file: {}", config.target.tape_path);
+    info!("Restoring commit {} ({})", hex::encode(commit.compute_hash()), commit.message);
+    if let Some(partial) = db.get_partial(*key)? {
+        warn!("The snapshot is partial ({}); what it lacks is not restored", partial.reason);
     }
-    info!("");
-    info!("Backup Summary:");
-    info!("  Total files backed up: {}", plan.new_files.len());
-    info!("  Total data size: {} bytes ({:.2} MB)", 
-        plan.total_size,
-        plan.total_size as f64 / 1024.0 / 1024.0
-    );
-    info!("  Unique blobs stored: {}", plan.new_files.len()); // TODO: count unique hashes
-    info!("");
-    info!("Files backed up:");
-    for (path, hash) in &plan.new_files {
-        let file_size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
-        info!("  • {} ({} bytes, hash: {}...)", 
-            path.display(),
-            file_size,
-            &hex::encode(hash)[..16]
+    let restorer = restore::Restorer::new(&db, archives);
+    let stats = restorer.restore_tree(&commit.tree_hash, target)?;
+
+    info!("Restore completed: {} files, {} directories, {} bytes", stats.files, stats.dirs, stats.bytes);
+    Ok(())
+}
+
+fn run_log(config: &config::Config, source: Option<&str>, limit: usize) -> Result<()> {
+    let db = db::BackupDb::new(&config.target.db_path)?;
+    let runs = db.list_runs()?;
+
+    println!("{:<19}  {:<16} {:<9} {:>8} {:>9} {:>8} {:>12} {:>6}  Commit", "Started", "Source", "Status", "Duration", "Scanned", "Written", "Bytes", "Errors");
+    let shown = runs.iter()
+        .rev()
+        .filter(|run| source.is_none_or(|name| run.source == name))
+        .take(limit);
+    for run in shown {
+        let commit = run.commit.map(|hash| hex::encode(&hash[..6])).unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<19}  {:<16} {:<9} {:>7}s {:>9} {:>8} {:>12} {:>6}  {}",
+            report::format_time(run.started),
+            run.source,
+            run.status,
+            run.finished.saturating_sub(run.started),
+            run.files_scanned,
+            run.files_written,
+            run.bytes_written,
+            run.errors,
+            commit
         );
+        if let Some(message) = &run.message {
+            println!("    {}", message);
+        }
+    }
+    Ok(())
+}
+
+fn run_source(config: &config::Config, config_path: &str, command: SourceCommands) -> Result<()> {
+    let db = db::BackupDb::new(&config.target.db_path)?;
+
+    match command {
+        SourceCommands::List => {
+            println!("{:>4}  {:<20} URL", "ID", "Name");
+            for source in db.list_sources()? {
+                println!("{:>4}  {:<20} {}", source.id, source.name, source.url);
+            }
+        }
+        SourceCommands::Relocate { url, name } => {
+            let name = match name {
+                Some(name) => name,
+                None if config.sources.len() == 1 => config.sources[0].name.clone(),
+                None => bail!("{} sources are configured; choose one with --name", config.sources.len()),
+            };
+            let mut source = db.get_source(&name)?
+                .with_context(|| format!("No source named '{}' in {}", name, config.target.db_path))?;
+
+            let root = config::root_for_url(&url);
+            if !root.is_dir() {
+                tracing::warn!("{:?} is not a directory (yet); relocating anyway", root);
+            }
+
+            info!("Relocating source '{}' from {} to {}", source.name, source.url, url);
+            source.url = url;
+            source.root = models::os_to_bytes(root.as_os_str());
+
+            let write_txn = db.begin_write()?;
+            db.insert_source(&write_txn, &source)?;
+            write_txn.commit()?;
+
+            if config.source(&source.name).is_ok_and(|s| s.url != source.url) {
+                info!("Remember to set source.url = {:?} in {}", source.url, config_path);
+            }
+        }
     }
-    info!("========================================");
 
     Ok(())
 }
@@ -1,4 +1,8 @@
 use rkyv::{Archive, Deserialize, Serialize};
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::object::{Encoder, ObjectType};
 
 // Fixed length hash, suitable for zero-copy and database keys
 pub type Hash = [u8; 32];
@@ -9,20 +13,60 @@ pub type Hash = [u8; 32];
 pub struct FileMetadata {
     pub size: u64,
     pub mtime: i64, // UNIX timestamp
-    pub mode: u32,  // Permissions
+    pub mtime_nsec: u32,
+    pub mode: u32,  // st_mode: file type and permission bits
     pub uid: u32,
     pub gid: u32,
+    // Change time and identity are only used to detect modifications, they cannot be restored
+    pub ctime: i64,
+    pub ctime_nsec: u32,
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    // Device number for character/block special files
+    pub rdev: u64,
     // File content hash (Content Addressable)
     pub content_hash: Hash,
 }
 
-#[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
 #[archive(check_bytes)]
 #[repr(C)]
 pub struct TreeEntry {
-    pub name: String,
+    /// Raw file name bytes, exactly as returned by the file system (not necessarily UTF-8)
+    pub name: Vec<u8>,
     pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub size: u64,
     pub hash: Hash,
+    /// Target of a symbolic link (raw bytes)
+    pub link_target: Option<Vec<u8>>,
+    /// Non-zero for files with several hard links; all members of a group share the same id
+    pub link_group: u64,
+    /// Device number of character/block special files
+    pub rdev: u64,
+    /// Extended attributes and ACLs, stored as an object in the `xattrs` table
+    pub xattrs: Option<Hash>,
+}
+
+/// Extended attributes (including POSIX ACLs and CIFS security descriptors) of one object,
+/// sorted by name. Content addressed like blobs, so identical permission sets are stored once.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct ExtendedAttributes {
+    pub attrs: Vec<Xattr>,
+}
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct Xattr {
+    pub name: String,
+    pub value: Vec<u8>,
 }
 
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
@@ -41,7 +85,17 @@ pub struct Commit {
 #[repr(C)]
 pub struct BlobLocation {
     pub tape_id: u64,
+    /// Byte offset of the tar entry in the stream written to the tape
     pub offset: u64,
+    /// Size of the fixed tape blocks the stream was written in (0 if unknown)
+    pub block_size: u64,
+}
+
+impl BlobLocation {
+    /// Tape block holding the start of the entry and the position inside that block
+    pub fn block_position(&self) -> Option<(u64, u64)> {
+        (self.block_size > 0).then(|| (self.offset / self.block_size, self.offset % self.block_size))
+    }
 }
 
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
@@ -49,41 +103,337 @@ pub struct BlobLocation {
 #[repr(C)]
 pub struct IndexEntry {
     pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub ctime: i64,
+    pub ctime_nsec: u32,
+    pub ino: u64,
     pub size: u64,
     pub hash: Hash,
 }
 
+/// A blob that must be kept even when no retained snapshot references it any more:
+/// the last known good content of a file whose content changed without a metadata change
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct ProtectedBlob {
+    /// Raw path bytes, relative to the source root
+    pub path: Vec<u8>,
+    pub source_id: u64,
+    /// UNIX time of the run that noticed the change
+    pub detected: u64,
+    /// Content found in its place
+    pub replaced_by: Hash,
+}
+
+/// Why a snapshot does not hold all of its source
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct PartialSnapshot {
+    /// Entries that could not be read and are missing or stored incompletely
+    pub errors: u64,
+    pub reason: String,
+}
+
+/// Outcome of one backup run, kept for `rumba log`; the full report is the JSON file
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct RunSummary {
+    pub source: String,
+    /// UNIX times
+    pub started: u64,
+    pub finished: u64,
+    /// "completed", "partial", "held", "interrupted" or "failed"
+    pub status: String,
+    /// Snapshot recorded by the run
+    pub commit: Option<Hash>,
+    pub files_scanned: u64,
+    /// Blobs written to tape, and the bytes of tape stream they took
+    pub files_written: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+    /// Why the run failed, or its first error
+    pub message: Option<String>,
+}
+
+/// A backup source, bound to the location it is currently mounted at.
+/// Index keys are relative to `root` and namespaced by `id`, so the source
+/// can move without invalidating its index.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct Source {
+    pub id: u64,
+    pub name: String,
+    pub url: String,
+    /// Raw bytes of the backup root path
+    pub root: Vec<u8>,
+}
+
+/// File type bits of `st_mode`
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFSOCK: u32 = 0o140000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFIFO: u32 = 0o010000;
+
+/// Splits a `SystemTime` into UNIX seconds and nanoseconds
+#[cfg(not(unix))]
+fn system_time_parts(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            // Before the epoch: keep the nanoseconds positive like `struct timespec`
+            let d = e.duration();
+            if d.subsec_nanos() == 0 {
+                (-(d.as_secs() as i64), 0)
+            } else {
+                (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        }
+    }
+}
+
+/// Raw bytes of a file name or path, as stored in trees and used as index keys.
+/// On Unix these are the exact bytes of the name; no lossy UTF-8 conversion happens.
+pub fn os_to_bytes(s: &OsStr) -> Vec<u8> {
+    s.as_encoded_bytes().to_vec()
+}
+
+/// Inverse of `os_to_bytes`
+#[cfg(unix)]
+pub fn bytes_to_os(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(bytes).to_os_string()
+}
+
+#[cfg(not(unix))]
+pub fn bytes_to_os(bytes: &[u8]) -> OsString {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+/// Key of a file in the `index` table: the big-endian source id followed by the
+/// path bytes relative to the source root
+pub fn index_key(source_id: u64, relative: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(8 + relative.len());
+    key.extend_from_slice(&source_id.to_be_bytes());
+    key.extend_from_slice(relative);
+    key
+}
+
+/// Inverse of `index_key`. Returns None for keys written before sources existed:
+/// absolute paths (`/mnt/share/…`, `C:\…`, `\\server\…`) stored as UTF-8, which never
+/// contains a NUL byte. Source ids are allocated from 1, so the id of a current key
+/// starts with NUL bytes (or is not UTF-8, like the id of a dry run).
+pub fn split_index_key(key: &[u8]) -> Option<(u64, &[u8])> {
+    if key.len() < 8 || !key.contains(&0) && std::str::from_utf8(key).is_ok() {
+        return None;
+    }
+    let (id, relative) = key.split_at(8);
+    Some((u64::from_be_bytes(id.try_into().ok()?), relative))
+}
+
+/// Rebuilds a `SystemTime` from UNIX seconds and nanoseconds
+pub fn system_time_from_parts(secs: i64, nsec: u32) -> SystemTime {
+    let nsec = std::time::Duration::from_nanos(nsec as u64);
+    if secs >= 0 {
+        UNIX_EPOCH + std::time::Duration::from_secs(secs as u64) + nsec
+    } else {
+        UNIX_EPOCH - std::time::Duration::from_secs(secs.unsigned_abs()) + nsec
+    }
+}
 
 impl FileMetadata {
+    /// Captures the metadata of a file system object as reported by `stat`.
+    /// The content hash is supplied by the caller (file hash or tree hash).
+    #[cfg(unix)]
+    pub fn from_fs(meta: &std::fs::Metadata, content_hash: Hash) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec() as u32,
+            mode: meta.mode(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec() as u32,
+            dev: meta.dev(),
+            ino: meta.ino(),
+            nlink: meta.nlink(),
+            rdev: meta.rdev(),
+            content_hash,
+        }
+    }
+
+    /// Captures the metadata of a file system object.
+    /// Platforms without POSIX metadata get a synthesized mode and no ownership.
+    #[cfg(not(unix))]
+    pub fn from_fs(meta: &std::fs::Metadata, content_hash: Hash) -> Self {
+        let (mtime, mtime_nsec) = meta.modified().map(system_time_parts).unwrap_or((0, 0));
+        let (ctime, ctime_nsec) = meta.created().map(system_time_parts).unwrap_or((0, 0));
+        let perm = if meta.permissions().readonly() { 0o555 } else { 0o755 };
+        let mode = if meta.is_dir() {
+            S_IFDIR | perm
+        } else if meta.file_type().is_symlink() {
+            S_IFLNK | 0o777
+        } else {
+            S_IFREG | (perm & 0o666)
+        };
+        Self {
+            size: meta.len(),
+            mtime,
+            mtime_nsec,
+            mode,
+            uid: 0,
+            gid: 0,
+            ctime,
+            ctime_nsec,
+            dev: 0,
+            ino: 0,
+            nlink: 1,
+            rdev: 0,
+            content_hash,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    /// Identifies the hard link group of a multiply-linked regular file
+    pub fn link_group(&self) -> Option<u64> {
+        if self.nlink > 1 && self.mode & S_IFMT == S_IFREG {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(&self.dev.to_le_bytes());
+            hasher.update(&self.ino.to_le_bytes());
+            let id = u64::from_le_bytes(hasher.finalize().as_bytes()[..8].try_into().unwrap());
+            // 0 means "not a hard link"
+            Some(id.max(1))
+        } else {
+            None
+        }
+    }
+
+    /// Hash of the metadata state. ctime, dev and ino are deliberately left out: they change on restore
     pub fn compute_hash(&self) -> Hash {
-        let mut hasher = blake3::Hasher::new();
-        // We hash the metadata fields to form a unique identifier for the metadata state
-        // Note: content_hash is part of the metadata, but usually we hash metadata *including* content hash
-        // to get the "File Object" hash in a Git-like system?
-        // Or is this just for the struct?
-        // The spec says "FileMetadata... content_hash".
-        // In Git, a blob hash is just content. A tree entry has mode, name, and blob hash.
-        // Let's assume we just need a utility to hash this struct if needed, 
-        // but for the Merkle Tree, the Tree Hash depends on TreeEntry(s).
-        
-        // For rkyv structs, we can hash the archived bytes for stability, 
-        // or hash fields manually. Manual is safer for cross-platform/version stability if layout changes.
-        hasher.update(&self.size.to_le_bytes());
-        hasher.update(&self.mtime.to_le_bytes());
-        hasher.update(&self.mode.to_le_bytes());
-        hasher.update(&self.uid.to_le_bytes());
-        hasher.update(&self.gid.to_le_bytes());
-        hasher.update(&self.content_hash);
-        *hasher.finalize().as_bytes()
+        Encoder::new()
+            .u64(self.size)
+            .i64(self.mtime)
+            .u32(self.mtime_nsec)
+            .u32(self.mode)
+            .u32(self.uid)
+            .u32(self.gid)
+            .hash(&self.content_hash)
+            .finish(ObjectType::Metadata)
     }
 }
 
 impl TreeEntry {
+    /// Builds a tree entry from captured metadata, pointing at `meta.content_hash`
+    pub fn from_metadata(name: Vec<u8>, meta: &FileMetadata) -> Self {
+        Self {
+            name,
+            mode: meta.mode,
+            uid: meta.uid,
+            gid: meta.gid,
+            mtime: meta.mtime,
+            mtime_nsec: meta.mtime_nsec,
+            size: meta.size,
+            hash: meta.content_hash,
+            link_target: None,
+            link_group: meta.link_group().unwrap_or(0),
+            rdev: if matches!(meta.mode & S_IFMT, S_IFCHR | S_IFBLK) { meta.rdev } else { 0 },
+            xattrs: None,
+        }
+    }
+
+    /// Name for output only; undecodable bytes are replaced by U+FFFD
+    pub fn display_name(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    pub fn compute_hash(&self) -> Hash {
+        Encoder::new()
+            .bytes(&self.name)
+            .u32(self.mode)
+            .u32(self.uid)
+            .u32(self.gid)
+            .i64(self.mtime)
+            .u32(self.mtime_nsec)
+            .u64(self.size)
+            .hash(&self.hash)
+            .optional_bytes(self.link_target.as_deref())
+            .u64(self.link_group)
+            .u64(self.rdev)
+            .optional_hash(self.xattrs.as_ref())
+            .finish(ObjectType::TreeEntry)
+    }
+}
+
+impl ExtendedAttributes {
+    pub fn compute_hash(&self) -> Hash {
+        let mut encoder = Encoder::new();
+        encoder.u64(self.attrs.len() as u64);
+        for attr in &self.attrs {
+            encoder.bytes(attr.name.as_bytes()).bytes(&attr.value);
+        }
+        encoder.finish(ObjectType::Xattrs)
+    }
+}
+
+impl Commit {
+    /// Hash identifying this commit; used as `parent_hash` by the next commit
     pub fn compute_hash(&self) -> Hash {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(self.name.as_bytes());
-        hasher.update(&self.mode.to_le_bytes());
-        hasher.update(&self.hash);
-        *hasher.finalize().as_bytes()
+        Encoder::new()
+            .hash(&self.tree_hash)
+            .optional_hash(self.parent_hash.as_ref())
+            .bytes(self.author.as_bytes())
+            .bytes(self.message.as_bytes())
+            .u64(self.timestamp)
+            .finish(ObjectType::Commit)
+    }
+}
+
+impl IndexEntry {
+    pub fn from_metadata(meta: &FileMetadata) -> Self {
+        Self {
+            mtime: meta.mtime,
+            mtime_nsec: meta.mtime_nsec,
+            ctime: meta.ctime,
+            ctime_nsec: meta.ctime_nsec,
+            ino: meta.ino,
+            size: meta.size,
+            hash: meta.content_hash,
+        }
+    }
+
+    /// Returns true if the stat data still matches what was indexed.
+    /// Nanosecond mtime catches same-second edits; ctime and inode catch
+    /// replaced files and tools that restore the old mtime.
+    pub fn matches(&self, meta: &FileMetadata) -> bool {
+        self.size == meta.size
+            && self.mtime == meta.mtime
+            && self.mtime_nsec == meta.mtime_nsec
+            && self.ctime == meta.ctime
+            && self.ctime_nsec == meta.ctime_nsec
+            && self.ino == meta.ino
     }
 }
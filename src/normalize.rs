@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+use std::path::Path;
+use anyhow::{Result, bail};
+use unicode_normalization::UnicodeNormalization;
+use crate::config::BackupConfig;
+use crate::models;
+
+/// Unicode normalization form applied to names before comparing them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalizationForm {
+    /// Compare raw bytes
+    None,
+    /// Composed form, as written by Windows clients
+    Nfc,
+    /// Decomposed form, as written by macOS clients
+    Nfd,
+}
+
+/// How file names are compared. Names are always stored byte-for-byte; the policy only
+/// decides which names are considered the same file (sorting, tree hashing, index keys).
+/// Names that are not valid UTF-8 are compared as raw bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamePolicy {
+    pub form: NormalizationForm,
+    pub case_insensitive: bool,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self { form: NormalizationForm::None, case_insensitive: false }
+    }
+}
+
+impl NamePolicy {
+    pub fn from_config(config: &BackupConfig) -> Result<Self> {
+        let form = match config.name_normalization.as_str() {
+            "none" => NormalizationForm::None,
+            "nfc" => NormalizationForm::Nfc,
+            "nfd" => NormalizationForm::Nfd,
+            other => bail!("Name normalization must be one of 'none', 'nfc' or 'nfd', got: {}", other),
+        };
+        Ok(Self { form, case_insensitive: config.case_insensitive })
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.form == NormalizationForm::None && !self.case_insensitive
+    }
+
+    /// Comparison key of a name (or a whole path: separators are not affected)
+    pub fn key<'a>(&self, name: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.is_identity() {
+            return Cow::Borrowed(name);
+        }
+        let Ok(text) = std::str::from_utf8(name) else {
+            return Cow::Borrowed(name);
+        };
+        let normalized: String = match self.form {
+            NormalizationForm::None => text.to_string(),
+            NormalizationForm::Nfc => text.nfc().collect(),
+            NormalizationForm::Nfd => text.nfd().collect(),
+        };
+        let folded = if self.case_insensitive {
+            // Lowercasing can produce decomposable characters, so normalize again
+            match self.form {
+                NormalizationForm::None => normalized.to_lowercase(),
+                NormalizationForm::Nfc => normalized.to_lowercase().nfc().collect(),
+                NormalizationForm::Nfd => normalized.to_lowercase().nfd().collect(),
+            }
+        } else {
+            normalized
+        };
+        if folded.as_bytes() == name {
+            Cow::Borrowed(name)
+        } else {
+            Cow::Owned(folded.into_bytes())
+        }
+    }
+
+    /// Index key of a path
+    pub fn path_key(&self, path: &Path) -> Vec<u8> {
+        self.key(&models::os_to_bytes(path.as_os_str())).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nfc_nfd_and_case_collide() {
+        let nfc = "監視チーム/ガイド.txt".nfc().collect::<String>();
+        let nfd = "監視チーム/ガイド.txt".nfd().collect::<String>();
+        assert_ne!(nfc.as_bytes(), nfd.as_bytes());
+
+        let raw = NamePolicy::default();
+        assert_ne!(raw.key(nfc.as_bytes()), raw.key(nfd.as_bytes()));
+
+        let policy = NamePolicy { form: NormalizationForm::Nfc, case_insensitive: false };
+        assert_eq!(policy.key(nfc.as_bytes()), policy.key(nfd.as_bytes()));
+        assert_ne!(policy.key(b"Report.TXT"), policy.key(b"report.txt"));
+
+        let folding = NamePolicy { form: NormalizationForm::Nfc, case_insensitive: true };
+        assert_eq!(folding.key(b"Report.TXT"), folding.key(b"report.txt"));
+
+        // Undecodable names are left alone
+        assert_eq!(folding.key(b"caf\xe9.TXT").as_ref(), b"caf\xe9.TXT");
+    }
+}
@@ -1,172 +1,1065 @@
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use anyhow::Result;
-use tracing::{info, debug};
-use crate::scanner::{Scanner, ScannedDir};
+use tracing::{info, debug, warn};
+use crate::scanner::{self, Scanner, ScannedDir, EntryKind};
+use crate::config::BackupConfig;
 use crate::db::BackupDb;
-use crate::models::{Hash, FileMetadata, TreeEntry};
+use crate::models::{self, Hash, ExtendedAttributes, FileMetadata, IndexEntry, TreeEntry};
 use crate::diff::DiffEngine;
-use std::sync::mpsc;
-use std::io::Read;
+use crate::normalize::NamePolicy;
+use crate::errors::{ErrorCollector, Operation, ScanError};
+use crate::interrupt::StopSignal;
+use crate::progress::{NoProgress, ProgressObserver};
+use crate::retry::{self, Attempts, RetryPolicy, RetryReader};
+use crate::spill::{PlannedBlob, SpillStore};
+use std::sync::Arc;
+use std::sync::mpsc::{self, SyncSender};
+use std::io::{self, Read};
+
+/// Directory listings buffered between the scanner and the stat stage.
+/// The scanner blocks when the pipeline falls behind.
+const SCAN_QUEUE_SIZE: usize = 256;
+
+/// Stat'ed directories buffered between the stat stage and the hash stage
+const STAT_QUEUE_SIZE: usize = 64;
+
+/// New blobs buffered between the hash stage and the tape writer (see `Pipeline::run_streaming`).
+/// Hashing pauses while the tape is slower than the source.
+pub const BLOB_QUEUE_SIZE: usize = 256;
+
+/// Files handed to the hash workers and not hashed yet. Beyond this the hash stage
+/// takes no more directories from the stat stage until some files are done.
+const HASH_QUEUE_SIZE: usize = 1024;
+
+/// Tree entries collected before they are written to the database in one transaction
+const TREE_BATCH_SIZE: usize = 10_000;
+
+/// Files at least this large are hashed by several threads over a memory map
+const MMAP_RAYON_THRESHOLD: u64 = 64 * 1024 * 1024;
 
 pub struct BackupPlan {
-    pub new_files: Vec<(PathBuf, Hash)>,
+    /// Blobs to write, in scan order, and index entries of every hashed file, spilled to disk
+    pub new_files: SpillStore,
     pub total_size: u64,
+    /// Tree hash of the backup root, referenced by the commit.
+    /// Trees and extended attribute objects are already stored in the database.
+    pub root_tree: Hash,
+    /// Pairs of paths whose names are equal under the name normalization policy
+    pub name_collisions: Vec<(PathBuf, PathBuf)>,
+    /// Directories whose tree is unchanged since the previous snapshot and was reused
+    pub reused_trees: u64,
+    /// Hashed files whose content was already stored or planned, so it is not written again
+    pub deduplicated: u64,
+    /// Clean files rehashed to verify their content (`rehash_days`)
+    pub rehashed: u64,
+    /// Rehashed files whose content changed although their metadata did not
+    pub silent_changes: Vec<SilentChange>,
+    /// Regular files seen, and how many of them the index showed to be unchanged
+    pub files: u64,
+    pub clean: u64,
+    /// Content bytes read to hash files
+    pub hashed_bytes: u64,
+    /// Size of the deduplicated files
+    pub deduplicated_bytes: u64,
+    /// Entries left out by the exclude patterns (an excluded directory counts once)
+    pub excluded: u64,
+    /// Entries that could not be read; if there are any, the snapshot is partial
+    pub errors: Vec<ScanError>,
+    /// The run was stopped (see `Pipeline::with_stop`) before it planned the whole source
+    pub stopped: bool,
+}
+
+/// Content of a file that changed without any change of size, mtime, ctime or inode
+#[derive(Debug, Clone, PartialEq)]
+pub struct SilentChange {
+    pub path: PathBuf,
+    /// Hash recorded in the index, the last content known to be good
+    pub previous: Hash,
+    pub current: Hash,
 }
 
 pub struct Pipeline {
     db: BackupDb,
     root: PathBuf,
+    config: BackupConfig,
+    source_id: u64,
+    excludes: Vec<String>,
+    previous_tree: Option<Hash>,
+    dry_run: bool,
+    progress: Arc<dyn ProgressObserver>,
+    stop: StopSignal,
+}
+
+/// A directory listing whose entries have been stat'ed and checked against the index
+struct StatDir {
+    path: PathBuf,
+    /// Tree of the same directory in the previous snapshot
+    previous: Option<Hash>,
+    /// Set if every entry is as in the previous tree (files clean in the index), with the
+    /// previous trees of the subdirectories: if they come out the same, so does this tree
+    reusable: Option<Vec<(PathBuf, Hash)>>,
+    /// Subdirectories in the listing, including those that could not be stat'ed
+    subdirs: usize,
+    entries: Vec<StatEntry>,
+    excluded: u64,
+    /// Outcome of the files hashed so far, by entry: None if the file could not be read
+    hashed: HashMap<usize, Option<Hash>>,
+    hashed_bytes: u64,
+}
+
+struct StatEntry {
+    path: PathBuf,
+    stage: Stage,
+    xattrs: Option<ExtendedAttributes>,
+}
+
+impl StatEntry {
+    fn name(&self) -> &[u8] {
+        match &self.stage {
+            Stage::Ready(tree_entry) | Stage::Dir(tree_entry) => &tree_entry.name,
+            Stage::File { name, .. } => name,
+        }
+    }
+}
+
+/// A directory entry whose metadata has been read
+enum Stage {
+    /// Symlinks and special files need no content hash
+    Ready(TreeEntry),
+    /// A subdirectory; its tree hash is filled in once it is finished
+    Dir(TreeEntry),
+    /// A regular file, with its content hash if the index already knows it.
+    /// `verify` asks for the known hash to be checked against the content.
+    File { name: Vec<u8>, metadata: FileMetadata, known: Option<Hash>, verify: bool },
+}
+
+/// A stat'ed directory whose files are not all hashed or whose subdirectories
+/// are not all finished yet
+struct PendingDir {
+    dir: StatDir,
+    /// Subdirectories whose tree hash is still unknown
+    waiting: usize,
+    /// Files on the worker pool
+    hashing: usize,
+    /// Some files were locked; the directory waits for the end of the run
+    locked: bool,
+    /// Tree hashes of finished subdirectories
+    children: HashMap<PathBuf, Hash>,
+}
+
+/// A file hashed on the worker pool, by directory and position in the listing.
+/// `result` is None if the run was stopped before the file was read.
+struct HashedFile {
+    dir: PathBuf,
+    index: usize,
+    last_try: bool,
+    result: Option<Result<Hash, ReadFailure>>,
+}
+
+/// The directories between the stat stage and the finished trees. Each one is
+/// finished as soon as its files are hashed and its subdirectories finished.
+struct Frontier<'p> {
+    root: &'p Path,
+    pending: HashMap<PathBuf, PendingDir>,
+    /// Subdirectories finished before the listing of their parent arrived, by parent
+    orphans: HashMap<PathBuf, HashMap<PathBuf, Hash>>,
+    root_tree: Option<Hash>,
+}
+
+/// Everything the hash stage accumulates while directories are finished one by one
+struct RunState<'a> {
+    db: &'a BackupDb,
+    diff_engine: DiffEngine<'a>,
+    policy: NamePolicy,
+    // Hashes dirty files, `parallel_threads` at a time, and sends back the results
+    pool: rayon::ThreadPool,
+    hashed_tx: mpsc::Sender<HashedFile>,
+    hashed_rx: mpsc::Receiver<HashedFile>,
+    in_flight: usize,
+    new_files: SpillStore,
+    // Receives each new blob as soon as it is known
+    sink: Option<SyncSender<PlannedBlob>>,
+    progress: Arc<dyn ProgressObserver>,
+    retry: RetryPolicy,
+    errors: ErrorCollector,
+    stop: StopSignal,
+    // Files were left unhashed because the run was stopped
+    stopped: bool,
+    total_size: u64,
+    // Content hashes of hard link groups seen so far, so each inode is hashed once
+    hardlinks: HashMap<u64, Hash>,
+    // Distinct extended attribute sets, stored once each
+    xattr_hashes: HashSet<Hash>,
+    name_collisions: Vec<(PathBuf, PathBuf)>,
+    reused_trees: u64,
+    deduplicated: u64,
+    rehashed: u64,
+    silent_changes: Vec<SilentChange>,
+    files: u64,
+    clean: u64,
+    hashed_bytes: u64,
+    deduplicated_bytes: u64,
+    excluded: u64,
+    // Finished trees and attribute sets not yet written to the database (never, in a dry run)
+    dry_run: bool,
+    trees: Vec<(Hash, Vec<TreeEntry>)>,
+    tree_entries: usize,
+    xattrs: Vec<(Hash, ExtendedAttributes)>,
 }
 
 impl Pipeline {
     pub fn new(db: BackupDb, root: PathBuf) -> Self {
-        Self { db, root }
+        Self {
+            db,
+            root,
+            config: BackupConfig::default(),
+            source_id: 0,
+            excludes: Vec::new(),
+            previous_tree: None,
+            dry_run: false,
+            progress: Arc::new(NoProgress),
+            stop: StopSignal::new(),
+        }
     }
 
+    /// Reports the scan, the files checked and the blobs planned to `progress`
+    pub fn with_progress(mut self, progress: Arc<dyn ProgressObserver>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Ends the run early once `stop` fires: no more directories are listed and no more
+    /// files hashed, and the directories listed so far are finished with what they hold.
+    /// The plan then describes part of the source.
+    pub fn with_stop(mut self, stop: StopSignal) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Root tree of the previous snapshot of the source. Directories that come out
+    /// the same reuse their stored tree instead of writing it again.
+    pub fn with_previous_tree(mut self, tree: Option<Hash>) -> Self {
+        self.previous_tree = tree;
+        self
+    }
+
+    /// Plan without writing anything to the database: finished trees are dropped
+    /// instead of stored, so the root tree of the plan cannot be committed
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Glob patterns of paths to leave out of the backup (see `scanner::build_excludes`)
+    pub fn with_excludes(mut self, patterns: &[String]) -> Self {
+        self.excludes = patterns.to_vec();
+        self
+    }
+
+    /// Looks up and keys index entries in the namespace of the given source
+    pub fn with_source(mut self, source_id: u64) -> Self {
+        self.source_id = source_id;
+        self
+    }
+
+    /// Applies the `[backup]` section of the configuration
+    pub fn with_config(mut self, config: &BackupConfig) -> Self {
+        self.config = config.clone();
+        self
+    }
+
+    /// Scans the tree and plans the blobs to write
     pub fn run(&self) -> Result<BackupPlan> {
+        self.execute(None)
+    }
+
+    /// Like `run`, but also sends every new blob to `sink` as soon as it is hashed,
+    /// so the tape writer can start before the scan is done. The channel is closed
+    /// when the run ends; a closed receiver aborts the run.
+    pub fn run_streaming(&self, sink: SyncSender<PlannedBlob>) -> Result<BackupPlan> {
+        self.execute(Some(sink))
+    }
+
+    /// Runs the stages concurrently, joined by bounded channels:
+    /// scan → stat/index check → hash → (optional) writer.
+    /// Every directory is finished as soon as all of its subdirectories are finished
+    /// (post-order), so only the directories on the scan frontier are held in memory.
+    /// Planned blobs go to a spill store on disk, finished trees to the database.
+    fn execute(&self, sink: Option<SyncSender<PlannedBlob>>) -> Result<BackupPlan> {
         info!("Starting backup pipeline for {:?}", self.root);
+        let policy = NamePolicy::from_config(&self.config)?;
+        let retry = RetryPolicy::from_config(&self.config);
+        let excludes = scanner::build_excludes(&self.excludes)?;
+        let today = chrono::Utc::now().timestamp().max(0) as u64 / 86400;
+        let diff_engine = || {
+            DiffEngine::new(&self.db)
+                .with_name_policy(policy)
+                .with_source(self.source_id, &self.root)
+                .with_rehash(self.config.rehash_days, today)
+        };
 
         // 1. Scan
-        let (tx, rx) = mpsc::channel();
+        let (scan_tx, scan_rx) = mpsc::sync_channel(SCAN_QUEUE_SIZE);
         let scanner_root = self.root.clone();
+        let threads = self.config.parallel_threads;
+        let progress = self.progress.clone();
+        let errors = ErrorCollector::new();
+        let scan_errors = errors.clone();
         std::thread::spawn(move || {
-            let scanner = Scanner::new(scanner_root);
-            if let Err(e) = scanner.scan_parallel(tx) {
+            let scanner = Scanner::new(scanner_root)
+                .with_progress(progress)
+                .with_errors(scan_errors)
+                .with_threads(threads)
+                .with_name_policy(policy)
+                .with_excludes(excludes);
+            if let Err(e) = scanner.scan_parallel(scan_tx) {
                 tracing::error!("Scanner failed: {}", e);
             }
         });
 
-        // 2. Ingest & Build Tree (Bottom-Up Strategy)
-        let mut dir_map: HashMap<PathBuf, ScannedDir> = HashMap::new();
-        
-        for scanned_dir in rx {
-            dir_map.insert(scanned_dir.path.clone(), scanned_dir);
-        }
-
-        // Sort paths by length descending (leaves first)
-        let mut paths: Vec<PathBuf> = dir_map.keys().cloned().collect();
-        paths.sort_by(|a, b| b.as_os_str().len().cmp(&a.as_os_str().len()));
-
-        let mut new_files = Vec::new();
-        let mut total_size = 0;
-
-        // We need to store computed Tree Hashes for directories to use in their parents
-        // Map<Path, Hash>
-        let mut tree_hashes: HashMap<PathBuf, Hash> = HashMap::new();
-
-        let diff_engine = DiffEngine::new(&self.db);
-
-        for path in paths {
-            if let Some(dir_info) = dir_map.get(&path) {
-                let mut tree_entries = Vec::new();
-
-                for entry in &dir_info.entries {
-                    let entry_path = entry.path.clone();
-                    
-                    if entry.is_dir {
-                        // It's a directory, look up its computed hash
-                        if let Some(hash) = tree_hashes.get(&entry_path) {
-                            tree_entries.push(TreeEntry {
-                                name: entry.name.clone(),
-                                mode: 0o040755, // Directory mode
-                                hash: *hash,
-                            });
-                        } else {
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let mut state = RunState {
+            db: &self.db,
+            diff_engine: diff_engine(),
+            policy,
+            pool: rayon::ThreadPoolBuilder::new()
+                .num_threads(self.config.parallel_threads)
+                .thread_name(|i| format!("rumba-hash-{}", i))
+                .build()?,
+            hashed_tx,
+            hashed_rx,
+            in_flight: 0,
+            new_files: SpillStore::new(self.config.spill_dir.as_deref().map(Path::new))?,
+            sink,
+            progress: self.progress.clone(),
+            retry,
+            errors: errors.clone(),
+            stop: self.stop.clone(),
+            stopped: false,
+            total_size: 0,
+            hardlinks: HashMap::new(),
+            xattr_hashes: HashSet::new(),
+            name_collisions: Vec::new(),
+            reused_trees: 0,
+            deduplicated: 0,
+            rehashed: 0,
+            silent_changes: Vec::new(),
+            files: 0,
+            clean: 0,
+            hashed_bytes: 0,
+            deduplicated_bytes: 0,
+            excluded: 0,
+            dry_run: self.dry_run,
+            trees: Vec::new(),
+            tree_entries: 0,
+            xattrs: Vec::new(),
+        };
+
+        let root_tree = std::thread::scope(|s| {
+            // 2. Stat & index check
+            let (stat_tx, stat_rx) = mpsc::sync_channel(STAT_QUEUE_SIZE);
+            let errors = &errors;
+            // Returns true if the run was stopped before everything was listed
+            let stat_stage = s.spawn(move || -> Result<bool> {
+                let diff_engine = diff_engine();
+                // Previous trees of the directories listed next, followed down from the root
+                let mut previous: HashMap<PathBuf, Hash> = self.previous_tree
+                    .map(|hash| (self.root.clone(), hash))
+                    .into_iter()
+                    .collect();
+                for scanned_dir in scan_rx {
+                    // Dropping the listings stops the scanner as well
+                    if let Some(reason) = self.stop.reason() {
+                        info!("Stopping the scan ({})", reason);
+                        return Ok(true);
+                    }
+                    // The scanner also reports the listing that holds the root itself
+                    if !scanned_dir.path.starts_with(&self.root) {
+                        continue;
+                    }
+                    let prev = match previous.remove(&scanned_dir.path) {
+                        Some(hash) => follow_previous(&self.db, hash, &scanned_dir, &mut previous)?,
+                        None => None,
+                    };
+                    let mut dir = stat_dir(&self.config, &retry, &diff_engine, errors, scanned_dir)?;
+                    if let Some((hash, entries)) = prev {
+                        dir.reusable = reusable_subtrees(&dir, &entries);
+                        dir.previous = Some(hash);
+                    }
+                    if stat_tx.send(dir).is_err() {
+                        // The hash stage failed and reports why
+                        break;
+                    }
+                }
+                Ok(false)
+            });
+
+            // 3. Hash & build trees
+            let root_tree = self.finish_dirs(&mut state, stat_rx);
+            let stat_result = stat_stage.join().expect("stat stage panicked");
+            // An error in the stat stage ends the listings early, so it is the cause
+            stat_result.and_then(|stopped| Ok((stopped, root_tree?)))
+        })?;
+        let (scan_stopped, root_tree) = root_tree;
+        state.flush_trees()?;
+
+        let root_tree = root_tree
+            .ok_or_else(|| anyhow::anyhow!("Backup root {:?} could not be scanned", self.root))?;
+
+        Ok(BackupPlan {
+            new_files: state.new_files,
+            total_size: state.total_size,
+            root_tree,
+            name_collisions: state.name_collisions,
+            reused_trees: state.reused_trees,
+            deduplicated: state.deduplicated,
+            rehashed: state.rehashed,
+            silent_changes: state.silent_changes,
+            files: state.files,
+            clean: state.clean,
+            hashed_bytes: state.hashed_bytes,
+            deduplicated_bytes: state.deduplicated_bytes,
+            excluded: state.excluded,
+            errors: errors.take(),
+            stopped: scan_stopped || state.stopped,
+        })
+    }
+
+    /// Finishes the stat'ed directories in post-order (completion counting)
+    /// and returns the tree hash of the root. The files of every listed directory
+    /// go to the worker pool at once, so small directories are hashed side by side.
+    fn finish_dirs(&self, state: &mut RunState, stat_rx: mpsc::Receiver<StatDir>) -> Result<Option<Hash>> {
+        let mut frontier = Frontier::new(&self.root);
+        for stat_dir in stat_rx {
+            frontier.add(state, stat_dir)?;
+            // Take in the files hashed meanwhile; wait for some while too many are queued
+            while let Some(hashed) = state.poll_hashed(state.in_flight >= HASH_QUEUE_SIZE) {
+                frontier.take_hashed(state, hashed)?;
+            }
+        }
+        while let Some(hashed) = state.poll_hashed(true) {
+            frontier.take_hashed(state, hashed)?;
+        }
+        frontier.finish_rest(state)?;
+        Ok(frontier.root_tree)
+    }
+}
+
+impl<'p> Frontier<'p> {
+    fn new(root: &'p Path) -> Self {
+        Self { root, pending: HashMap::new(), orphans: HashMap::new(), root_tree: None }
+    }
+
+    /// Takes in a stat'ed directory and hands its files to the worker pool
+    fn add(&mut self, state: &mut RunState, dir: StatDir) -> Result<()> {
+        let path = dir.path.clone();
+        let children = self.orphans.remove(&path).unwrap_or_default();
+        let waiting = dir.subdirs.saturating_sub(children.len());
+        let hashing = state.submit_files(&dir, false);
+        self.pending.insert(path.clone(), PendingDir { dir, waiting, hashing, locked: false, children });
+        self.finish_ready(state, path)
+    }
+
+    /// Records a hashed file in its directory, and finishes the directory if it was the last
+    fn take_hashed(&mut self, state: &mut RunState, hashed: HashedFile) -> Result<()> {
+        let pending = self.pending.get_mut(&hashed.dir).expect("directories wait for their files");
+        pending.hashing -= 1;
+        if !state.record_hashed(&mut pending.dir, hashed.index, hashed.last_try, hashed.result) {
+            pending.locked = true;
+        }
+        self.finish_ready(state, hashed.dir)
+    }
+
+    /// Finishes the directory at `path` if it is complete, and every ancestor it was
+    /// the last missing child of
+    fn finish_ready(&mut self, state: &mut RunState, path: PathBuf) -> Result<()> {
+        let mut ready = Some(path);
+        while let Some(path) = ready.take() {
+            // Directories with locked files wait, and with them their ancestors
+            if !self.pending.get(&path).is_some_and(|p| p.waiting == 0 && p.hashing == 0 && !p.locked) {
+                break;
+            }
+            let done = self.pending.remove(&path).unwrap();
+            let hash = state.finish_dir(done.dir, &done.children)?;
+            if path == self.root {
+                self.root_tree = Some(hash);
+                break;
+            }
+            let Some(parent) = path.parent().map(Path::to_path_buf) else { break };
+            match self.pending.get_mut(&parent) {
+                Some(p) => {
+                    p.children.insert(path, hash);
+                    p.waiting = p.waiting.saturating_sub(1);
+                    ready = Some(parent);
+                }
+                None => {
+                    self.orphans.entry(parent).or_default().insert(path, hash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Directories still waiting have files that were locked, or subdirectories that could
+    /// not be read; finish them deepest first, trying the locked files one last time
+    fn finish_rest(&mut self, state: &mut RunState) -> Result<()> {
+        for pending in self.pending.values_mut() {
+            pending.hashing += state.submit_files(&pending.dir, true);
+        }
+        while let Some(hashed) = state.poll_hashed(true) {
+            let pending = self.pending.get_mut(&hashed.dir).expect("directories wait for their files");
+            state.record_hashed(&mut pending.dir, hashed.index, hashed.last_try, hashed.result);
+        }
+
+        let mut rest: Vec<PathBuf> = self.pending.keys().cloned().collect();
+        rest.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+        for path in rest {
+            let done = self.pending.remove(&path).unwrap();
+            let hash = state.finish_dir(done.dir, &done.children)?;
+            if path == self.root {
+                self.root_tree = Some(hash);
+            } else if let Some(p) = path.parent().and_then(|parent| self.pending.get_mut(parent)) {
+                p.children.insert(path, hash);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Looks up the previous tree `hash` of a listed directory and notes the previous trees
+/// of its subdirectories in `previous`. Returns the tree, or None if it is not stored.
+fn follow_previous(db: &BackupDb, hash: Hash, dir: &ScannedDir, previous: &mut HashMap<PathBuf, Hash>) -> Result<Option<(Hash, Vec<TreeEntry>)>> {
+    let Some(entries) = db.get_tree(&hash)? else { return Ok(None) };
+    let subdirs: HashMap<&[u8], Hash> = entries.iter()
+        .filter(|e| e.is_dir())
+        .map(|e| (e.name.as_slice(), e.hash))
+        .collect();
+    for entry in dir.entries.iter().filter(|e| e.is_dir()) {
+        if let Some(hash) = subdirs.get(models::os_to_bytes(&entry.name).as_slice()) {
+            previous.insert(entry.path.clone(), *hash);
+        }
+    }
+    Ok(Some((hash, entries)))
+}
+
+/// Previous trees of the subdirectories of `dir` if all of its entries are as in its
+/// previous tree `previous` (see `StatDir::reusable`)
+fn reusable_subtrees(dir: &StatDir, previous: &[TreeEntry]) -> Option<Vec<(PathBuf, Hash)>> {
+    if dir.entries.len() != previous.len() {
+        return None;
+    }
+    let mut subtrees = Vec::new();
+    for (entry, previous) in dir.entries.iter().zip(previous) {
+        if !unchanged(entry, previous) {
+            return None;
+        }
+        if previous.is_dir() {
+            subtrees.push((entry.path.clone(), previous.hash));
+        }
+    }
+    Some(subtrees)
+}
+
+/// True if `entry` comes out in the new tree exactly as `previous`, the tree of a
+/// subdirectory aside: a clean file not due for verification, or a directory, symlink
+/// or special file whose metadata did not change
+fn unchanged(entry: &StatEntry, previous: &TreeEntry) -> bool {
+    let xattrs = entry.xattrs.as_ref().map(ExtendedAttributes::compute_hash);
+    let current = match &entry.stage {
+        Stage::Ready(tree_entry) => TreeEntry { xattrs, ..tree_entry.clone() },
+        Stage::Dir(tree_entry) => TreeEntry { hash: previous.hash, xattrs, ..tree_entry.clone() },
+        Stage::File { name, metadata, known: Some(hash), verify: false } => {
+            let metadata = FileMetadata { content_hash: *hash, ..*metadata };
+            TreeEntry { xattrs, ..TreeEntry::from_metadata(name.clone(), &metadata) }
+        }
+        Stage::File { .. } => return false,
+    };
+    current == *previous
+}
+
+/// Reads the metadata, symlink targets and extended attributes of a listing
+/// and looks its files up in the index
+fn stat_dir(config: &BackupConfig, retry: &RetryPolicy, diff_engine: &DiffEngine, errors: &ErrorCollector, dir_info: ScannedDir) -> Result<StatDir> {
+    let subdirs = dir_info.entries.iter().filter(|e| e.is_dir()).count();
+    let mut entries = Vec::with_capacity(dir_info.entries.len());
+
+    for entry in dir_info.entries {
+        let entry_path = entry.path;
+        let name = models::os_to_bytes(&entry.name);
+
+        if entry.kind == EntryKind::Special && config.special_files == "skip" {
+            debug!("Skipping special file {:?}", entry_path);
+            continue;
+        }
+
+        // Never follow symlinks: the link itself is what gets backed up
+        let mut attempts = retry.for_file();
+        let fs_metadata = match attempts.run(Operation::Stat, &entry_path, || std::fs::symlink_metadata(&entry_path)) {
+            Ok(m) => m,
+            Err(e) => {
+                errors.record(&entry_path, Operation::Stat, &e);
+                continue;
+            }
+        };
+
+        let stage = match entry.kind {
+            EntryKind::Dir => {
+                let metadata = FileMetadata::from_fs(&fs_metadata, [0u8; 32]);
+                Stage::Dir(TreeEntry::from_metadata(name, &metadata))
+            }
+            EntryKind::Symlink => {
+                let target = match attempts.run(Operation::ReadLink, &entry_path, || std::fs::read_link(&entry_path)) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        errors.record(&entry_path, Operation::ReadLink, &e);
+                        continue;
+                    }
+                };
+                let metadata = FileMetadata::from_fs(&fs_metadata, [0u8; 32]);
+                let mut tree_entry = TreeEntry::from_metadata(name, &metadata);
+                tree_entry.link_target = Some(models::os_to_bytes(target.as_os_str()));
+                Stage::Ready(tree_entry)
+            }
+            EntryKind::Special => {
+                // FIFOs, sockets and devices have no content to read
+                let metadata = FileMetadata::from_fs(&fs_metadata, [0u8; 32]);
+                Stage::Ready(TreeEntry::from_metadata(name, &metadata))
+            }
+            EntryKind::File => {
+                // Check Index (Fast Path)
+                let metadata = FileMetadata::from_fs(&fs_metadata, [0u8; 32]);
+                let known = diff_engine.check_index(&entry_path, &metadata)?;
+                let verify = known.is_some() && diff_engine.due_for_rehash(&entry_path);
+                Stage::File { name, metadata, known, verify }
+            }
+        };
+
+        // Extended attributes and ACLs
+        let xattrs = if config.xattrs {
+            crate::xattrs::read_xattrs(&entry_path).unwrap_or_else(|e| {
+                let e = e.downcast::<std::io::Error>().unwrap_or_else(|e| std::io::Error::other(e.to_string()));
+                errors.record(&entry_path, Operation::ReadXattrs, &e);
+                None
+            })
+        } else {
+            None
+        };
+
+        entries.push(StatEntry { path: entry_path, stage, xattrs });
+    }
+
+    Ok(StatDir {
+        path: dir_info.path,
+        previous: None,
+        reusable: None,
+        subdirs,
+        entries,
+        excluded: dir_info.excluded,
+        hashed: HashMap::new(),
+        hashed_bytes: 0,
+    })
+}
+
+impl RunState<'_> {
+    /// Hands the files of a directory that need hashing (dirty ones and clean ones due for
+    /// verification) to the worker pool; reuses the hash of another link to the same inode,
+    /// and hashes links to one inode in this directory once. `last_try` also waits for
+    /// files locked by another program. Returns the number of files handed over.
+    fn submit_files(&mut self, dir: &StatDir, last_try: bool) -> usize {
+        let mut to_hash = Vec::new();
+        let mut hashing_groups = HashSet::new();
+        for (i, entry) in dir.entries.iter().enumerate() {
+            let Stage::File { metadata, known, verify, .. } = &entry.stage else { continue };
+            if known.is_some() && !verify || dir.hashed.contains_key(&i) {
+                continue;
+            }
+            let link_group = metadata.link_group();
+            if link_group.is_some_and(|id| self.hardlinks.contains_key(&id)) {
+                continue;
+            }
+            if link_group.is_none_or(|id| hashing_groups.insert(id)) {
+                to_hash.push((i, entry.path.clone()));
+            }
+        }
+        if to_hash.is_empty() {
+            return 0;
+        }
+        // Once the run is stopped, files not hashed yet are left out of the plan
+        if self.stop.is_stopped() {
+            self.stopped = true;
+            return 0;
+        }
+
+        let count = to_hash.len();
+        for (index, path) in to_hash {
+            let (dir, tx, retry, stop) = (dir.path.clone(), self.hashed_tx.clone(), self.retry, self.stop.clone());
+            self.pool.spawn(move || {
+                let result = (!stop.is_stopped()).then(|| {
+                    let mut attempts = retry.for_file();
+                    if last_try {
+                        attempts = attempts.retrying_locked();
+                    }
+                    compute_file_hash(&path, &mut attempts)
+                });
+                // Nobody listens any more if the run failed
+                let _ = tx.send(HashedFile { dir, index, last_try, result });
+            });
+        }
+        self.in_flight += count;
+        count
+    }
+
+    /// Next file back from the worker pool, if any is on its way. Waits for it with `wait`.
+    fn poll_hashed(&mut self, wait: bool) -> Option<HashedFile> {
+        if self.in_flight == 0 {
+            return None;
+        }
+        let hashed = if wait {
+            // The run holds a sender, so the channel never closes
+            self.hashed_rx.recv().ok()
+        } else {
+            self.hashed_rx.try_recv().ok()
+        }?;
+        self.in_flight -= 1;
+        Some(hashed)
+    }
+
+    /// Records the hash of file `i` of a directory. Files that cannot be read are
+    /// recorded and left out. Returns false if the file is locked by another program:
+    /// it is tried again with `last_try` once everything else is done.
+    fn record_hashed(&mut self, dir: &mut StatDir, i: usize, last_try: bool, result: Option<Result<Hash, ReadFailure>>) -> bool {
+        let StatDir { entries, hashed, hashed_bytes, .. } = dir;
+        let Some(result) = result else {
+            self.stopped = true;
+            return true;
+        };
+        let entry = &entries[i];
+        let Stage::File { metadata, known, .. } = &entry.stage else { unreachable!("only files are hashed") };
+        match result {
+            Ok(hash) => {
+                *hashed_bytes += metadata.size;
+                hashed.insert(i, Some(hash));
+            }
+            Err((_, e)) if !last_try && retry::is_locked(&e) => {
+                debug!("{:?} is locked, trying again at the end of the run", entry.path);
+                return false;
+            }
+            Err((operation, e)) if known.is_some() => {
+                // The snapshot keeps the indexed content; only the verification is missing
+                warn!("Cannot verify {:?}: cannot {}: {}", entry.path, operation, e);
+                hashed.insert(i, None);
+            }
+            Err((operation, e)) => {
+                self.errors.record(&entry.path, operation, &e);
+                hashed.insert(i, None);
+                // Other links to the same inode in this directory are missing as well
+                let Some(id) = metadata.link_group() else { return true };
+                for (j, other) in entries.iter().enumerate() {
+                    if let Stage::File { metadata, .. } = &other.stage {
+                        if j != i && metadata.link_group() == Some(id) {
+                            self.errors.record(&other.path, operation, &e);
+                            hashed.insert(j, None);
+                        }
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Builds the tree of a directory whose subdirectories are all finished and whose
+    /// files are hashed, and returns its hash
+    fn finish_dir(&mut self, dir_info: StatDir, child_trees: &HashMap<PathBuf, Hash>) -> Result<Hash> {
+        let path = dir_info.path;
+        let previous = dir_info.previous;
+        let hashed = dir_info.hashed;
+        self.excluded += dir_info.excluded;
+        let mut tree_entries = Vec::with_capacity(dir_info.entries.len());
+
+        // 1. Count the files checked
+        let entries = dir_info.entries;
+        let (mut files, mut bytes) = (0, 0);
+        for entry in &entries {
+            if let Stage::File { metadata, known, .. } = &entry.stage {
+                files += 1;
+                bytes += metadata.size;
+                if known.is_some() {
+                    self.clean += 1;
+                }
+            }
+        }
+        self.files += files;
+        self.hashed_bytes += dir_info.hashed_bytes;
+        self.progress.files_processed(files, bytes, dir_info.hashed_bytes);
+
+        // 2. Nothing in or below this directory changed: take the previous tree as it is
+        if let (Some(previous), Some(subtrees)) = (previous, &dir_info.reusable) {
+            if subtrees.iter().all(|(path, hash)| child_trees.get(path) == Some(hash)) {
+                for entry in &entries {
+                    if let Stage::File { metadata, known: Some(hash), .. } = &entry.stage {
+                        if let Some(id) = metadata.link_group() {
+                            self.hardlinks.insert(id, *hash);
+                        }
+                    }
+                }
+                // Listings are sorted by normalized name like trees
+                let names: Vec<&[u8]> = entries.iter().map(StatEntry::name).collect();
+                self.note_collisions(&path, &names);
+                self.reused_trees += 1;
+                return Ok(previous);
+            }
+        }
+
+        // 3. Build the tree entries in listing order
+        for (i, entry) in entries.into_iter().enumerate() {
+            let entry_path = entry.path;
+            let mut tree_entry = match entry.stage {
+                Stage::Ready(tree_entry) => tree_entry,
+                Stage::Dir(mut tree_entry) => {
+                    // It's a directory, look up its computed hash
+                    match child_trees.get(&entry_path) {
+                        Some(hash) => {
+                            tree_entry.hash = *hash;
+                            tree_entry
+                        }
+                        None => {
                             debug!("Subdirectory hash not found for {:?}, assuming empty or error", entry_path);
+                            continue;
                         }
-                    } else {
-                        // It's a file
-                        match std::fs::metadata(&entry_path) {
-                            Ok(fs_metadata) => {
-                                let mtime = fs_metadata.modified()
-                                    .map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs() as i64)
-                                    .unwrap_or(0);
-                                let size = fs_metadata.len();
-                                
-                                // 1. Check Index (Fast Path)
-                                let content_hash = match diff_engine.check_index(&entry_path, mtime, size)? {
-                                    Some(hash) => hash, // Clean
-                                    None => {
-                                        // Dirty: Compute Hash
-                                        compute_file_hash(&entry_path)?
-                                    }
-                                };
-
-                                // 2. Check Deduplication
-                                // If we computed a new hash (or even if we got it from index, though less likely to be missing from blobs if in index),
-                                // we should check if we need to back it up.
-                                // Optimization: If check_index returned Some, we assume blob exists? 
-                                // Safety: Always check blob existence if we want to be sure, or trust index implies blob existence.
-                                // For robustness, let's check blob existence if it was dirty. 
-                                // If it was clean, we trust the blob is there (unless GC happened, which is out of scope).
-                                
-                                // Actually, let's just check `should_backup_blob` if we computed the hash.
-                                // If we got it from index, we assume it's already backed up.
-                                let mut needs_backup = false;
-                                if diff_engine.should_backup_blob(&content_hash)? {
-                                    needs_backup = true;
-                                }
-
-                                let metadata = FileMetadata {
-                                    size,
-                                    mtime,
-                                    mode: 0o100644, // TODO: Real mode
-                                    uid: 0,
-                                    gid: 0,
-                                    content_hash,
-                                };
-
-                                if needs_backup {
-                                    new_files.push((entry_path.clone(), content_hash));
-                                    total_size += metadata.size;
-                                }
-
-                                tree_entries.push(TreeEntry {
-                                    name: entry.name.clone(),
-                                    mode: metadata.mode,
-                                    hash: content_hash,
-                                });
-                            },
-                            Err(e) => {
-                                tracing::warn!("Failed to get metadata for {:?}: {}", entry_path, e);
+                    }
+                }
+                Stage::File { name, mut metadata, known, .. } => {
+                    let link_group = metadata.link_group();
+                    let rehashed = hashed.get(&i).copied().flatten();
+                    if let (Some(previous), Some(current)) = (known, rehashed) {
+                        self.rehashed += 1;
+                        if previous != current {
+                            tracing::warn!("Content of {:?} changed without a metadata change", entry_path);
+                            self.silent_changes.push(SilentChange { path: entry_path.clone(), previous, current });
+                        }
+                    }
+                    // Unless another link to the same inode was hashed already
+                    let hash = rehashed.or(known).or_else(|| link_group.and_then(|id| self.hardlinks.get(&id).copied()));
+                    metadata.content_hash = match hash {
+                        Some(hash) => hash,
+                        // Could not be read (recorded when it was hashed), or the run stopped first
+                        None => continue,
+                    };
+                    if let Some(id) = link_group {
+                        self.hardlinks.insert(id, metadata.content_hash);
+                    }
+
+                    // Index every file that was not clean (or whose content changed silently),
+                    // whether or not its content is new, with the stat data read before hashing
+                    if known != Some(metadata.content_hash) {
+                        let key = self.diff_engine.index_key(&entry_path);
+                        self.new_files.record_index(key, IndexEntry::from_metadata(&metadata))?;
+                    }
+
+                    // Check Deduplication
+                    // If the index was clean we trust the blob is there (unless GC happened, which is out of scope),
+                    // but checking is cheap and keeps the plan correct.
+                    // Content already planned in this run (copies, hard links) is written once.
+                    let blob = PlannedBlob {
+                        path: entry_path.clone(),
+                        hash: metadata.content_hash,
+                        size: metadata.size,
+                    };
+                    if self.diff_engine.should_backup_blob(&blob.hash)? && self.new_files.push(blob.clone())? {
+                        self.total_size += metadata.size;
+                        self.progress.blob_planned(metadata.size);
+                        // A stopped writer takes no more blobs; they are left out of the snapshot
+                        if let Some(sink) = &self.sink {
+                            if sink.send(blob).is_err() && !self.stop.is_stopped() {
+                                anyhow::bail!("The tape writer stopped");
                             }
                         }
+                    } else if known != Some(metadata.content_hash) {
+                        self.deduplicated += 1;
+                        self.deduplicated_bytes += metadata.size;
                     }
+
+                    TreeEntry::from_metadata(name, &metadata)
                 }
+            };
 
-                // Compute Tree Hash
-                tree_entries.sort_by(|a, b| a.name.cmp(&b.name));
-                
-                let mut hasher = blake3::Hasher::new();
-                for entry in &tree_entries {
-                    hasher.update(&entry.compute_hash());
+            if let Some(attrs) = entry.xattrs {
+                let hash = attrs.compute_hash();
+                if self.xattr_hashes.insert(hash) {
+                    self.xattrs.push((hash, attrs));
                 }
-                let tree_hash = *hasher.finalize().as_bytes();
-                
-                tree_hashes.insert(path.clone(), tree_hash);
+                tree_entry.xattrs = Some(hash);
             }
+
+            tree_entries.push(tree_entry);
         }
 
-        Ok(BackupPlan {
-            new_files,
-            total_size,
-        })
+        // Sort by normalized name so that name variants sit side by side; the
+        // hash covers the raw names that are stored and restored
+        let policy = self.policy;
+        tree_entries.sort_by(|a, b| {
+            policy.key(&a.name).cmp(&policy.key(&b.name)).then_with(|| a.name.cmp(&b.name))
+        });
+
+        let names: Vec<&[u8]> = tree_entries.iter().map(|e| e.name.as_slice()).collect();
+        self.note_collisions(&path, &names);
+
+        let tree_hash = tree_hash(&tree_entries);
+
+        // The rebuilt tree is the one the previous snapshot already stored, e.g. after
+        // files were touched without a change of content
+        if previous == Some(tree_hash) {
+            self.reused_trees += 1;
+            return Ok(tree_hash);
+        }
+
+        self.tree_entries += tree_entries.len() + 1;
+        self.trees.push((tree_hash, tree_entries));
+        if self.tree_entries >= TREE_BATCH_SIZE {
+            self.flush_trees()?;
+        }
+        Ok(tree_hash)
+    }
+
+    /// Reports neighbouring names of directory `dir`, sorted by normalized name,
+    /// that are equal under the normalization policy
+    fn note_collisions(&mut self, dir: &Path, names: &[&[u8]]) {
+        for pair in names.windows(2) {
+            if self.policy.key(pair[0]) == self.policy.key(pair[1]) {
+                let first = dir.join(models::bytes_to_os(pair[0]));
+                let second = dir.join(models::bytes_to_os(pair[1]));
+                warn!("Names collide after normalization: {:?} and {:?}", first, second);
+                self.name_collisions.push((first, second));
+            }
+        }
+    }
+
+    /// Stores finished trees and attribute sets. They are content-addressed, so
+    /// objects left behind by a failed run are harmless and reused by the next one.
+    fn flush_trees(&mut self) -> Result<()> {
+        if self.dry_run {
+            self.trees.clear();
+            self.xattrs.clear();
+            self.tree_entries = 0;
+            return Ok(());
+        }
+        let write_txn = self.db.begin_write()?;
+        for (hash, entries) in self.trees.drain(..) {
+            self.db.insert_tree(&write_txn, &hash, &entries)?;
+        }
+        for (hash, xattrs) in self.xattrs.drain(..) {
+            self.db.insert_xattrs(&write_txn, &hash, &xattrs)?;
+        }
+        write_txn.commit()?;
+        self.tree_entries = 0;
+        Ok(())
     }
 }
 
 
+/// Hash of a tree whose entries are sorted by normalized name
+fn tree_hash(entries: &[TreeEntry]) -> Hash {
+    let entry_hashes: Vec<Hash> = entries.iter().map(TreeEntry::compute_hash).collect();
+    crate::object::hash_tree(&entry_hashes)
+}
 
-fn compute_file_hash(path: &Path) -> Result<Hash> {
-    let mut file = std::fs::File::open(path)?;
-    let mut hasher = blake3::Hasher::new();
-    let mut buffer = [0u8; 8192];
+/// Rewrites the snapshot tree `root` without the files whose content is in `unwritten`,
+/// e.g. because the run was stopped before they reached the tape, so that the snapshot
+/// only references stored blobs. Stores the new trees and returns the new root.
+pub fn drop_unwritten(db: &BackupDb, root: Hash, unwritten: &HashSet<Hash>) -> Result<Hash> {
+    fn rewrite(
+        db: &BackupDb,
+        hash: Hash,
+        unwritten: &HashSet<Hash>,
+        rewritten: &mut HashMap<Hash, Hash>,
+        trees: &mut Vec<(Hash, Vec<TreeEntry>)>,
+    ) -> Result<Hash> {
+        if let Some(new) = rewritten.get(&hash) {
+            return Ok(*new);
+        }
+        let entries = db.get_tree(&hash)?.ok_or_else(|| anyhow::anyhow!("Tree {} is not stored", hex::encode(hash)))?;
+        let mut kept = Vec::with_capacity(entries.len());
+        for mut entry in entries {
+            if entry.is_file() && unwritten.contains(&entry.hash) {
+                continue;
+            }
+            if entry.is_dir() {
+                entry.hash = rewrite(db, entry.hash, unwritten, rewritten, trees)?;
+            }
+            kept.push(entry);
+        }
+        let new = tree_hash(&kept);
+        if new != hash {
+            trees.push((new, kept));
+        }
+        rewritten.insert(hash, new);
+        Ok(new)
+    }
+
+    if unwritten.is_empty() {
+        return Ok(root);
+    }
+    let mut trees = Vec::new();
+    let new_root = rewrite(db, root, unwritten, &mut HashMap::new(), &mut trees)?;
+    let write_txn = db.begin_write()?;
+    for (hash, entries) in &trees {
+        db.insert_tree(&write_txn, hash, entries)?;
+    }
+    write_txn.commit()?;
+    Ok(new_root)
+}
+
+/// The operation on a file that failed, and its error
+type ReadFailure = (Operation, io::Error);
+
+/// Hashes the content of a file, retrying transient errors within `attempts`.
+/// Fails with the operation that failed, to record the file as unreadable.
+fn compute_file_hash(path: &Path, attempts: &mut Attempts) -> Result<Hash, ReadFailure> {
+    let mut file = attempts.run(Operation::Open, path, || std::fs::File::open(path)).map_err(|e| (Operation::Open, e))?;
+    let read = |e| (Operation::Read, e);
+    let changed_size = || (Operation::Read, io::Error::other("the file changed size while it was hashed"));
+
+    // Holes are hashed as zeros without reading them
+    let size = attempts.run(Operation::Stat, path, || file.metadata()).map_err(|e| (Operation::Stat, e))?.len();
+    let extents = crate::sparse::data_extents(&file, size).unwrap_or_else(|e| {
+        debug!("Cannot map holes of {:?}: {}", path, e);
+        None
+    });
+    if let Some(extents) = extents {
+        // Every extent is read from its offset, so a failed read starts over
+        let hasher = attempts.run(Operation::Read, path, || crate::sparse::hash_sparse(&mut file, &extents, size)).map_err(read)?;
+        return Ok(*hasher.finalize().as_bytes());
+    }
+
+    // The blob header carries the length, so the content must not change size while it is read
+    if size >= MMAP_RAYON_THRESHOLD {
+        // Runs on the current (hashing) pool, splitting the file between idle workers
+        let hasher = attempts.run(Operation::Read, path, || {
+            let mut hasher = crate::object::blob_hasher(size);
+            hasher.update_mmap_rayon(path)?;
+            Ok(hasher)
+        }).map_err(read)?;
+        if std::fs::metadata(path).map_err(read)?.len() != size {
+            return Err(changed_size());
+        }
+        return Ok(*hasher.finalize().as_bytes());
+    }
+
+    let mut hasher = crate::object::blob_hasher(size);
+    let mut reader = RetryReader::new(&mut file, attempts, path);
+    let mut buffer = [0u8; 64 * 1024];
+    let mut hashed = 0;
     loop {
-        let n = file.read(&mut buffer)?;
+        let n = reader.read(&mut buffer).map_err(read)?;
         if n == 0 {
             break;
         }
         hasher.update(&buffer[..n]);
+        hashed += n as u64;
+    }
+    if hashed != size {
+        return Err(changed_size());
     }
     Ok(*hasher.finalize().as_bytes())
 }
@@ -182,8 +1075,9 @@ mod tests {
     fn test_pipeline_flow() -> anyhow::Result<()> {
         let temp_dir = TempDir::new()?;
         let root = temp_dir.path();
-        let db_path = root.join("test.redb");
-        let db = BackupDb::new(&db_path)?;
+        // Keep the database outside the scanned tree so it is not picked up as a new file
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
 
         // Create some files
         fs::write(root.join("file1.txt"), "content1")?;
@@ -191,16 +1085,377 @@ mod tests {
         fs::write(root.join("subdir").join("file2.txt"), "content2")?;
 
         let pipeline = Pipeline::new(db, root.to_path_buf());
-        let plan = pipeline.run()?;
+        let mut plan = pipeline.run()?;
 
         // Verify plan
         // We expect 2 new files (since DB is empty)
         assert_eq!(plan.new_files.len(), 2);
         
         // Check if paths are correct (order might vary, so check existence)
-        let paths: Vec<_> = plan.new_files.iter().map(|(p, _)| p.file_name().unwrap().to_str().unwrap()).collect();
-        assert!(paths.contains(&"file1.txt"));
-        assert!(paths.contains(&"file2.txt"));
+        let paths: Vec<_> = plan.new_files.iter()?
+            .map(|b| b.unwrap().path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert!(paths.contains(&"file1.txt".to_string()));
+        assert!(paths.contains(&"file2.txt".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hardlinks_and_symlinks_planned_once() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+
+        fs::write(root.join("original.bin"), "shared content")?;
+        fs::hard_link(root.join("original.bin"), root.join("linked.bin"))?;
+        std::os::unix::fs::symlink("original.bin", root.join("alias.bin"))?;
+
+        let plan = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+        assert_eq!(plan.new_files.len(), 1);
+
+        let root_entries = db.get_tree(&plan.root_tree)?.unwrap();
+        let alias = root_entries.iter().find(|e| e.name == b"alias.bin").unwrap();
+        assert!(alias.is_symlink());
+        assert_eq!(alias.link_target.as_deref(), Some(b"original.bin".as_slice()));
+
+        let original = root_entries.iter().find(|e| e.name == b"original.bin").unwrap();
+        let linked = root_entries.iter().find(|e| e.name == b"linked.bin").unwrap();
+        assert_ne!(original.link_group, 0);
+        assert_eq!(original.link_group, linked.link_group);
+        assert_eq!(original.hash, linked.hash);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_normalized_names_collide() -> anyhow::Result<()> {
+        use unicode_normalization::UnicodeNormalization;
+
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+
+        // Same name written by a Windows (NFC) and a macOS (NFD) client
+        fs::write(root.join("résumé.txt".nfc().collect::<String>()), "windows")?;
+        fs::write(root.join("résumé.txt".nfd().collect::<String>()), "macos")?;
+        fs::write(root.join("other.txt"), "other")?;
+
+        let raw = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+        assert!(raw.name_collisions.is_empty());
+
+        let config = BackupConfig { name_normalization: "nfc".to_string(), ..BackupConfig::default() };
+        let plan = Pipeline::new(db, root.to_path_buf()).with_config(&config).run()?;
+        assert_eq!(plan.name_collisions.len(), 1);
+        // Both files are still backed up under their original names
+        assert_eq!(plan.new_files.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_renormalized_directory_gets_a_new_tree() -> anyhow::Result<()> {
+        use unicode_normalization::UnicodeNormalization;
+
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+        let config = BackupConfig { name_normalization: "nfc".to_string(), ..BackupConfig::default() };
+        let nfc: String = "Café".nfc().collect();
+        let nfd: String = "Café".nfd().collect();
+        fs::create_dir(root.join(&nfc))?;
+        fs::write(root.join(&nfc).join("menu.txt"), "menu")?;
+        let first = Pipeline::new(db.clone(), root.to_path_buf()).with_config(&config).run()?;
+
+        // A macOS client renames the directory to the NFD form of the same name
+        fs::rename(root.join(&nfc), root.join(&nfd))?;
+        let second = Pipeline::new(db.clone(), root.to_path_buf()).with_config(&config).run()?;
+        assert_ne!(second.root_tree, first.root_tree);
+        let names: Vec<Vec<u8>> = db.get_tree(&second.root_tree)?.unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec![nfd.into_bytes()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_hashing_matches_serial() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        for i in 0..200 {
+            fs::write(root.join(format!("file{}.txt", i)), format!("content {}", i % 150))?;
+        }
+
+        let mut trees = Vec::new();
+        for threads in [1, 4] {
+            let db_dir = TempDir::new()?;
+            let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+            let config = BackupConfig { parallel_threads: threads, ..BackupConfig::default() };
+            let mut plan = Pipeline::new(db, root.to_path_buf()).with_config(&config).run()?;
+            // Duplicate contents are planned once, in listing order
+            assert_eq!(plan.new_files.len(), 150);
+            let first = plan.new_files.iter()?.next().unwrap()?;
+            assert_eq!(first.hash, crate::object::hash_blob(b"content 0"));
+            trees.push(plan.root_tree);
+        }
+        assert_eq!(trees[0], trees[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_small_directories_hashed_together() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        for i in 0..60 {
+            let dir = root.join(format!("dir{}", i / 6)).join(format!("sub{}", i));
+            fs::create_dir_all(&dir)?;
+            for j in 0..3 {
+                fs::write(dir.join(format!("file{}.txt", j)), format!("content {} {}", i, j))?;
+            }
+        }
+
+        let mut plans = Vec::new();
+        for threads in [1, 4] {
+            let db_dir = TempDir::new()?;
+            let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+            let config = BackupConfig { parallel_threads: threads, ..BackupConfig::default() };
+            let plan = Pipeline::new(db.clone(), root.to_path_buf()).with_config(&config).run()?;
+            assert_eq!((plan.files, plan.new_files.len()), (180, 180));
+            assert_eq!(db.get_tree(&plan.root_tree)?.unwrap().len(), 10);
+            plans.push((plan.root_tree, plan.hashed_bytes));
+        }
+        assert_eq!(plans[0], plans[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_streaming_sends_new_blobs() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+        fs::create_dir(root.join("a"))?;
+        fs::write(root.join("a").join("one.txt"), "one")?;
+        fs::write(root.join("a").join("copy.txt"), "one")?;
+        fs::write(root.join("two.txt"), "two")?;
+
+        // A channel smaller than the plan: the run only completes if the consumer keeps up
+        let (tx, rx) = mpsc::sync_channel(1);
+        let consumer = std::thread::spawn(move || rx.into_iter().collect::<Vec<PlannedBlob>>());
+        let mut plan = Pipeline::new(db, root.to_path_buf()).run_streaming(tx)?;
+        // The sink is closed once the run ends
+        let streamed = consumer.join().unwrap();
+
+        let planned = plan.new_files.iter()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(streamed, planned);
+        assert_eq!(streamed.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copies_of_stored_content_are_indexed() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+        fs::write(root.join("original.txt"), "shared")?;
+
+        // Commit blobs and index entries the way `rumba backup` does
+        let commit = |plan: &mut BackupPlan| -> anyhow::Result<()> {
+            let txn = db.begin_write()?;
+            for blob in plan.new_files.iter()? {
+                let blob = blob?;
+                db.insert_blob(&txn, &blob.hash, &models::BlobLocation { tape_id: 1, offset: 0, block_size: 512 })?;
+            }
+            for update in plan.new_files.index_entries()? {
+                let (key, entry) = update?;
+                db.insert_index(&txn, &key, &entry)?;
+            }
+            txn.commit()?;
+            Ok(())
+        };
+
+        let mut first = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+        assert_eq!(first.new_files.index_len(), 1);
+        commit(&mut first)?;
+
+        // The copy needs no new blob, but is hashed once and indexed
+        fs::write(root.join("copy.txt"), "shared")?;
+        let mut second = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+        assert_eq!(second.new_files.len(), 0);
+        let keys: Vec<Vec<u8>> = second.new_files.index_entries()?.map(|u| u.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, vec![models::index_key(0, b"copy.txt")]);
+        commit(&mut second)?;
+
+        let third = Pipeline::new(db, root.to_path_buf()).run()?;
+        assert_eq!(third.new_files.index_len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rehash_finds_silent_changes() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+
+        // The index says the file is clean, but its content is not what was hashed:
+        // what bit rot on the NAS looks like
+        fs::write(root.join("disk.img"), "corrupt!")?;
+        let good = crate::object::hash_blob(b"original");
+        let metadata = FileMetadata::from_fs(&fs::metadata(root.join("disk.img"))?, good);
+        let txn = db.begin_write()?;
+        db.insert_index(&txn, &models::index_key(0, b"disk.img"), &IndexEntry::from_metadata(&metadata))?;
+        db.insert_blob(&txn, &good, &models::BlobLocation { tape_id: 1, offset: 0, block_size: 512 })?;
+        txn.commit()?;
+
+        let trusting = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+        assert_eq!((trusting.rehashed, trusting.new_files.len()), (0, 0));
+
+        let config = BackupConfig { rehash_days: 1, ..BackupConfig::default() };
+        let paranoid = Pipeline::new(db, root.to_path_buf()).with_config(&config).run()?;
+        assert_eq!(paranoid.rehashed, 1);
+        assert_eq!(paranoid.silent_changes, vec![SilentChange {
+            path: root.join("disk.img"),
+            previous: good,
+            current: crate::object::hash_blob(b"corrupt!"),
+        }]);
+        // The current content is backed up and indexed as usual
+        assert_eq!(paranoid.new_files.len(), 1);
+        assert_eq!(paranoid.new_files.index_len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unchanged_subtrees_are_reused() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+        for dir in ["photos/2023", "photos/2024", "docs"] {
+            fs::create_dir_all(root.join(dir))?;
+            fs::write(root.join(dir).join("file.txt"), dir)?;
+        }
+
+        let mut first = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+        assert_eq!(first.reused_trees, 0);
+
+        // Nothing changed: every directory, the root included, is reused
+        let same = Pipeline::new(db.clone(), root.to_path_buf())
+            .with_previous_tree(Some(first.root_tree))
+            .run()?;
+        assert_eq!(same.root_tree, first.root_tree);
+        assert_eq!(same.reused_trees, 5);
+
+        // With the files indexed, the previous trees are taken without hashing anything
+        let txn = db.begin_write()?;
+        for update in first.new_files.index_entries()? {
+            let (key, entry) = update?;
+            db.insert_index(&txn, &key, &entry)?;
+        }
+        txn.commit()?;
+        let clean = Pipeline::new(db.clone(), root.to_path_buf())
+            .with_previous_tree(Some(first.root_tree))
+            .run()?;
+        assert_eq!((clean.root_tree, clean.reused_trees), (first.root_tree, 5));
+        assert_eq!((clean.clean, clean.hashed_bytes, clean.new_files.index_len()), (3, 0, 0));
+
+        // Only the changed directory and its ancestors are rebuilt
+        fs::write(root.join("photos/2024/new.txt"), "new")?;
+        let changed = Pipeline::new(db.clone(), root.to_path_buf())
+            .with_previous_tree(Some(first.root_tree))
+            .run()?;
+        assert_ne!(changed.root_tree, first.root_tree);
+        assert_eq!(changed.reused_trees, 2);
+        assert!(db.get_tree(&changed.root_tree)?.is_some());
+
+        // A change of metadata alone rebuilds the directory
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(root.join("docs/file.txt"), fs::Permissions::from_mode(0o600))?;
+            let chmod = Pipeline::new(db.clone(), root.to_path_buf())
+                .with_previous_tree(Some(first.root_tree))
+                .run()?;
+            assert_ne!(chmod.root_tree, first.root_tree);
+            assert_eq!(chmod.reused_trees, 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_counters() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+        fs::write(root.join("a.txt"), "same")?;
+        fs::write(root.join("b.txt"), "same")?;
+        fs::write(root.join("scratch.tmp"), "temporary")?;
+        fs::create_dir(root.join("cache"))?;
+        fs::write(root.join("cache").join("c.bin"), "cached")?;
+
+        let plan = Pipeline::new(db, root.to_path_buf())
+            .with_excludes(&["*.tmp".to_string(), "cache".to_string()])
+            .run()?;
+        assert_eq!((plan.files, plan.clean, plan.hashed_bytes), (2, 0, 8));
+        assert_eq!((plan.deduplicated, plan.deduplicated_bytes), (1, 4));
+        assert_eq!(plan.excluded, 2);
+        assert!(plan.errors.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unwritten_files_are_dropped_from_tree() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let db_dir = TempDir::new()?;
+        let db = BackupDb::new(db_dir.path().join("test.redb"))?;
+        fs::create_dir(root.join("a"))?;
+        fs::write(root.join("a").join("one.txt"), "one")?;
+        fs::write(root.join("two.txt"), "two")?;
+
+        let plan = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+        assert_eq!(drop_unwritten(&db, plan.root_tree, &HashSet::new())?, plan.root_tree);
+
+        // The run stopped before two.txt reached the tape
+        let unwritten = HashSet::from([crate::object::hash_blob(b"two")]);
+        let pruned = drop_unwritten(&db, plan.root_tree, &unwritten)?;
+        let names: Vec<Vec<u8>> = db.get_tree(&pruned)?.unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec![b"a".to_vec()]);
+        // The same tree comes out of a run over what was written
+        fs::remove_file(root.join("two.txt"))?;
+        let rerun = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+        assert_eq!(rerun.root_tree, pruned);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreadable_file_fails_with_operation() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::write(root.join("ledger.xlsx"), "balance")?;
+
+        let retry = RetryPolicy { backoff: std::time::Duration::ZERO, ..RetryPolicy::default() };
+        let mut attempts = retry.for_file();
+        assert_eq!(compute_file_hash(&root.join("ledger.xlsx"), &mut attempts).ok(), Some(crate::object::hash_blob(b"balance")));
+
+        // A file gone since it was listed is not retried, and the run goes on without it
+        let (operation, error) = compute_file_hash(&root.join("gone.xlsx"), &mut attempts).unwrap_err();
+        assert_eq!((operation, error.kind()), (Operation::Open, io::ErrorKind::NotFound));
+        assert_eq!(attempts.failures(), 0);
 
         Ok(())
     }
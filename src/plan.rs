@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use crate::models::{self, FileMetadata, Hash};
+use crate::spill::{PlannedBlob, SpillStore};
+
+/// Version of the plan file format; `apply` refuses other versions
+pub const PLAN_VERSION: u32 = 1;
+
+/// A backup plan written by `rumba plan` for review and executed later by `rumba apply`.
+/// It lists the files whose content would be written, with the metadata they had when
+/// they were hashed, so that apply can tell whether they changed since.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub version: u32,
+    pub source: String,
+    pub url: String,
+    /// UNIX time the plan was made
+    pub created: u64,
+    /// Hash of the snapshot the plan builds on; apply refuses if the source moved on
+    pub parent: Option<String>,
+    pub total_size: u64,
+    /// Approximate size of the tape stream, including tar headers and padding
+    pub estimated_tape_bytes: u64,
+    /// Hashed files whose content is already stored and is not written again
+    pub deduplicated: u64,
+    pub files: Vec<PlannedFile>,
+}
+
+/// A file whose content would be written to tape
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlannedFile {
+    /// Relative to the source root
+    #[serde(with = "raw_path")]
+    pub path: PathBuf,
+    pub hash: String,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub ctime: i64,
+    pub ctime_nsec: u32,
+    pub ino: u64,
+}
+
+impl PlannedFile {
+    /// Describes `blob` with the current metadata of its file
+    pub fn from_blob(root: &Path, blob: &PlannedBlob) -> Result<Self> {
+        let metadata = std::fs::symlink_metadata(&blob.path)
+            .with_context(|| format!("Cannot stat {:?}", blob.path))?;
+        let meta = FileMetadata::from_fs(&metadata, blob.hash);
+        Ok(Self {
+            path: blob.path.strip_prefix(root).unwrap_or(&blob.path).to_path_buf(),
+            hash: hex::encode(blob.hash),
+            size: meta.size,
+            mtime: meta.mtime,
+            mtime_nsec: meta.mtime_nsec,
+            ctime: meta.ctime,
+            ctime_nsec: meta.ctime_nsec,
+            ino: meta.ino,
+        })
+    }
+
+    /// True if the file under `root` still has the size, times and inode of the plan
+    pub fn is_unchanged(&self, root: &Path) -> bool {
+        let Ok(metadata) = std::fs::symlink_metadata(root.join(&self.path)) else {
+            return false;
+        };
+        let meta = FileMetadata::from_fs(&metadata, [0u8; 32]);
+        meta.size == self.size
+            && meta.mtime == self.mtime
+            && meta.mtime_nsec == self.mtime_nsec
+            && meta.ctime == self.ctime
+            && meta.ctime_nsec == self.ctime_nsec
+            && meta.ino == self.ino
+    }
+}
+
+impl PlanFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Cannot open plan {}", path.display()))?;
+        let plan: PlanFile = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Invalid plan file {}", path.display()))?;
+        if plan.version != PLAN_VERSION {
+            bail!("Plan {} has version {}, this version of rumba reads version {}", path.display(), plan.version, PLAN_VERSION);
+        }
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Cannot create plan {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Planned files that changed or disappeared since the plan was made
+    pub fn changed_files(&self, root: &Path) -> Vec<&Path> {
+        self.files.iter()
+            .filter(|file| !file.is_unchanged(root))
+            .map(|file| file.path.as_path())
+            .collect()
+    }
+
+    /// Checks that a new run of the pipeline writes nothing the plan does not list
+    pub fn check_blobs(&self, blobs: &mut SpillStore) -> Result<()> {
+        let planned = self.files.iter()
+            .map(|file| {
+                let mut hash: Hash = [0u8; 32];
+                hex::decode_to_slice(&file.hash, &mut hash)
+                    .with_context(|| format!("Invalid hash in plan: {}", file.hash))?;
+                Ok(hash)
+            })
+            .collect::<Result<HashSet<Hash>>>()?;
+        let mut unplanned = Vec::new();
+        for blob in blobs.iter()? {
+            let blob = blob?;
+            if !planned.contains(&blob.hash) {
+                unplanned.push(blob.path);
+            }
+        }
+        if !unplanned.is_empty() {
+            bail!(
+                "{} file(s) would be written that the plan does not list, e.g. {}; make a new plan",
+                unplanned.len(),
+                unplanned[0].display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Paths as JSON strings when they are UTF-8, and as `{"raw": "<hex>"}` otherwise,
+/// so that names in legacy encodings survive the round trip
+mod raw_path {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Text(String),
+        Raw { raw: String },
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = models::os_to_bytes(path.as_os_str());
+        match String::from_utf8(bytes) {
+            Ok(text) => Repr::Text(text),
+            Err(e) => Repr::Raw { raw: hex::encode(e.into_bytes()) },
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        let bytes = match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => text.into_bytes(),
+            Repr::Raw { raw } => hex::decode(raw).map_err(serde::de::Error::custom)?,
+        };
+        Ok(PathBuf::from(models::bytes_to_os(&bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_plan_round_trip_and_validation() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        std::fs::write(root.join("report.txt"), "quarterly numbers")?;
+        let blob = PlannedBlob {
+            path: root.join("report.txt"),
+            hash: crate::object::hash_blob(b"quarterly numbers"),
+            size: 17,
+        };
+
+        let plan = PlanFile {
+            version: PLAN_VERSION,
+            source: "default".to_string(),
+            url: root.display().to_string(),
+            created: 0,
+            parent: None,
+            total_size: 17,
+            estimated_tape_bytes: 2048,
+            deduplicated: 0,
+            files: vec![PlannedFile::from_blob(root, &blob)?],
+        };
+        let path = root.join("plan.json");
+        plan.save(&path)?;
+        let loaded = PlanFile::load(&path)?;
+        assert_eq!(loaded.files, plan.files);
+        assert_eq!(loaded.files[0].path, PathBuf::from("report.txt"));
+        assert!(loaded.changed_files(root).is_empty());
+
+        let mut blobs = SpillStore::new(None)?;
+        blobs.push(blob)?;
+        loaded.check_blobs(&mut blobs)?;
+        blobs.push(PlannedBlob { path: root.join("new.txt"), hash: [9u8; 32], size: 1 })?;
+        assert!(loaded.check_blobs(&mut blobs).is_err());
+
+        // Rewriting the file changes its size and times
+        std::fs::write(root.join("report.txt"), "restated quarterly numbers")?;
+        assert_eq!(loaded.changed_files(root), vec![Path::new("report.txt")]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_non_utf8_paths_survive() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        let file = PlannedFile {
+            path: PathBuf::from(name),
+            hash: hex::encode([0u8; 32]),
+            size: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            ctime: 0,
+            ctime_nsec: 0,
+            ino: 0,
+        };
+        let json = serde_json::to_string(&file)?;
+        assert!(json.contains(r#""raw":"636166e92e747874""#));
+        assert_eq!(serde_json::from_str::<PlannedFile>(&json)?, file);
+        Ok(())
+    }
+}
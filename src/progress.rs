@@ -0,0 +1,297 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the terminal line is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Receives progress events from the scanner, the pipeline and the tape writer.
+/// Called from their threads concurrently, so implementations only take `&self`.
+pub trait ProgressObserver: Send + Sync {
+    /// A directory was listed with `files` regular files and `dirs` subdirectories
+    fn dir_scanned(&self, _files: u64, _dirs: u64) {}
+    /// Every directory has been listed, so the number of files is final
+    fn scan_finished(&self) {}
+    /// `files` files of `bytes` bytes were checked and, if needed, hashed
+    /// (`hashed` of those bytes were read to do so)
+    fn files_processed(&self, _files: u64, _bytes: u64, _hashed: u64) {}
+    /// New content of `size` bytes was queued for the tape
+    fn blob_planned(&self, _size: u64) {}
+    /// A blob of `size` bytes was written to the tape
+    fn blob_written(&self, _size: u64) {}
+}
+
+/// Ignores all events
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {}
+
+/// Counts the events of a run
+#[derive(Debug, Default)]
+pub struct ProgressCounters {
+    files_found: AtomicU64,
+    dirs_found: AtomicU64,
+    scan_done: AtomicBool,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    bytes_hashed: AtomicU64,
+    blobs_planned: AtomicU64,
+    bytes_planned: AtomicU64,
+    blobs_written: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl ProgressObserver for ProgressCounters {
+    fn dir_scanned(&self, files: u64, dirs: u64) {
+        self.files_found.fetch_add(files, Ordering::Relaxed);
+        self.dirs_found.fetch_add(dirs, Ordering::Relaxed);
+    }
+
+    fn scan_finished(&self) {
+        self.scan_done.store(true, Ordering::Relaxed);
+    }
+
+    fn files_processed(&self, files: u64, bytes: u64, hashed: u64) {
+        self.files_done.fetch_add(files, Ordering::Relaxed);
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        self.bytes_hashed.fetch_add(hashed, Ordering::Relaxed);
+    }
+
+    fn blob_planned(&self, size: u64) {
+        self.blobs_planned.fetch_add(1, Ordering::Relaxed);
+        self.bytes_planned.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn blob_written(&self, size: u64) {
+        self.blobs_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+impl ProgressCounters {
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            files_found: self.files_found.load(Ordering::Relaxed),
+            dirs_found: self.dirs_found.load(Ordering::Relaxed),
+            scan_done: self.scan_done.load(Ordering::Relaxed),
+            files_done: self.files_done.load(Ordering::Relaxed),
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            bytes_hashed: self.bytes_hashed.load(Ordering::Relaxed),
+            blobs_planned: self.blobs_planned.load(Ordering::Relaxed),
+            bytes_planned: self.bytes_planned.load(Ordering::Relaxed),
+            blobs_written: self.blobs_written.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counters at one point in time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProgressSnapshot {
+    pub files_found: u64,
+    pub dirs_found: u64,
+    pub scan_done: bool,
+    pub files_done: u64,
+    pub bytes_done: u64,
+    pub bytes_hashed: u64,
+    pub blobs_planned: u64,
+    pub bytes_planned: u64,
+    pub blobs_written: u64,
+    pub bytes_written: u64,
+}
+
+impl ProgressSnapshot {
+    /// Time left at the average rates so far: the longer of checking the remaining
+    /// files and writing the remaining queued bytes. Unknown until the scan is done.
+    pub fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        if !self.scan_done {
+            return None;
+        }
+        let remaining = |done: u64, total: u64| -> Option<Duration> {
+            let left = total.saturating_sub(done);
+            if left == 0 {
+                Some(Duration::ZERO)
+            } else if done == 0 {
+                None
+            } else {
+                // Unknown as well if it is too far off to represent
+                Duration::try_from_secs_f64(elapsed.as_secs_f64() * (left as f64 / done as f64)).ok()
+            }
+        };
+        let check = remaining(self.files_done, self.files_found)?;
+        let write = remaining(self.bytes_written, self.bytes_planned)?;
+        Some(check.max(write))
+    }
+
+    /// One line of status, e.g. for a log file
+    pub fn status_line(&self, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64().max(0.001);
+        let files_total = if self.scan_done {
+            self.files_found.to_string()
+        } else {
+            format!("{}+", self.files_found)
+        };
+        let eta = match self.eta(elapsed) {
+            Some(eta) => format_duration(eta),
+            None => "?".to_string(),
+        };
+        format!(
+            "{}/{} files ({}), hashed {} at {}/s, wrote {} of {} at {}/s, elapsed {}, ETA {}",
+            self.files_done,
+            files_total,
+            format_bytes(self.bytes_done),
+            format_bytes(self.bytes_hashed),
+            format_bytes((self.bytes_hashed as f64 / secs) as u64),
+            format_bytes(self.bytes_written),
+            format_bytes(self.bytes_planned),
+            format_bytes((self.bytes_written as f64 / secs) as u64),
+            format_duration(elapsed),
+            eta
+        )
+    }
+}
+
+/// Shows the progress of a run: a line on the terminal redrawn in place (only if
+/// stderr is a terminal) and, every `log_interval`, a status line in the log
+pub struct ProgressDisplay {
+    counters: Arc<ProgressCounters>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProgressDisplay {
+    pub fn start(label: &str, log_interval: Option<Duration>) -> Self {
+        let counters = Arc::new(ProgressCounters::default());
+        let (stop, stopped) = mpsc::channel::<()>();
+        let interactive = std::io::stderr().is_terminal();
+        let thread = (interactive || log_interval.is_some()).then(|| {
+            let counters = counters.clone();
+            let label = label.to_string();
+            std::thread::spawn(move || {
+                let started = Instant::now();
+                let mut last_log = started;
+                // Runs until the display is finished and drops the sender
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(REDRAW_INTERVAL) {
+                    let line = counters.snapshot().status_line(started.elapsed());
+                    if interactive {
+                        let mut stderr = std::io::stderr().lock();
+                        let _ = write!(stderr, "\r\x1b[K{}: {}", label, line);
+                        let _ = stderr.flush();
+                    }
+                    if log_interval.is_some_and(|interval| last_log.elapsed() >= interval) {
+                        last_log = Instant::now();
+                        if interactive {
+                            let _ = write!(std::io::stderr(), "\r\x1b[K");
+                        }
+                        tracing::info!("Progress of {}: {}", label, line);
+                    }
+                }
+                if interactive {
+                    let _ = write!(std::io::stderr(), "\r\x1b[K");
+                }
+            })
+        });
+        Self { counters, stop: Some(stop), thread }
+    }
+
+    /// The observer to hand to the scanner, pipeline and tape writer
+    pub fn observer(&self) -> Arc<dyn ProgressObserver> {
+        self.counters.clone()
+    }
+
+    /// Stops the display and clears the terminal line
+    pub fn finish(mut self) -> ProgressSnapshot {
+        self.stop_thread();
+        self.counters.snapshot()
+    }
+
+    fn stop_thread(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ProgressDisplay {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. "1.5 GiB"
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration as e.g. "1h02m", "3m05s" or "42s"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eta_and_status_line() {
+        let counters = ProgressCounters::default();
+        counters.dir_scanned(100, 3);
+        counters.files_processed(25, 25 * 1024 * 1024, 10 * 1024 * 1024);
+        counters.blob_planned(8 * 1024 * 1024);
+        counters.blob_written(4 * 1024 * 1024);
+
+        // The total is not known while the scan runs
+        let elapsed = Duration::from_secs(60);
+        assert_eq!(counters.snapshot().eta(elapsed), None);
+        assert!(counters.snapshot().status_line(elapsed).starts_with("25/100+ files (25.0 MiB)"));
+
+        // A quarter of the files took a minute: three more to check them all,
+        // which is longer than writing the queued bytes
+        counters.scan_finished();
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.eta(elapsed), Some(Duration::from_secs(180)));
+        assert_eq!(
+            snapshot.status_line(elapsed),
+            "25/100 files (25.0 MiB), hashed 10.0 MiB at 170.7 KiB/s, wrote 4.0 MiB of 8.0 MiB at 68.3 KiB/s, elapsed 1m00s, ETA 3m00s"
+        );
+
+        // One byte written in a day, with a huge plan left, has no ETA instead of a panic
+        let stalled = ProgressCounters::default();
+        stalled.dir_scanned(1, 0);
+        stalled.files_processed(1, 1, 1);
+        stalled.blob_planned(u64::MAX);
+        stalled.blob_written(1);
+        stalled.scan_finished();
+        assert_eq!(stalled.snapshot().eta(Duration::from_secs(86_400)), None);
+    }
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536 * 1024 * 1024), "1.5 GiB");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h02m");
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+use crate::errors;
+use crate::models::{Hash, RunSummary};
+use crate::pipeline::BackupPlan;
+
+/// Version of the report format, for monitoring that parses it
+pub const REPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    /// The snapshot was recorded and became the latest one of its source
+    Completed,
+    /// Like completed, but some entries could not be read and the snapshot is partial
+    Partial,
+    /// The snapshot was recorded but held back by the anomaly checks
+    Held,
+    /// Stopped by a signal or the end of the backup window; a partial snapshot
+    /// of what was written was recorded, but not made the latest one
+    Interrupted,
+    Failed,
+}
+
+impl RunStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RunStatus::Completed => "completed",
+            RunStatus::Partial => "partial",
+            RunStatus::Held => "held",
+            RunStatus::Interrupted => "interrupted",
+            RunStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Machine-readable account of one backup run of one source
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub version: u32,
+    pub source: String,
+    pub status: RunStatus,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub duration_secs: f64,
+    /// Wall time of each phase in the order they ran. Scanning and writing to
+    /// tape overlap, so the phases can add up to more than the whole run.
+    pub phases: Vec<PhaseTiming>,
+    pub files: FileCounts,
+    pub bytes: ByteCounts,
+    /// Share of the changed content that was already stored (0 to 1)
+    pub dedup_ratio: f64,
+    pub tapes: Vec<TapeUsage>,
+    /// Hash of the recorded snapshot
+    pub commit: Option<String>,
+    pub errors: Vec<ReportedError>,
+    /// Number of errors of each kind, e.g. "PermissionDenied"
+    pub errors_by_kind: BTreeMap<String, u64>,
+    #[serde(skip)]
+    clock: Instant,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseTiming {
+    pub name: String,
+    pub secs: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileCounts {
+    /// Regular files seen
+    pub scanned: u64,
+    /// Files the index showed to be unchanged; they are not read
+    pub clean: u64,
+    /// Files hashed because their metadata changed or they are new to the index
+    pub dirty: u64,
+    /// Files whose content was written to tape
+    pub new: u64,
+    /// Dirty files whose content was already stored
+    pub deduplicated: u64,
+    /// Entries left out by the exclude patterns
+    pub excluded: u64,
+    /// Entries that could not be read
+    pub errored: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ByteCounts {
+    /// Read from the source: hashed, then read again to write new content
+    pub read: u64,
+    /// Of the tape stream, including tar headers and padding
+    pub written: u64,
+    /// Size of the new content
+    pub new: u64,
+    /// Size of the deduplicated files
+    pub deduplicated: u64,
+}
+
+/// Part of a tape written by the run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TapeUsage {
+    pub tape_id: u64,
+    pub start_offset: u64,
+    pub end_offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportedError {
+    /// Entry the error is about (lossily converted to UTF-8); None for errors that ended the run
+    pub path: Option<String>,
+    /// What was being done, e.g. "stat"
+    pub operation: Option<String>,
+    pub kind: Option<String>,
+    pub message: String,
+}
+
+impl RunReport {
+    /// Starts the report of a run of `source` that starts now
+    pub fn start(source: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            version: REPORT_VERSION,
+            source: source.to_string(),
+            status: RunStatus::Failed,
+            started: now,
+            finished: now,
+            duration_secs: 0.0,
+            phases: Vec::new(),
+            files: FileCounts::default(),
+            bytes: ByteCounts::default(),
+            dedup_ratio: 0.0,
+            tapes: Vec::new(),
+            commit: None,
+            errors: Vec::new(),
+            errors_by_kind: BTreeMap::new(),
+            clock: Instant::now(),
+        }
+    }
+
+    pub fn phase(&mut self, name: &str, duration: Duration) {
+        self.phases.push(PhaseTiming { name: name.to_string(), secs: duration.as_secs_f64() });
+    }
+
+    /// Records an error that ended the run
+    pub fn error(&mut self, message: impl Into<String>) {
+        self.errors.push(ReportedError { path: None, operation: None, kind: None, message: message.into() });
+    }
+
+    /// Records an entry that could not be read
+    pub fn scan_error(&mut self, error: &errors::ScanError) {
+        *self.errors_by_kind.entry(error.kind_name()).or_insert(0) += 1;
+        self.errors.push(ReportedError {
+            path: Some(error.path.to_string_lossy().into_owned()),
+            operation: Some(error.operation.to_string()),
+            kind: Some(error.kind_name()),
+            message: error.message.clone(),
+        });
+    }
+
+    /// Takes over the counters of a finished pipeline run
+    pub fn add_plan(&mut self, plan: &BackupPlan) {
+        self.files.scanned += plan.files;
+        self.files.clean += plan.clean;
+        self.files.dirty += plan.files - plan.clean;
+        self.files.new += plan.new_files.len();
+        self.files.deduplicated += plan.deduplicated;
+        self.files.excluded += plan.excluded;
+        self.files.errored += plan.errors.len() as u64;
+        self.bytes.read += plan.hashed_bytes + plan.total_size;
+        self.bytes.new += plan.total_size;
+        self.bytes.deduplicated += plan.deduplicated_bytes;
+        for error in &plan.errors {
+            self.scan_error(error);
+        }
+    }
+
+    pub fn add_tape(&mut self, usage: TapeUsage) {
+        self.bytes.written += usage.end_offset - usage.start_offset;
+        self.tapes.push(usage);
+    }
+
+    pub fn set_commit(&mut self, hash: &Hash) {
+        self.commit = Some(hex::encode(hash));
+    }
+
+    /// Ends the report now with the given outcome
+    pub fn finish(&mut self, status: RunStatus) {
+        self.status = status;
+        self.finished = Utc::now();
+        self.duration_secs = self.clock.elapsed().as_secs_f64();
+        let changed = self.bytes.new + self.bytes.deduplicated;
+        self.dedup_ratio = if changed == 0 { 0.0 } else { self.bytes.deduplicated as f64 / changed as f64 };
+    }
+
+    /// Where to write the report, given the `report_path` template
+    pub fn output_path(&self, template: &str) -> PathBuf {
+        let time = self.started.with_timezone(&Local).format("%Y%m%d_%H%M%S").to_string();
+        PathBuf::from(template.replace("{source}", &self.source).replace("{time}", &time))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Cannot create report {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// The part of the report kept in the database
+    pub fn summary(&self) -> RunSummary {
+        let commit = self.commit.as_ref().and_then(|hex| {
+            let mut hash: Hash = [0u8; 32];
+            hex::decode_to_slice(hex, &mut hash).ok().map(|()| hash)
+        });
+        let message = self.errors.iter()
+            .find(|error| error.path.is_none())
+            .or(self.errors.first())
+            .map(|error| match &error.path {
+                Some(path) => format!("{}: {}", path, error.message),
+                None => error.message.clone(),
+            });
+        RunSummary {
+            source: self.source.clone(),
+            started: self.started.timestamp().max(0) as u64,
+            finished: self.finished.timestamp().max(0) as u64,
+            status: self.status.as_str().to_string(),
+            commit,
+            files_scanned: self.files.scanned,
+            files_written: self.files.new,
+            bytes_written: self.bytes.written,
+            errors: self.errors.len() as u64,
+            message,
+        }
+    }
+}
+
+/// Formats a UNIX time of a run summary for display
+pub fn format_time(timestamp: u64) -> String {
+    DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_json_and_summary() -> Result<()> {
+        let mut report = RunReport::start("office");
+        report.phase("scan", Duration::from_millis(1500));
+        report.files.scanned = 10;
+        report.files.new = 2;
+        report.bytes.new = 300;
+        report.bytes.deduplicated = 100;
+        report.add_tape(TapeUsage { tape_id: 1, start_offset: 0, end_offset: 2048 });
+        report.scan_error(&errors::ScanError {
+            path: PathBuf::from("/mnt/share/locked.xlsx"),
+            operation: errors::Operation::Stat,
+            kind: std::io::ErrorKind::PermissionDenied,
+            message: "Permission denied".to_string(),
+        });
+        report.set_commit(&[0xab; 32]);
+        report.finish(RunStatus::Completed);
+        assert_eq!(report.dedup_ratio, 0.25);
+
+        let json: serde_json::Value = serde_json::to_value(&report)?;
+        assert_eq!(json["status"], "completed");
+        assert_eq!(json["phases"][0]["secs"], 1.5);
+        assert_eq!(json["bytes"]["written"], 2048);
+        assert_eq!(json["tapes"][0]["end_offset"], 2048);
+        assert_eq!(json["errors"][0]["path"], "/mnt/share/locked.xlsx");
+        assert_eq!(json["errors_by_kind"]["PermissionDenied"], 1);
+        assert!(json["started"].as_str().is_some_and(|s| s.ends_with('Z')));
+
+        let summary = report.summary();
+        assert_eq!(summary.status, "completed");
+        assert_eq!(summary.commit, Some([0xab; 32]));
+        assert_eq!((summary.files_scanned, summary.files_written, summary.bytes_written), (10, 2, 2048));
+        assert_eq!(summary.message.as_deref(), Some("/mnt/share/locked.xlsx: Permission denied"));
+
+        assert_eq!(report.output_path("/var/log/rumba/{source}.json"), PathBuf::from("/var/log/rumba/office.json"));
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, bail};
+use tracing::{debug, info, warn};
+use crate::db::BackupDb;
+use crate::models::{self, BlobLocation, Hash, TreeEntry};
+use crate::{object, sparse};
+
+#[derive(Debug, Default)]
+pub struct RestoreStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub hardlinks: u64,
+    pub special: u64,
+    pub bytes: u64,
+}
+
+/// Restores a snapshot tree from tar archives written by `TapeWriter`.
+pub struct Restorer<'a> {
+    db: &'a BackupDb,
+    /// Archives to look for blobs in (one per backup run in tar mode)
+    archives: Vec<PathBuf>,
+}
+
+impl<'a> Restorer<'a> {
+    pub fn new(db: &'a BackupDb, archives: Vec<PathBuf>) -> Self {
+        Self { db, archives }
+    }
+
+    /// Restores the tree `tree_hash` into `target`, which is created if missing.
+    pub fn restore_tree(&self, tree_hash: &Hash, target: &Path) -> Result<RestoreStats> {
+        info!("Restoring tree {} into {:?}", hex::encode(tree_hash), target);
+        std::fs::create_dir_all(target)?;
+        let mut stats = RestoreStats::default();
+        let mut hardlinks = HashMap::new();
+        self.restore_dir(tree_hash, target, &mut hardlinks, &mut stats)?;
+        Ok(stats)
+    }
+
+    /// `hardlinks` maps a link group to the first path restored for it
+    fn restore_dir(&self, tree_hash: &Hash, dir: &Path, hardlinks: &mut HashMap<u64, PathBuf>, stats: &mut RestoreStats) -> Result<()> {
+        let entries = self.db.get_tree(tree_hash)?
+            .with_context(|| format!("Tree {} not found in database", hex::encode(tree_hash)))?;
+
+        for entry in &entries {
+            let path = dir.join(models::bytes_to_os(&entry.name));
+            if entry.is_dir() {
+                std::fs::create_dir_all(&path)?;
+                self.restore_dir(&entry.hash, &path, hardlinks, stats)?;
+                stats.dirs += 1;
+            } else if entry.is_symlink() {
+                let target = entry.link_target.as_deref().context("Symlink entry without target")?;
+                create_symlink(Path::new(&models::bytes_to_os(target)), &path)?;
+                stats.symlinks += 1;
+            } else if entry.is_file() {
+                if entry.link_group != 0 {
+                    if let Some(first) = hardlinks.get(&entry.link_group) {
+                        // Shares the inode (and thus the metadata) of the first link
+                        std::fs::hard_link(first, &path)?;
+                        stats.hardlinks += 1;
+                        continue;
+                    }
+                    hardlinks.insert(entry.link_group, path.clone());
+                }
+                stats.bytes += self.restore_blob(&entry.hash, &path)?;
+                stats.files += 1;
+            } else if !create_special(&path, entry)? {
+                continue;
+            } else {
+                stats.special += 1;
+            }
+            // Directories get their metadata after their children, otherwise
+            // restoring the children would bump mtime or fail on read-only modes.
+            // Extended attributes go first: ACLs need write access and chmod adjusts their mask.
+            if let Some(xattr_hash) = &entry.xattrs {
+                match self.db.get_xattrs(xattr_hash)? {
+                    Some(xattrs) => crate::xattrs::apply_xattrs(&path, &xattrs)?,
+                    None => warn!("Extended attributes {} of {:?} missing from database", hex::encode(xattr_hash), path),
+                }
+            }
+            apply_metadata(&path, entry)?;
+        }
+        Ok(())
+    }
+
+    /// Extracts a blob into `path` by seeking to its recorded offset in each archive
+    /// until the content hash matches. Returns the logical size of the file.
+    fn restore_blob(&self, hash: &Hash, path: &Path) -> Result<u64> {
+        let location = self.db.get_blob(hash)?
+            .with_context(|| format!("Blob {} not found in database", hex::encode(hash)))?;
+
+        for archive in &self.archives {
+            match extract_tar_entry_at(archive, &location, path) {
+                Ok((restored_hash, size)) => {
+                    if restored_hash == *hash || object::legacy_blob_hash(path).ok() == Some(*hash) {
+                        return Ok(size);
+                    }
+                    debug!("Blob {} not at offset {} of {:?}", hex::encode(hash), location.offset, archive);
+                }
+                Err(e) => debug!("Cannot read {:?} at offset {}: {}", archive, location.offset, e),
+            }
+        }
+        bail!("Blob {} not found in any archive (tape {}, offset {})", hex::encode(hash), location.tape_id, location.offset)
+    }
+}
+
+/// Positions `file` at the entry of `location` the way a tape is read: at the start
+/// of the block holding it, then past the bytes of the block that precede it
+fn seek_to_entry(file: &mut File, location: &BlobLocation) -> Result<()> {
+    match location.block_position() {
+        Some((block, within)) => {
+            file.seek(SeekFrom::Start(block * location.block_size))?;
+            std::io::copy(&mut Read::by_ref(file).take(within), &mut std::io::sink())?;
+        }
+        // Written before block sizes were recorded
+        None => {
+            file.seek(SeekFrom::Start(location.offset))?;
+        }
+    }
+    Ok(())
+}
+
+/// Streams the tar entry at `location` into `dest`, recreating holes of sparse entries.
+/// Returns the hash of the logical content and its size.
+fn extract_tar_entry_at(archive: &Path, location: &BlobLocation, dest: &Path) -> Result<(Hash, u64)> {
+    let mut file = File::open(archive)?;
+    seek_to_entry(&mut file, location)?;
+    let mut tar = tar::Archive::new(file);
+    let mut entry = tar.entries()?.next().context("No tar entry at offset")??;
+    let sparse_map = match entry.pax_extensions()? {
+        Some(extensions) => sparse::parse_pax(extensions)?,
+        None => None,
+    };
+
+    let mut out = File::create(dest)?;
+    let (hasher, size) = match sparse_map {
+        Some((size, extents)) => (sparse::write_sparse(&mut out, &mut entry, &extents, size)?, size),
+        None => {
+            let mut hasher = crate::object::blob_hasher(entry.header().size()?);
+            let mut buffer = vec![0u8; 65536];
+            let mut size = 0;
+            loop {
+                let n = entry.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                out.write_all(&buffer[..n])?;
+                hasher.update(&buffer[..n]);
+                size += n as u64;
+            }
+            (hasher, size)
+        }
+    };
+    Ok((*hasher.finalize().as_bytes(), size))
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::windows::fs::symlink_file(target, path)?;
+    Ok(())
+}
+
+/// Recreates a FIFO or device node. Sockets cannot be restored meaningfully and are skipped.
+/// Returns false if nothing was created.
+#[cfg(unix)]
+fn create_special(path: &Path, entry: &TreeEntry) -> Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let file_type = entry.mode & models::S_IFMT;
+    if file_type == models::S_IFSOCK {
+        debug!("Not restoring socket {:?}", path);
+        return Ok(false);
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Permission bits are applied afterwards by apply_metadata
+    let ret = unsafe {
+        match file_type {
+            models::S_IFIFO => libc::mkfifo(c_path.as_ptr(), 0o600),
+            _ => libc::mknod(c_path.as_ptr(), (file_type | 0o600) as libc::mode_t, entry.rdev as libc::dev_t),
+        }
+    };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        // Device nodes need root; a missing device should not abort the restore
+        warn!("Failed to create special file {:?}: {}", path, err);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn create_special(path: &Path, _entry: &TreeEntry) -> Result<bool> {
+    debug!("Special files are not supported on this platform, skipping {:?}", path);
+    Ok(false)
+}
+
+/// Applies ownership, permissions and timestamps recorded in the tree entry.
+/// Ownership is best effort: only root may give files away.
+#[cfg(unix)]
+fn apply_metadata(path: &Path, entry: &TreeEntry) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // chown first: it clears setuid/setgid bits that chmod restores afterwards
+    if let Err(e) = std::os::unix::fs::lchown(path, Some(entry.uid), Some(entry.gid)) {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            debug!("Cannot restore owner {}:{} of {:?}: {}", entry.uid, entry.gid, path, e);
+        } else {
+            warn!("Failed to restore owner of {:?}: {}", path, e);
+        }
+    }
+
+    set_mtime(path, entry)?;
+    // Symlink permissions are meaningless on Linux and chmod would follow the link
+    if !entry.is_symlink() {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode & 0o7777))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_metadata(path: &Path, entry: &TreeEntry) -> Result<()> {
+    if entry.is_symlink() {
+        return Ok(());
+    }
+    set_mtime(path, entry)?;
+    let mut perms = std::fs::metadata(path)?.permissions();
+    perms.set_readonly(entry.mode & 0o222 == 0);
+    std::fs::set_permissions(path, perms)?;
+    Ok(())
+}
+
+/// Sets mtime without opening the file (FIFOs would block) and without following symlinks
+#[cfg(unix)]
+fn set_mtime(path: &Path, entry: &TreeEntry) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: entry.mtime as libc::time_t, tv_nsec: entry.mtime_nsec as _ },
+    ];
+    let ret = unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| format!("Failed to set mtime of {:?}", path));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mtime(path: &Path, entry: &TreeEntry) -> Result<()> {
+    let mtime = models::system_time_from_parts(entry.mtime, entry.mtime_nsec);
+    let file = File::options().write(true).open(path)?;
+    file.set_times(std::fs::FileTimes::new().set_modified(mtime))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{BackupPlan, Pipeline};
+    use crate::tape::TapeWriter;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Backs up `root` into the tar file `archive` the way `rumba backup` does,
+    /// in blocks larger than most test files so entries start inside blocks
+    fn backup_to_tar(db: &BackupDb, root: &Path, archive: &Path) -> Result<BackupPlan> {
+        let mut plan = Pipeline::new(db.clone(), root.to_path_buf()).run()?;
+
+        let mut writer = TapeWriter::new_tar_file(archive.to_str().unwrap(), 1)?.with_block_size(16 * 1024);
+        let blobs = plan.new_files.iter()?;
+        writer.write_blobs(blobs, |blob, location| plan.new_files.record_location(blob.hash, location))?;
+        writer.finish()?;
+
+        let txn = db.begin_write()?;
+        for location in plan.new_files.locations()? {
+            let (hash, location) = location?;
+            db.insert_blob(&txn, &hash, &location)?;
+        }
+        txn.commit()?;
+        Ok(plan)
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_restore_preserves_metadata() -> Result<()> {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let source = TempDir::new()?;
+        let work = TempDir::new()?;
+        let root = source.path();
+
+        fs::write(root.join("script.sh"), "#!/bin/sh\n")?;
+        fs::set_permissions(root.join("script.sh"), fs::Permissions::from_mode(0o750))?;
+        fs::create_dir(root.join("private"))?;
+        fs::write(root.join("private").join("notes.txt"), "secret")?;
+        fs::set_permissions(root.join("private"), fs::Permissions::from_mode(0o700))?;
+        let mtime = models::system_time_from_parts(1_600_000_000, 123_456_789);
+        File::options().write(true).open(root.join("script.sh"))?
+            .set_times(fs::FileTimes::new().set_modified(mtime))?;
+
+        let db = BackupDb::new(work.path().join("test.redb"))?;
+        let archive = work.path().join("backup.tar");
+        let plan = backup_to_tar(&db, root, &archive)?;
+
+        let target = work.path().join("restored");
+        let stats = Restorer::new(&db, vec![archive]).restore_tree(&plan.root_tree, &target)?;
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.dirs, 1);
+
+        let script = fs::metadata(target.join("script.sh"))?;
+        assert_eq!(script.mode() & 0o7777, 0o750);
+        assert_eq!(script.mtime(), 1_600_000_000);
+        assert_eq!(script.mtime_nsec(), 123_456_789);
+        assert_eq!(fs::metadata(target.join("private"))?.mode() & 0o7777, 0o700);
+        assert_eq!(fs::read_to_string(target.join("private").join("notes.txt"))?, "secret");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_restore_links_and_fifos() -> Result<()> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let source = TempDir::new()?;
+        let work = TempDir::new()?;
+        let root = source.path();
+
+        fs::create_dir(root.join("a"))?;
+        fs::create_dir(root.join("b"))?;
+        fs::write(root.join("a").join("data.bin"), "payload")?;
+        fs::hard_link(root.join("a").join("data.bin"), root.join("b").join("data.bin"))?;
+        std::os::unix::fs::symlink("../a/data.bin", root.join("b").join("alias"))?;
+        let fifo = std::ffi::CString::new(root.join("pipe").to_str().unwrap())?;
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o640) }, 0);
+
+        let db = BackupDb::new(work.path().join("test.redb"))?;
+        let archive = work.path().join("backup.tar");
+        let plan = backup_to_tar(&db, root, &archive)?;
+        assert_eq!(plan.new_files.len(), 1);
+
+        let target = work.path().join("restored");
+        let stats = Restorer::new(&db, vec![archive]).restore_tree(&plan.root_tree, &target)?;
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.hardlinks, 1);
+        assert_eq!(stats.symlinks, 1);
+        assert_eq!(stats.special, 1);
+
+        let a = fs::metadata(target.join("a").join("data.bin"))?;
+        let b = fs::metadata(target.join("b").join("data.bin"))?;
+        assert_eq!(a.ino(), b.ino());
+        assert_eq!(fs::read_link(target.join("b").join("alias"))?, Path::new("../a/data.bin"));
+        assert!(fs::symlink_metadata(target.join("pipe"))?.file_type().is_fifo());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_restore_sparse_file() -> Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::MetadataExt;
+
+        let source = TempDir::new()?;
+        let work = TempDir::new()?;
+        let root = source.path();
+        let size = 16 * 1024 * 1024;
+
+        let mut image = File::create(root.join("vm.img"))?;
+        image.write_all(b"boot sector")?;
+        image.seek(SeekFrom::Start(size / 2))?;
+        image.write_all(b"superblock")?;
+        image.set_len(size)?;
+        drop(image);
+        if sparse::data_extents(&File::open(root.join("vm.img"))?, size)?.is_none() {
+            // File system without hole reporting
+            return Ok(());
+        }
+
+        let db = BackupDb::new(work.path().join("test.redb"))?;
+        let archive = work.path().join("backup.tar");
+        let mut plan = backup_to_tar(&db, root, &archive)?;
+        assert_eq!(plan.new_files.iter()?.next().unwrap()?.hash, object::hash_blob(&fs::read(root.join("vm.img"))?));
+
+        // Only the data extents went to the archive
+        assert!(fs::metadata(&archive)?.len() < size / 4);
+
+        let target = work.path().join("restored");
+        let stats = Restorer::new(&db, vec![archive]).restore_tree(&plan.root_tree, &target)?;
+        assert_eq!(stats.bytes, size);
+
+        let restored = fs::metadata(target.join("vm.img"))?;
+        assert_eq!(restored.len(), size);
+        assert!(restored.blocks() * 512 < size / 4, "holes were not preserved");
+        assert_eq!(fs::read(target.join("vm.img"))?, fs::read(root.join("vm.img"))?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_restore_non_utf8_names() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let source = TempDir::new()?;
+        let work = TempDir::new()?;
+        let root = source.path();
+
+        // Latin-1 "café.txt" and "caf\xff.txt" both decode lossily to "caf\u{FFFD}.txt";
+        // Shift-JIS "表.txt" contains a backslash byte
+        let latin1 = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        let sjis = std::ffi::OsStr::from_bytes(b"\x95\\.txt");
+        let other = std::ffi::OsStr::from_bytes(b"caf\xff.txt");
+        fs::write(root.join(latin1), "latin-1")?;
+        fs::write(root.join(sjis), "shift-jis")?;
+        fs::write(root.join(other), "other")?;
+
+        let db = BackupDb::new(work.path().join("test.redb"))?;
+        let archive = work.path().join("backup.tar");
+        let plan = backup_to_tar(&db, root, &archive)?;
+
+        let target = work.path().join("restored");
+        let stats = Restorer::new(&db, vec![archive]).restore_tree(&plan.root_tree, &target)?;
+        assert_eq!(stats.files, 3);
+
+        assert_eq!(fs::read_to_string(target.join(latin1))?, "latin-1");
+        assert_eq!(fs::read_to_string(target.join(sjis))?, "shift-jis");
+        assert_eq!(fs::read_to_string(target.join(other))?, "other");
+
+        Ok(())
+    }
+}
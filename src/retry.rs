@@ -0,0 +1,187 @@
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+use crate::config::BackupConfig;
+use crate::errors::Operation;
+
+/// How opens, stats and reads of the source are retried when they fail with a
+/// transient error, as SMB shares over a WAN link regularly do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Tries of one operation before its error is given up on
+    pub attempts: u32,
+    /// Wait before the first retry, doubled for every further one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Failed tries of all operations on one file after which the file is skipped (0: no limit)
+    pub skip_after: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::from_config(&BackupConfig::default())
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &BackupConfig) -> Self {
+        Self {
+            attempts: config.retry_attempts.max(1),
+            backoff: Duration::from_millis(config.retry_backoff_ms),
+            max_backoff: Duration::from_millis(config.retry_max_backoff_ms),
+            skip_after: config.skip_after_failures,
+        }
+    }
+
+    /// Wait before retry number `retry` (starting at 1)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    /// Retries of the operations on one file
+    pub fn for_file(&self) -> Attempts {
+        Attempts { policy: *self, failures: 0, retry_locked: false }
+    }
+}
+
+/// Errors worth trying again after a pause: interrupted or timed out calls,
+/// dropped connections and I/O errors of network file systems
+pub fn is_transient(error: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    if matches!(
+        error.kind(),
+        Interrupted | WouldBlock | TimedOut | ConnectionReset | ConnectionAborted | NotConnected
+            | BrokenPipe | NetworkDown | NetworkUnreachable | HostUnreachable | StaleNetworkFileHandle
+    ) {
+        return true;
+    }
+    #[cfg(unix)]
+    if error.raw_os_error() == Some(libc::EIO) {
+        return true;
+    }
+    false
+}
+
+/// Errors of files that another program holds open or locked (a sharing violation over SMB)
+pub fn is_locked(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::ResourceBusy | io::ErrorKind::ExecutableFileBusy)
+}
+
+/// The tries of the operations on one file, counted against the policy
+#[derive(Debug)]
+pub struct Attempts {
+    policy: RetryPolicy,
+    failures: u32,
+    retry_locked: bool,
+}
+
+impl Attempts {
+    /// Also retries operations that fail because the file is locked
+    pub fn retrying_locked(mut self) -> Self {
+        self.retry_locked = true;
+        self
+    }
+
+    /// Failed tries so far
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Runs `op`, retrying transient errors with backoff until the operation or the
+    /// file runs out of attempts; returns the last error then
+    pub fn run<T>(&mut self, operation: Operation, path: &Path, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut tries = 0;
+        loop {
+            let error = match op() {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if !(is_transient(&error) || self.retry_locked && is_locked(&error)) {
+                return Err(error);
+            }
+            tries += 1;
+            self.failures += 1;
+            if tries >= self.policy.attempts || self.policy.skip_after > 0 && self.failures >= self.policy.skip_after {
+                return Err(error);
+            }
+            let backoff = self.policy.backoff(tries);
+            info!("Cannot {} {:?}: {}; retrying in {:?}", operation, path, error, backoff);
+            std::thread::sleep(backoff);
+        }
+    }
+}
+
+/// Retries the reads of `inner` within the attempts of its file. A failed read
+/// consumes nothing, so it is simply issued again.
+pub struct RetryReader<'a, R: Read> {
+    inner: R,
+    attempts: &'a mut Attempts,
+    path: &'a Path,
+}
+
+impl<'a, R: Read> RetryReader<'a, R> {
+    pub fn new(inner: R, attempts: &'a mut Attempts, path: &'a Path) -> Self {
+        Self { inner, attempts, path }
+    }
+}
+
+impl<R: Read> Read for RetryReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.attempts.run(Operation::Read, self.path, || inner.read(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(attempts: u32, skip_after: u32) -> RetryPolicy {
+        RetryPolicy { attempts, backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(4), skip_after }
+    }
+
+    #[test]
+    fn test_retries_transient_errors_only() {
+        let path = Path::new("/share/report.xlsx");
+        let mut attempts = policy(3, 0).for_file();
+        let mut calls = 0;
+        let result = attempts.run(Operation::Read, path, || {
+            calls += 1;
+            if calls < 3 { Err(io::Error::from(io::ErrorKind::ConnectionReset)) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(attempts.failures(), 2);
+
+        // Permanent errors are not retried, locked files only when asked to
+        let mut calls = 0;
+        let result: io::Result<()> = attempts.run(Operation::Open, path, || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert_eq!((result.unwrap_err().kind(), calls), (io::ErrorKind::PermissionDenied, 1));
+        let mut locked = policy(3, 0).for_file().retrying_locked();
+        let _ = locked.run(Operation::Open, path, || Err::<(), _>(io::Error::from(io::ErrorKind::ResourceBusy)));
+        assert_eq!(locked.failures(), 3);
+
+        // Backoff doubles up to the maximum
+        let backoffs: Vec<u64> = (1..=4).map(|retry| policy(3, 0).backoff(retry).as_millis() as u64).collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 4]);
+    }
+
+    #[test]
+    fn test_file_is_given_up_after_failures() {
+        let path = Path::new("/share/flaky.bin");
+        let mut attempts = policy(3, 4).for_file();
+        let mut calls = 0;
+        let mut flaky = || {
+            calls += 1;
+            Err::<(), _>(io::Error::from(io::ErrorKind::TimedOut))
+        };
+        assert!(attempts.run(Operation::Read, path, &mut flaky).is_err());
+        // The second read gives up after one more failure: four for the file in all
+        assert!(attempts.run(Operation::Read, path, &mut flaky).is_err());
+        assert_eq!((attempts.failures(), calls), (4, 4));
+    }
+}
@@ -1,30 +1,117 @@
 use std::cmp::Ordering;
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
-use jwalk::WalkDir;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{Sender, SyncSender};
+use jwalk::{Parallelism, WalkDir};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use tracing::{debug, warn};
+use crate::errors::{ErrorCollector, Operation};
+use crate::models;
+use crate::normalize::NamePolicy;
+use crate::progress::{NoProgress, ProgressObserver};
 
 #[derive(Debug)]
 pub struct ScannedDir {
     pub path: PathBuf,
     pub entries: Vec<ScannedEntry>,
+    /// Children left out by the exclude patterns
+    pub excluded: u64,
+}
+
+/// File type of a scanned entry, as reported without following symlinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    /// FIFOs, sockets and device nodes: never read, only their metadata is recorded
+    Special,
+}
+
+impl EntryKind {
+    pub fn from_file_type(file_type: &std::fs::FileType) -> Self {
+        if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else {
+            EntryKind::Special
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ScannedEntry {
-    pub name: String,
-    pub is_dir: bool,
+    /// File name as returned by the OS, never converted lossily
+    pub name: OsString,
+    pub kind: EntryKind,
     // We capture minimal metadata needed for sorting and initial processing
     pub path: PathBuf, 
 }
 
+impl ScannedEntry {
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Dir
+    }
+}
+
+/// Compiles exclude patterns. `*` also matches `/`, so "*.tmp" excludes
+/// temporary files at any depth.
+pub fn build_excludes(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
 pub struct Scanner {
     root: PathBuf,
+    policy: NamePolicy,
+    excludes: GlobSet,
+    threads: Option<usize>,
+    progress: Arc<dyn ProgressObserver>,
+    errors: ErrorCollector,
 }
 
 impl Scanner {
     pub fn new(root: PathBuf) -> Self {
-        Self { root }
+        Self { root, policy: NamePolicy::default(), excludes: GlobSet::empty(), threads: None, progress: Arc::new(NoProgress), errors: ErrorCollector::new() }
+    }
+
+    /// Records directories and entries that cannot be read in `errors`
+    pub fn with_errors(mut self, errors: ErrorCollector) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Reports every listed directory, and the end of the scan, to `progress`
+    pub fn with_progress(mut self, progress: Arc<dyn ProgressObserver>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Read directories on a dedicated pool of `threads` threads instead of
+    /// rayon's global pool (one thread walks serially)
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Leave out entries whose path relative to the root, or whose name, matches.
+    /// Excluded directories are not descended into.
+    pub fn with_excludes(mut self, excludes: GlobSet) -> Self {
+        self.excludes = excludes;
+        self
+    }
+
+    /// Sort children by their normalized names instead of raw bytes
+    pub fn with_name_policy(mut self, policy: NamePolicy) -> Self {
+        self.policy = policy;
+        self
     }
 
     /// Scans the directory tree and sends sorted directory listings through the channel.
@@ -48,16 +135,57 @@ impl Scanner {
         Ok(())
     }
 
-    /// Alternative scan using process_read_dir to capture children
-    pub fn scan_parallel(&self, tx: Sender<ScannedDir>) -> anyhow::Result<()> {
+    /// Alternative scan using process_read_dir to capture children.
+    /// The channel is bounded, so the walk pauses while the consumer is behind.
+    pub fn scan_parallel(&self, tx: SyncSender<ScannedDir>) -> anyhow::Result<()> {
         let tx = tx.clone();
+        let policy = self.policy;
+        let excludes = self.excludes.clone();
+        let root = self.root.clone();
+        let progress = self.progress.clone();
+        let errors = self.errors.clone();
         
+        let parallelism = match self.threads {
+            Some(1) => Parallelism::Serial,
+            Some(threads) => Parallelism::RayonNewPool(threads),
+            None => Parallelism::RayonDefaultPool,
+        };
+
         WalkDir::new(&self.root)
-            .process_read_dir(move |_depth, path, _state, children| {
-                // 1. Sort children deterministically by name
+            .parallelism(parallelism)
+            .process_read_dir(move |depth, path, _state, children| {
+                // 0. Drop excluded entries, which also stops jwalk from descending into them
+                // (depth is None for the listing that holds the root itself, which is never excluded)
+                let listed = children.len();
+                if depth.is_some() && !excludes.is_empty() {
+                    children.retain(|child| match child {
+                        Ok(child) => {
+                            let full = path.join(child.file_name());
+                            let relative = full.strip_prefix(&root).unwrap_or(&full);
+                            let excluded = excludes.is_match(relative) || excludes.is_match(child.file_name());
+                            if excluded {
+                                debug!("Excluding {:?}", full);
+                            }
+                            !excluded
+                        }
+                        Err(_) => true,
+                    });
+                }
+
+                // Entries that cannot even be listed are left out; say which
+                for child in children.iter() {
+                    if let Err(e) = child {
+                        record_walk_error(&errors, path, e);
+                    }
+                }
+
+                // 1. Sort children deterministically by (normalized) name, raw bytes break ties
                 children.sort_by(|a, b| {
                     match (a, b) {
-                        (Ok(a), Ok(b)) => a.file_name().cmp(b.file_name()),
+                        (Ok(a), Ok(b)) => {
+                            let (a, b) = (models::os_to_bytes(a.file_name()), models::os_to_bytes(b.file_name()));
+                            policy.key(&a).cmp(&policy.key(&b)).then_with(|| a.cmp(&b))
+                        }
                         (Ok(_), Err(_)) => Ordering::Less,
                         (Err(_), Ok(_)) => Ordering::Greater,
                         (Err(_), Err(_)) => Ordering::Equal,
@@ -66,14 +194,18 @@ impl Scanner {
 
                 // 2. Collect sorted entries to send
                 let mut entries = Vec::with_capacity(children.len());
-                for child in children.iter() {
-                    if let Ok(child) = child {
-                        entries.push(ScannedEntry {
-                            name: child.file_name().to_string_lossy().to_string(),
-                            is_dir: child.file_type().is_dir(),
-                            path: child.path(),
-                        });
-                    }
+                for child in children.iter().flatten() {
+                    entries.push(ScannedEntry {
+                        name: child.file_name().to_os_string(),
+                        kind: EntryKind::from_file_type(&child.file_type()),
+                        path: child.path(),
+                    });
+                }
+
+                let files = entries.iter().filter(|e| e.kind == EntryKind::File).count();
+                let dirs = entries.iter().filter(|e| e.is_dir()).count();
+                if depth.is_some() {
+                    progress.dir_scanned(files as u64, dirs as u64);
                 }
 
                 // 3. Send the sorted directory listing
@@ -81,17 +213,37 @@ impl Scanner {
                 if let Err(e) = tx.send(ScannedDir {
                     path: path.to_path_buf(),
                     entries,
+                    excluded: (listed - children.len()) as u64,
                 }) {
                     debug!("Scanner channel closed: {}", e);
+                    // Nobody is listening any more: stop descending
+                    children.clear();
                 }
             })
             .into_iter()
-            .for_each(|_| {}); // Drive the iterator
+            // Drive the iterator; directories that cannot be read only show up here
+            .for_each(|entry| {
+                if let Ok(entry) = entry {
+                    if let Some(e) = &entry.read_children_error {
+                        record_walk_error(&self.errors, &entry.path(), e);
+                    }
+                }
+            });
 
+        self.progress.scan_finished();
         Ok(())
     }
 }
 
+/// Records an error of the walk under the path it names, or else the directory being listed
+fn record_walk_error(errors: &ErrorCollector, dir: &Path, error: &jwalk::Error) {
+    let path = error.path().unwrap_or(dir);
+    match error.io_error() {
+        Some(io_error) => errors.record(path, Operation::List, io_error),
+        None => errors.record(path, Operation::List, &std::io::Error::other(error.to_string())),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -112,7 +264,7 @@ mod tests {
         fs::write(root.join("b_dir").join("sub_b.txt"), "content")?;
 
         let scanner = Scanner::new(root.to_path_buf());
-        let (tx, rx) = mpsc::channel();
+        let (tx, rx) = mpsc::sync_channel(64);
 
         scanner.scan_parallel(tx)?;
 
@@ -137,4 +289,84 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_entry_kinds() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::create_dir(root.join("dir"))?;
+        fs::write(root.join("file.txt"), "content")?;
+        std::os::unix::fs::symlink("dir", root.join("link_to_dir"))?;
+        std::os::unix::net::UnixListener::bind(root.join("socket"))?;
+
+        let scanner = Scanner::new(root.to_path_buf());
+        let (tx, rx) = mpsc::sync_channel(64);
+        scanner.scan_parallel(tx)?;
+
+        let results: Vec<ScannedDir> = rx.into_iter().collect();
+        let root_dir = results.iter().find(|d| d.path == root).expect("Root not found");
+        let kinds: Vec<_> = root_dir.entries.iter().map(|e| (e.name.to_str().unwrap(), e.kind)).collect();
+        assert_eq!(kinds, vec![
+            ("dir", EntryKind::Dir),
+            ("file.txt", EntryKind::File),
+            ("link_to_dir", EntryKind::Symlink),
+            ("socket", EntryKind::Special),
+        ]);
+
+        // The symlinked directory must not be descended into
+        assert!(!results.iter().any(|d| d.path == root.join("link_to_dir")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_excludes() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::create_dir_all(root.join("project").join("node_modules").join("pkg"))?;
+        fs::write(root.join("project").join("node_modules").join("pkg").join("index.js"), "x")?;
+        fs::write(root.join("project").join("main.rs"), "x")?;
+        fs::write(root.join("project").join("~$report.docx"), "x")?;
+        fs::write(root.join("notes.tmp"), "x")?;
+
+        let excludes = build_excludes(&["node_modules".to_string(), "~$*".to_string(), "*.tmp".to_string()])?;
+        let scanner = Scanner::new(root.to_path_buf()).with_excludes(excludes);
+        let (tx, rx) = mpsc::sync_channel(64);
+        scanner.scan_parallel(tx)?;
+
+        let results: Vec<ScannedDir> = rx.into_iter().filter(|d| d.path.starts_with(root)).collect();
+        let names: Vec<_> = results.iter()
+            .flat_map(|d| d.entries.iter().map(|e| e.name.to_string_lossy().to_string()))
+            .collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names.contains(&"project".to_string()));
+        assert!(names.contains(&"main.rs".to_string()));
+        // Excluded directories are not descended into
+        assert!(!results.iter().any(|d| d.path.ends_with("node_modules")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreadable_root_is_recorded() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().join("unmounted");
+
+        let errors = ErrorCollector::new();
+        let scanner = Scanner::new(root.clone()).with_errors(errors.clone());
+        let (tx, rx) = mpsc::sync_channel(64);
+        scanner.scan_parallel(tx)?;
+        assert!(rx.into_iter().all(|d| d.entries.is_empty()));
+
+        let recorded = errors.take();
+        assert_eq!(recorded.len(), 1, "{:?}", recorded);
+        assert_eq!(recorded[0].path, root);
+        assert_eq!(recorded[0].operation, Operation::List);
+        assert_eq!(recorded[0].kind, std::io::ErrorKind::NotFound);
+
+        Ok(())
+    }
 }
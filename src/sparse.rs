@@ -0,0 +1,361 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// A region of a file that contains data; everything between extents is a hole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+static ZEROS: [u8; 65536] = [0u8; 65536];
+
+/// Returns the data extents of `file` using `SEEK_DATA`/`SEEK_HOLE`.
+/// Returns None if the file has no holes or the file system cannot report them,
+/// in which case the file is handled as a plain byte stream.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+pub fn data_extents(file: &File, size: u64) -> io::Result<Option<Vec<Extent>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut pos: u64 = 0;
+
+    while pos < size {
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // No more data after `pos`: the rest of the file is a hole
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) | Some(libc::ENOTSUP) => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (data, hole) = (data as u64, (hole as u64).min(size));
+        if hole <= data {
+            break;
+        }
+        extents.push(Extent { offset: data, length: hole - data });
+        pos = hole;
+    }
+
+    // lseek moved the file position; callers expect it at the start
+    let mut file_ref = file;
+    file_ref.seek(SeekFrom::Start(0))?;
+
+    let dense = extents.len() == 1 && extents[0].offset == 0 && extents[0].length == size;
+    if dense || (extents.is_empty() && size == 0) {
+        return Ok(None);
+    }
+    Ok(Some(extents))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")))]
+pub fn data_extents(_file: &File, _size: u64) -> io::Result<Option<Vec<Extent>>> {
+    Ok(None)
+}
+
+/// Total number of data bytes described by the extents
+pub fn data_size(extents: &[Extent]) -> u64 {
+    extents.iter().map(|e| e.length).sum()
+}
+
+/// Feeds `len` zero bytes to the hasher without touching the file
+pub fn hash_zeros(hasher: &mut blake3::Hasher, mut len: u64) {
+    while len > 0 {
+        let n = len.min(ZEROS.len() as u64) as usize;
+        hasher.update(&ZEROS[..n]);
+        len -= n as u64;
+    }
+}
+
+/// Hashes the logical content of a sparse file: holes are hashed as zeros but never read,
+/// so the result equals the hash of the fully materialized file.
+pub fn hash_sparse(file: &mut File, extents: &[Extent], size: u64) -> io::Result<blake3::Hasher> {
+    let mut hasher = crate::object::blob_hasher(size);
+    let mut buffer = vec![0u8; 65536];
+    let mut pos = 0;
+    for extent in extents {
+        hash_zeros(&mut hasher, extent.offset - pos);
+        file.seek(SeekFrom::Start(extent.offset))?;
+        let mut remaining = extent.length;
+        while remaining > 0 {
+            let want = remaining.min(buffer.len() as u64) as usize;
+            let n = file.read(&mut buffer[..want])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sparse file shrank while hashing"));
+            }
+            hasher.update(&buffer[..n]);
+            remaining -= n as u64;
+        }
+        pos = extent.offset + extent.length;
+    }
+    hash_zeros(&mut hasher, size.saturating_sub(pos));
+    Ok(hasher)
+}
+
+/// Reads only the data extents of a sparse file, back to back
+pub struct ExtentReader<'a> {
+    file: &'a mut File,
+    extents: &'a [Extent],
+    index: usize,
+    /// Bytes already read from the current extent
+    done: u64,
+}
+
+impl<'a> ExtentReader<'a> {
+    pub fn new(file: &'a mut File, extents: &'a [Extent]) -> Self {
+        Self { file, extents, index: 0, done: 0 }
+    }
+}
+
+impl Read for ExtentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(extent) = self.extents.get(self.index) {
+            if self.done == extent.length {
+                self.index += 1;
+                self.done = 0;
+                continue;
+            }
+            if self.done == 0 {
+                self.file.seek(SeekFrom::Start(extent.offset))?;
+            }
+            let want = (extent.length - self.done).min(buf.len() as u64) as usize;
+            let n = self.file.read(&mut buf[..want])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sparse file shrank while reading"));
+            }
+            self.done += n as u64;
+            return Ok(n);
+        }
+        Ok(0)
+    }
+}
+
+/// Hashes the data extents read through it, back to back as `ExtentReader` yields them,
+/// with the holes between them hashed as zeros: the result is the hash of the logical content.
+/// A dense file is a single extent covering all of it.
+pub struct HashingReader<'a, R: Read> {
+    inner: R,
+    extents: &'a [Extent],
+    size: u64,
+    hasher: blake3::Hasher,
+    index: usize,
+    /// Bytes already hashed of the current extent
+    done: u64,
+    /// Logical offset up to which the content is hashed
+    pos: u64,
+}
+
+impl<'a, R: Read> HashingReader<'a, R> {
+    pub fn new(inner: R, extents: &'a [Extent], size: u64) -> Self {
+        let hasher = crate::object::blob_hasher(size);
+        Self { inner, extents, size, hasher, index: 0, done: 0, pos: 0 }
+    }
+
+    /// Hashes the trailing hole and returns the hasher
+    pub fn finish(mut self) -> blake3::Hasher {
+        hash_zeros(&mut self.hasher, self.size.saturating_sub(self.pos));
+        self.hasher
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let Some(extent) = self.extents.get(self.index) else {
+                // More data than the map describes; it is hashed as is and cannot match
+                self.hasher.update(data);
+                self.pos += data.len() as u64;
+                return;
+            };
+            if self.done == 0 {
+                hash_zeros(&mut self.hasher, extent.offset.saturating_sub(self.pos));
+                self.pos = self.pos.max(extent.offset);
+            }
+            let n = (extent.length - self.done).min(data.len() as u64) as usize;
+            self.hasher.update(&data[..n]);
+            self.done += n as u64;
+            self.pos += n as u64;
+            data = &data[n..];
+            if self.done == extent.length {
+                self.index += 1;
+                self.done = 0;
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Encodes the extent map as PAX records in GNU tar's sparse format 0.1,
+/// so the archive stays readable by `tar` as well as by restore.
+pub fn pax_records(extents: &[Extent], size: u64) -> Vec<u8> {
+    let map = extents.iter()
+        .map(|e| format!("{},{}", e.offset, e.length))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut records = Vec::new();
+    for (key, value) in [
+        ("GNU.sparse.size", size.to_string()),
+        ("GNU.sparse.numblocks", extents.len().to_string()),
+        ("GNU.sparse.map", map),
+    ] {
+        records.extend_from_slice(&pax_record(key, &value));
+    }
+    records
+}
+
+/// A PAX record is "<len> <key>=<value>\n" where <len> counts the whole record including itself
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let body = key.len() + value.len() + 3; // ' ', '=', '\n'
+    let mut len = body + 1;
+    while len != body + len.to_string().len() {
+        len = body + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value).into_bytes()
+}
+
+/// Parses the sparse PAX records of a tar entry back into (logical size, extents)
+pub fn parse_pax(extensions: tar::PaxExtensions<'_>) -> io::Result<Option<(u64, Vec<Extent>)>> {
+    let mut size = None;
+    let mut map = None;
+    for ext in extensions {
+        let ext = ext?;
+        match ext.key() {
+            Ok("GNU.sparse.size") => size = ext.value().ok().and_then(|v| v.parse::<u64>().ok()),
+            Ok("GNU.sparse.map") => map = ext.value().ok().map(|v| v.to_string()),
+            _ => {}
+        }
+    }
+    let (Some(size), Some(map)) = (size, map) else {
+        return Ok(None);
+    };
+    let numbers = map.split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if numbers.len() % 2 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "odd number of values in GNU.sparse.map"));
+    }
+    let extents: Vec<Extent> = numbers.chunks(2).map(|c| Extent { offset: c[0], length: c[1] }).collect();
+    // Extents must be sorted, disjoint and within the file, or writing them back would wrap around
+    let mut pos = 0;
+    for extent in &extents {
+        let end = extent.offset.checked_add(extent.length).filter(|&end| extent.offset >= pos && end <= size);
+        pos = end.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "GNU.sparse.map is not sorted or exceeds the file size"))?;
+    }
+    Ok(Some((size, extents)))
+}
+
+/// Writes the data extents read from `data` into `file`, leaving holes unwritten,
+/// and returns the hash of the logical content.
+pub fn write_sparse<R: Read>(file: &mut File, data: &mut R, extents: &[Extent], size: u64) -> io::Result<blake3::Hasher> {
+    let mut hasher = crate::object::blob_hasher(size);
+    let mut buffer = vec![0u8; 65536];
+    let mut pos = 0;
+    for extent in extents {
+        hash_zeros(&mut hasher, extent.offset - pos);
+        file.seek(SeekFrom::Start(extent.offset))?;
+        let mut remaining = extent.length;
+        while remaining > 0 {
+            let want = remaining.min(buffer.len() as u64) as usize;
+            let n = data.read(&mut buffer[..want])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sparse data truncated"));
+            }
+            file.write_all(&buffer[..n])?;
+            hasher.update(&buffer[..n]);
+            remaining -= n as u64;
+        }
+        pos = extent.offset + extent.length;
+    }
+    hash_zeros(&mut hasher, size.saturating_sub(pos));
+    // Trailing hole: extend the file without writing
+    file.set_len(size)?;
+    Ok(hasher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_pax_record_length() {
+        let record = pax_record("GNU.sparse.size", "1048576");
+        let text = String::from_utf8(record.clone()).unwrap();
+        let (len, _) = text.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), record.len());
+    }
+
+    #[test]
+    fn test_parse_pax_validates_map() {
+        let extents = [Extent { offset: 0, length: 10 }, Extent { offset: 100, length: 20 }];
+        let records = pax_records(&extents, 200);
+        let parsed = parse_pax(tar::PaxExtensions::new(&records)).unwrap();
+        assert_eq!(parsed, Some((200, extents.to_vec())));
+
+        let map = |map: &str, size: u64| {
+            let mut records = pax_record("GNU.sparse.size", &size.to_string());
+            records.extend(pax_record("GNU.sparse.map", map));
+            parse_pax(tar::PaxExtensions::new(&records)).map_err(|e| e.kind())
+        };
+        assert_eq!(map("0,10,5,10", 100), Err(io::ErrorKind::InvalidData));
+        assert_eq!(map("50,10,0,10", 100), Err(io::ErrorKind::InvalidData));
+        assert_eq!(map("90,20", 100), Err(io::ErrorKind::InvalidData));
+        assert_eq!(map("18446744073709551615,1", u64::MAX), Err(io::ErrorKind::InvalidData));
+        assert_eq!(map("0,10,10,10", 20), Ok(Some((20, vec![Extent { offset: 0, length: 10 }, Extent { offset: 10, length: 10 }]))));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sparse_hash_matches_dense_hash() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("disk.img");
+        let size = 8 * 1024 * 1024;
+
+        let mut file = File::create(&path)?;
+        file.seek(SeekFrom::Start(4 * 1024 * 1024))?;
+        file.write_all(b"data in the middle")?;
+        file.set_len(size)?;
+        drop(file);
+
+        let mut file = File::open(&path)?;
+        let Some(extents) = data_extents(&file, size)? else {
+            // File system without hole reporting
+            return Ok(());
+        };
+        assert!(data_size(&extents) < size);
+
+        let sparse = hash_sparse(&mut file, &extents, size)?;
+        let dense = crate::object::hash_blob(&std::fs::read(&path)?);
+        assert_eq!(*sparse.finalize().as_bytes(), dense);
+
+        // Round trip through the extent stream
+        let mut stream = Vec::new();
+        ExtentReader::new(&mut file, &extents).read_to_end(&mut stream)?;
+        assert_eq!(stream.len() as u64, data_size(&extents));
+        let mut hashing = HashingReader::new(stream.as_slice(), &extents, size);
+        io::copy(&mut hashing, &mut io::sink())?;
+        assert_eq!(*hashing.finish().finalize().as_bytes(), dense);
+
+        let restored_path = temp_dir.path().join("restored.img");
+        let mut restored = File::create(&restored_path)?;
+        let hasher = write_sparse(&mut restored, &mut stream.as_slice(), &extents, size)?;
+        assert_eq!(*hasher.finalize().as_bytes(), dense);
+        assert_eq!(std::fs::read(&restored_path)?, std::fs::read(&path)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::{HashSet, VecDeque};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::Result;
+use redb::{Database, Durability, TableDefinition};
+use tempfile::TempDir;
+use crate::models::{self, BlobLocation, Hash, IndexEntry};
+
+/// Planned blobs in scan order: sequence number -> hash, size and path bytes
+const QUEUE_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("queue");
+/// Hashes already in the queue, for deduplication within the run
+const PLANNED_TABLE: TableDefinition<&[u8; 32], ()> = TableDefinition::new("planned");
+/// Where the writer put each blob: hash -> (tape id, offset, block size)
+const LOCATIONS_TABLE: TableDefinition<&[u8; 32], (u64, u64, u64)> = TableDefinition::new("locations");
+/// Index entries of the files hashed in this run: index key -> encoded entry
+const INDEX_TABLE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("index");
+
+/// Records buffered in memory before they are written out
+const BATCH_SIZE: usize = 4096;
+
+/// A blob that has to be written to tape
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedBlob {
+    pub path: PathBuf,
+    pub hash: Hash,
+    pub size: u64,
+}
+
+impl PlannedBlob {
+    fn encode(&self) -> Vec<u8> {
+        let path = models::os_to_bytes(self.path.as_os_str());
+        let mut bytes = Vec::with_capacity(40 + path.len());
+        bytes.extend_from_slice(&self.hash);
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&path);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        let (hash, rest) = bytes.split_at(32);
+        let (size, path) = rest.split_at(8);
+        Self {
+            path: PathBuf::from(models::bytes_to_os(path)),
+            hash: hash.try_into().unwrap(),
+            size: u64::from_be_bytes(size.try_into().unwrap()),
+        }
+    }
+}
+
+/// Disk-backed state of a backup run that grows with the number of files:
+/// the queue of planned blobs, the set of hashes already planned, the index
+/// entries of hashed files and the tape locations reported by the writer. Only
+/// the current batch is held in memory, so a first backup of a share with 100M
+/// files does not need 100M entries in RAM.
+/// The store lives in a temporary directory and disappears when dropped.
+pub struct SpillStore {
+    db: Arc<Database>,
+    _dir: TempDir,
+    len: u64,
+    queue: Vec<PlannedBlob>,
+    queued: HashSet<Hash>,
+    locations: Vec<(Hash, BlobLocation)>,
+    index: Vec<(Vec<u8>, IndexEntry)>,
+    index_len: u64,
+}
+
+fn encode_index(entry: &IndexEntry) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(72);
+    bytes.extend_from_slice(&entry.mtime.to_be_bytes());
+    bytes.extend_from_slice(&entry.mtime_nsec.to_be_bytes());
+    bytes.extend_from_slice(&entry.ctime.to_be_bytes());
+    bytes.extend_from_slice(&entry.ctime_nsec.to_be_bytes());
+    bytes.extend_from_slice(&entry.ino.to_be_bytes());
+    bytes.extend_from_slice(&entry.size.to_be_bytes());
+    bytes.extend_from_slice(&entry.hash);
+    bytes
+}
+
+fn decode_index(bytes: &[u8]) -> IndexEntry {
+    let field = |start: usize, len: usize| &bytes[start..start + len];
+    IndexEntry {
+        mtime: i64::from_be_bytes(field(0, 8).try_into().unwrap()),
+        mtime_nsec: u32::from_be_bytes(field(8, 4).try_into().unwrap()),
+        ctime: i64::from_be_bytes(field(12, 8).try_into().unwrap()),
+        ctime_nsec: u32::from_be_bytes(field(20, 4).try_into().unwrap()),
+        ino: u64::from_be_bytes(field(24, 8).try_into().unwrap()),
+        size: u64::from_be_bytes(field(32, 8).try_into().unwrap()),
+        hash: field(40, 32).try_into().unwrap(),
+    }
+}
+
+impl SpillStore {
+    /// Creates the store in a new temporary directory under `parent`
+    /// (the system temporary directory if None)
+    pub fn new(parent: Option<&Path>) -> Result<Self> {
+        let dir = match parent {
+            Some(parent) => tempfile::Builder::new().prefix("rumba-spill").tempdir_in(parent)?,
+            None => tempfile::Builder::new().prefix("rumba-spill").tempdir()?,
+        };
+        let db = Database::create(dir.path().join("spill.redb"))?;
+        let txn = db.begin_write()?;
+        {
+            txn.open_table(QUEUE_TABLE)?;
+            txn.open_table(PLANNED_TABLE)?;
+            txn.open_table(LOCATIONS_TABLE)?;
+            txn.open_table(INDEX_TABLE)?;
+        }
+        txn.commit()?;
+
+        Ok(Self {
+            db: Arc::new(db),
+            _dir: dir,
+            len: 0,
+            queue: Vec::new(),
+            queued: HashSet::new(),
+            locations: Vec::new(),
+            index: Vec::new(),
+            index_len: 0,
+        })
+    }
+
+    /// Number of planned blobs
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queues `blob` unless a blob with the same hash is already planned.
+    /// Returns true if it was queued.
+    pub fn push(&mut self, blob: PlannedBlob) -> Result<bool> {
+        if self.queued.contains(&blob.hash) || self.is_planned(&blob.hash)? {
+            return Ok(false);
+        }
+        self.queued.insert(blob.hash);
+        self.queue.push(blob);
+        self.len += 1;
+        if self.queue.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(true)
+    }
+
+    fn is_planned(&self, hash: &Hash) -> Result<bool> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(PLANNED_TABLE)?;
+        let planned = table.get(hash)?.is_some();
+        Ok(planned)
+    }
+
+    /// Records the index entry of a file hashed in this run
+    pub fn record_index(&mut self, key: Vec<u8>, entry: IndexEntry) -> Result<()> {
+        self.index.push((key, entry));
+        self.index_len += 1;
+        if self.index.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Number of index entries recorded
+    pub fn index_len(&self) -> u64 {
+        self.index_len
+    }
+
+    /// Records where the writer put a blob
+    pub fn record_location(&mut self, hash: Hash, location: BlobLocation) -> Result<()> {
+        self.locations.push((hash, location));
+        if self.locations.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered records to disk
+    pub fn flush(&mut self) -> Result<()> {
+        if self.queue.is_empty() && self.locations.is_empty() && self.index.is_empty() {
+            return Ok(());
+        }
+        let mut txn = self.db.begin_write()?;
+        // Scratch data: losing it on a crash is fine, the run starts over anyway
+        txn.set_durability(Durability::None);
+        {
+            let mut queue = txn.open_table(QUEUE_TABLE)?;
+            let mut planned = txn.open_table(PLANNED_TABLE)?;
+            let first = self.len - self.queue.len() as u64;
+            for (i, blob) in self.queue.iter().enumerate() {
+                queue.insert(first + i as u64, blob.encode().as_slice())?;
+                planned.insert(&blob.hash, ())?;
+            }
+            let mut locations = txn.open_table(LOCATIONS_TABLE)?;
+            for (hash, location) in &self.locations {
+                locations.insert(hash, (location.tape_id, location.offset, location.block_size))?;
+            }
+            let mut index = txn.open_table(INDEX_TABLE)?;
+            for (key, entry) in &self.index {
+                index.insert(key.as_slice(), encode_index(entry).as_slice())?;
+            }
+        }
+        txn.commit()?;
+        self.queue.clear();
+        self.queued.clear();
+        self.locations.clear();
+        self.index.clear();
+        Ok(())
+    }
+
+    /// Iterates over the planned blobs in the order they were queued
+    pub fn iter(&mut self) -> Result<BlobIter> {
+        self.flush()?;
+        Ok(BlobIter { db: self.db.clone(), next: 0, end: self.len, buffer: VecDeque::new() })
+    }
+
+    /// Iterates over the recorded blob locations (in hash order)
+    pub fn locations(&mut self) -> Result<LocationIter> {
+        self.flush()?;
+        Ok(LocationIter { db: self.db.clone(), last: None, done: false, buffer: VecDeque::new() })
+    }
+
+    /// Iterates over the recorded index entries (in key order)
+    pub fn index_entries(&mut self) -> Result<IndexIter> {
+        self.flush()?;
+        Ok(IndexIter { db: self.db.clone(), last: None, done: false, buffer: VecDeque::new() })
+    }
+}
+
+/// Reads the queue back in batches; does not borrow the store, so locations
+/// can be recorded while iterating
+pub struct BlobIter {
+    db: Arc<Database>,
+    next: u64,
+    end: u64,
+    buffer: VecDeque<PlannedBlob>,
+}
+
+impl BlobIter {
+    fn fill(&mut self) -> Result<()> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(QUEUE_TABLE)?;
+        let last = (self.next + BATCH_SIZE as u64).min(self.end);
+        for result in table.range(self.next..last)? {
+            let (_, value) = result?;
+            self.buffer.push_back(PlannedBlob::decode(value.value()));
+        }
+        self.next = last;
+        Ok(())
+    }
+}
+
+impl Iterator for BlobIter {
+    type Item = Result<PlannedBlob>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && self.next < self.end {
+            if let Err(e) = self.fill() {
+                self.next = self.end;
+                return Some(Err(e));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+pub struct LocationIter {
+    db: Arc<Database>,
+    last: Option<Hash>,
+    done: bool,
+    buffer: VecDeque<(Hash, BlobLocation)>,
+}
+
+impl LocationIter {
+    fn fill(&mut self) -> Result<()> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(LOCATIONS_TABLE)?;
+        let start = match &self.last {
+            Some(hash) => Bound::Excluded(hash),
+            None => Bound::Unbounded,
+        };
+        for result in table.range::<&[u8; 32]>((start, Bound::Unbounded))?.take(BATCH_SIZE) {
+            let (hash, value) = result?;
+            let (tape_id, offset, block_size) = value.value();
+            self.buffer.push_back((*hash.value(), BlobLocation { tape_id, offset, block_size }));
+        }
+        self.done = self.buffer.len() < BATCH_SIZE;
+        self.last = self.buffer.back().map(|(hash, _)| *hash);
+        Ok(())
+    }
+}
+
+impl Iterator for LocationIter {
+    type Item = Result<(Hash, BlobLocation)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            if let Err(e) = self.fill() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+pub struct IndexIter {
+    db: Arc<Database>,
+    last: Option<Vec<u8>>,
+    done: bool,
+    buffer: VecDeque<(Vec<u8>, IndexEntry)>,
+}
+
+impl IndexIter {
+    fn fill(&mut self) -> Result<()> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(INDEX_TABLE)?;
+        let start = match &self.last {
+            Some(key) => Bound::Excluded(key.as_slice()),
+            None => Bound::Unbounded,
+        };
+        for result in table.range::<&[u8]>((start, Bound::Unbounded))?.take(BATCH_SIZE) {
+            let (key, value) = result?;
+            self.buffer.push_back((key.value().to_vec(), decode_index(value.value())));
+        }
+        self.done = self.buffer.len() < BATCH_SIZE;
+        self.last = self.buffer.back().map(|(key, _)| key.clone());
+        Ok(())
+    }
+}
+
+impl Iterator for IndexIter {
+    type Item = Result<(Vec<u8>, IndexEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            if let Err(e) = self.fill() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill_dedups_and_preserves_order() -> Result<()> {
+        let mut store = SpillStore::new(None)?;
+        let count = BATCH_SIZE as u64 * 2 + 10;
+        for i in 0..count {
+            let hash = *blake3::hash(&i.to_be_bytes()).as_bytes();
+            assert!(store.push(PlannedBlob { path: PathBuf::from(format!("/share/{}", i)), hash, size: i })?);
+        }
+        // Same content again, both still in memory and already spilled
+        let first = *blake3::hash(&0u64.to_be_bytes()).as_bytes();
+        let latest = *blake3::hash(&(count - 1).to_be_bytes()).as_bytes();
+        assert!(!store.push(PlannedBlob { path: PathBuf::from("/share/copy"), hash: first, size: 0 })?);
+        assert!(!store.push(PlannedBlob { path: PathBuf::from("/share/copy"), hash: latest, size: 0 })?);
+        assert_eq!(store.len(), count);
+
+        let mut blobs = store.iter()?;
+        for i in 0..count {
+            let blob = blobs.next().unwrap()?;
+            assert_eq!(blob.size, i);
+            assert_eq!(blob.path, PathBuf::from(format!("/share/{}", i)));
+            store.record_location(blob.hash, BlobLocation { tape_id: 1, offset: i, block_size: 512 })?;
+        }
+        assert!(blobs.next().is_none());
+
+        let mut offsets: Vec<u64> = store.locations()?.map(|l| l.map(|(_, l)| l.offset)).collect::<Result<_>>()?;
+        offsets.sort();
+        assert_eq!(offsets, (0..count).collect::<Vec<_>>());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use tempfile::TempDir;
+use crate::config::TargetConfig;
+
+/// Largest segment file the buffer is cut into
+const MAX_SEGMENT_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Size and watermarks of the staging buffer
+#[derive(Debug, Clone)]
+pub struct StagingConfig {
+    /// Directory the segment files are created in
+    pub dir: PathBuf,
+    /// Bytes the buffer may hold; the source waits when it is full
+    pub max_size: u64,
+    /// The drive starts (or restarts) once this many bytes are buffered
+    pub high_watermark: u64,
+    /// The drive pauses when the buffer drains down to this many bytes
+    pub low_watermark: u64,
+}
+
+impl StagingConfig {
+    /// Staging settings of the target, None if no staging directory is configured
+    pub fn from_target(target: &TargetConfig) -> Option<Self> {
+        let dir = target.staging_dir.as_ref()?;
+        let max_size = target.staging_size_mb * 1024 * 1024;
+        Some(Self {
+            dir: PathBuf::from(dir),
+            max_size,
+            high_watermark: max_size / 100 * target.staging_high_watermark as u64,
+            low_watermark: max_size / 100 * target.staging_low_watermark as u64,
+        })
+    }
+}
+
+/// How well the buffer kept the drive streaming
+#[derive(Debug, Clone, Default)]
+pub struct StagingStats {
+    /// Bytes passed through the buffer
+    pub bytes: u64,
+    /// Most bytes buffered at once
+    pub peak: u64,
+    /// Times the drive ran dry and was paused at the low watermark
+    pub drive_stops: u64,
+    /// Time the drive spent paused, waiting for the buffer to refill
+    pub drive_starved: Duration,
+    /// Time the source spent waiting for room in the buffer
+    pub source_waited: Duration,
+}
+
+struct State {
+    /// Sealed segment files waiting to be drained, oldest first
+    segments: VecDeque<(PathBuf, u64)>,
+    /// Bytes in the sealed segments and the one being written
+    buffered: u64,
+    /// The drain thread is feeding the drive
+    draining: bool,
+    /// The writer is blocked until there is room
+    source_waiting: bool,
+    finished: bool,
+    aborted: bool,
+    failed: bool,
+    paused_since: Option<Instant>,
+    stats: StagingStats,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Buffers a stream in segment files on local disk and drains them into the sink
+/// on a separate thread. The sink is fed in bursts from the high watermark down to
+/// the low watermark, so a source slower than the drive does not make the drive
+/// stop and restart on every small write (shoe-shining). The bytes reaching the
+/// sink are exactly the bytes written, in order.
+pub struct StagingWriter {
+    shared: Arc<Shared>,
+    config: StagingConfig,
+    segment_size: u64,
+    current: Option<(BufWriter<File>, PathBuf, u64)>,
+    next_segment: u64,
+    drain: Option<JoinHandle<Result<()>>>,
+    // Holds the segment files; removed when the writer is dropped
+    dir: TempDir,
+}
+
+impl StagingWriter {
+    pub fn new(config: &StagingConfig, sink: Box<dyn Write + Send>) -> Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        let dir = tempfile::Builder::new().prefix("rumba-staging").tempdir_in(&config.dir)?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                segments: VecDeque::new(),
+                buffered: 0,
+                draining: false,
+                source_waiting: false,
+                finished: false,
+                aborted: false,
+                failed: false,
+                paused_since: None,
+                stats: StagingStats::default(),
+            }),
+            changed: Condvar::new(),
+        });
+
+        let drain = {
+            let shared = shared.clone();
+            let (high, low) = (config.high_watermark, config.low_watermark);
+            std::thread::Builder::new()
+                .name("rumba-staging".to_string())
+                .spawn(move || drain(&shared, high, low, sink))?
+        };
+
+        Ok(Self {
+            shared,
+            config: config.clone(),
+            segment_size: (config.max_size / 16).clamp(1, MAX_SEGMENT_SIZE),
+            current: None,
+            next_segment: 0,
+            drain: Some(drain),
+            dir,
+        })
+    }
+
+    /// Drains the rest of the buffer into the sink, closes it and returns the statistics
+    pub fn finish(mut self) -> Result<StagingStats> {
+        self.seal()?;
+        self.shared.state.lock().unwrap().finished = true;
+        self.shared.changed.notify_all();
+        self.drain.take().unwrap().join().expect("staging drain panicked")?;
+        let stats = self.shared.state.lock().unwrap().stats.clone();
+        Ok(stats)
+    }
+
+    /// Hands the segment being written over to the drain thread
+    fn seal(&mut self) -> io::Result<()> {
+        let Some((file, path, len)) = self.current.take() else { return Ok(()) };
+        file.into_inner().map_err(|e| e.into_error())?;
+        self.shared.state.lock().unwrap().segments.push_back((path, len));
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Reserves room for `n` more bytes, waiting for the drive if the buffer is full
+    fn reserve(&mut self, n: u64) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.buffered + n > self.config.max_size {
+            drop(state);
+            // Everything buffered must be drainable, or the wait would never end
+            self.seal()?;
+            state = self.shared.state.lock().unwrap();
+            let start = Instant::now();
+            state.source_waiting = true;
+            self.shared.changed.notify_all();
+            while state.buffered + n > self.config.max_size && !state.failed {
+                state = self.shared.changed.wait(state).unwrap();
+            }
+            state.source_waiting = false;
+            state.stats.source_waited += start.elapsed();
+        }
+        if state.failed {
+            return Err(io::Error::other("writing the staging buffer to tape failed"));
+        }
+        state.buffered += n;
+        state.stats.peak = state.stats.peak.max(state.buffered);
+        Ok(())
+    }
+}
+
+impl Write for StagingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let room = self.segment_size - self.current.as_ref().map_or(0, |(_, _, len)| *len);
+        let n = buf.len().min(room as usize);
+        self.reserve(n as u64)?;
+
+        if self.current.is_none() {
+            let path = self.dir.path().join(format!("segment-{:08}", self.next_segment));
+            self.next_segment += 1;
+            self.current = Some((BufWriter::new(File::create(&path)?), path, 0));
+        }
+        let (file, _, len) = self.current.as_mut().unwrap();
+        file.write_all(&buf[..n])?;
+        *len += n as u64;
+        if *len >= self.segment_size {
+            self.seal()?;
+        }
+        Ok(n)
+    }
+
+    /// Data only reaches the sink in segments; see `finish`
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for StagingWriter {
+    fn drop(&mut self) {
+        // Dropped without `finish` (the backup failed): stop feeding the drive
+        if let Some(drain) = self.drain.take() {
+            self.shared.state.lock().unwrap().aborted = true;
+            self.shared.changed.notify_all();
+            let _ = drain.join();
+        }
+    }
+}
+
+/// Feeds the sealed segments to the sink between the watermarks
+fn drain(shared: &Shared, high: u64, low: u64, mut sink: Box<dyn Write + Send>) -> Result<()> {
+    let result = (|| -> Result<()> {
+        loop {
+            let (path, size) = {
+                let mut state = shared.state.lock().unwrap();
+                loop {
+                    if state.aborted || (state.finished && state.segments.is_empty()) {
+                        sink.flush()?;
+                        return Ok(());
+                    }
+                    let ready = state.draining || state.finished || state.source_waiting || state.buffered >= high;
+                    if ready && !state.segments.is_empty() {
+                        break;
+                    }
+                    state = shared.changed.wait(state).unwrap();
+                }
+                if !state.draining {
+                    state.draining = true;
+                    if let Some(since) = state.paused_since.take() {
+                        state.stats.drive_starved += since.elapsed();
+                    }
+                }
+                state.segments.pop_front().unwrap()
+            };
+
+            io::copy(&mut File::open(&path)?, &mut sink)?;
+            std::fs::remove_file(&path)?;
+
+            let mut state = shared.state.lock().unwrap();
+            state.buffered -= size;
+            state.stats.bytes += size;
+            if state.buffered <= low && !state.finished {
+                // Let the buffer refill instead of trickling data to the drive
+                state.draining = false;
+                state.stats.drive_stops += 1;
+                state.paused_since = Some(Instant::now());
+            }
+            shared.changed.notify_all();
+        }
+    })();
+
+    if result.is_err() {
+        shared.state.lock().unwrap().failed = true;
+        shared.changed.notify_all();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sink the test can read back after the drain thread is done
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_staging_preserves_stream() -> Result<()> {
+        let dir = TempDir::new()?;
+        let config = StagingConfig {
+            dir: dir.path().join("staging"),
+            max_size: 64 * 1024,
+            high_watermark: 48 * 1024,
+            low_watermark: 8 * 1024,
+        };
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut writer = StagingWriter::new(&config, Box::new(SharedSink(output.clone())))?;
+
+        // Several times the buffer size, in odd-sized writes
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        for chunk in data.chunks(3000) {
+            writer.write_all(chunk)?;
+        }
+        let stats = writer.finish()?;
+
+        assert_eq!(*output.lock().unwrap(), data);
+        assert_eq!(stats.bytes, data.len() as u64);
+        assert!(stats.peak <= config.max_size);
+        // Segment files are cleaned up
+        assert_eq!(std::fs::read_dir(&config.dir)?.count(), 0);
+
+        Ok(())
+    }
+}
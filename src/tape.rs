@@ -1,9 +1,16 @@
 use anyhow::Result;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::process::{Command, Stdio, Child};
+use std::sync::Arc;
 use tar::Builder;
-use std::collections::HashMap;
-use crate::models::{Hash, BlobLocation};
+use crate::models::{BlobLocation, Hash};
+use crate::errors::{ErrorCollector, Operation};
+use crate::interrupt::StopSignal;
+use crate::progress::{NoProgress, ProgressObserver};
+use crate::retry::{RetryPolicy, RetryReader};
+use crate::spill::PlannedBlob;
+use crate::sparse;
+use crate::staging::{StagingConfig, StagingWriter};
 
 pub enum TapeOutput {
     /// Write to rustltfs process via pipe
@@ -12,28 +19,143 @@ pub enum TapeOutput {
     TarFile(std::fs::File),
 }
 
+/// Wraps the output stream and counts the bytes that actually went through it,
+/// so blob offsets stay correct when tar emits extra headers (e.g. GNU long names).
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Where the tar stream goes: straight to the output, or through the staging buffer
+enum Sink {
+    Direct(Box<dyn Write + Send>),
+    Staged(StagingWriter),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::Direct(w) => w.write(buf),
+            Sink::Staged(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Direct(w) => w.flush(),
+            Sink::Staged(w) => w.flush(),
+        }
+    }
+}
+
+/// Tar record size, the smallest block size a tar stream can be cut into
+pub const TAR_RECORD_SIZE: u64 = 512;
+
+/// Approximate size of the tape stream for files of the given sizes: a header and the
+/// padded data per file, the end-of-archive records, and padding to a full block.
+/// Long names and sparse maps add a few records each; holes of sparse files are not written.
+pub fn estimate_stream_size(sizes: impl IntoIterator<Item = u64>, block_size: u64) -> u64 {
+    let records: u64 = sizes.into_iter()
+        .map(|size| 1 + size.div_ceil(TAR_RECORD_SIZE))
+        .sum::<u64>() + 2;
+    (records * TAR_RECORD_SIZE).div_ceil(block_size) * block_size
+}
+
+/// Re-blocks a byte stream into fixed-size writes of `block_size` bytes, as tape
+/// drives expect. The stream must be padded to a multiple of the block size by
+/// the caller; a trailing partial block is never written.
+pub struct BlockWriter<W: Write> {
+    inner: W,
+    block: Vec<u8>,
+    block_size: usize,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(inner: W, block_size: u64) -> Self {
+        let block_size = block_size as usize;
+        Self { inner, block: Vec::with_capacity(block_size), block_size }
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.block_size - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == self.block_size {
+            self.inner.write_all(&self.block)?;
+            self.block.clear();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The error recorded for a file whose content is not the planned one any more
+fn changed_since_hashed(what: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} since it was hashed", what))
+}
+
 pub struct TapeWriter {
     output: TapeOutput,
     tape_id: u64,
     current_offset: u64,
+    staging: Option<StagingConfig>,
+    block_size: u64,
+    progress: Arc<dyn ProgressObserver>,
+    retry: RetryPolicy,
+    stop: StopSignal,
+    errors: ErrorCollector,
+}
+
+/// What `write_blobs` did with the blobs it was given
+pub struct BlobsWritten {
+    pub count: u64,
+    /// Blobs whose file could no longer be opened; they are not in the archive
+    pub skipped: Vec<Hash>,
 }
 
 impl TapeWriter {
     /// Create a new TapeWriter that pipes to rustltfs process
     pub fn new_rustltfs(rustltfs_path: &str, device_path: &str, tape_id: u64) -> Result<Self> {
-        let child = Command::new(rustltfs_path)
+        let mut command = Command::new(rustltfs_path);
+        command
             .arg("write")
             .arg("--device")
             .arg(device_path)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+            .stderr(Stdio::piped());
+        // Ctrl-C goes to the whole process group; rustltfs must live on until
+        // rumba has finished the archive and closes its input
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let child = command.spawn()?;
         
         Ok(Self {
             output: TapeOutput::RustLtfs(child),
             tape_id,
             current_offset: 0,
+            staging: None,
+            block_size: TAR_RECORD_SIZE,
+            progress: Arc::new(NoProgress),
+            retry: RetryPolicy::default(),
+            stop: StopSignal::new(),
+            errors: ErrorCollector::new(),
         })
     }
     
@@ -45,65 +167,208 @@ impl TapeWriter {
             output: TapeOutput::TarFile(file),
             tape_id,
             current_offset: 0,
+            staging: None,
+            block_size: TAR_RECORD_SIZE,
+            progress: Arc::new(NoProgress),
+            retry: RetryPolicy::default(),
+            stop: StopSignal::new(),
+            errors: ErrorCollector::new(),
         })
     }
     
-    /// Write the backup plan to tape/file
-    /// Returns a map of file hashes to their locations on tape
-    pub fn write_plan(&mut self, plan: &crate::pipeline::BackupPlan) -> Result<HashMap<Hash, BlobLocation>> {
-        let mut blob_locations = HashMap::new();
+    /// Writes the stream in fixed blocks of `block_size` bytes (a multiple of 512),
+    /// padding the end of the archive to a full block
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size;
+        self
+    }
+    
+    /// Stages the stream in a local disk buffer before it reaches the output.
+    /// The bytes written, and so the offsets recorded, are the same.
+    pub fn with_staging(mut self, staging: Option<StagingConfig>) -> Self {
+        self.staging = staging;
+        self
+    }
+    
+    /// Reports every blob written to `progress`
+    pub fn with_progress(mut self, progress: Arc<dyn ProgressObserver>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Retries transient errors of opening and reading the source files. A file that
+    /// cannot be opened any more is skipped; one that fails while it is read fails
+    /// the run, as its header is already in the stream.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Once `stop` fires, takes no more blobs: the entry being written is finished
+    /// and the archive is closed as usual
+    pub fn with_stop(mut self, stop: StopSignal) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Records the files that are skipped because they cannot be opened any more
+    pub fn with_errors(mut self, errors: ErrorCollector) -> Self {
+        self.errors = errors;
+        self
+    }
+    
+    pub fn tape_id(&self) -> u64 {
+        self.tape_id
+    }
+
+    /// Position in the stream where the next blob would be written
+    pub fn offset(&self) -> u64 {
+        self.current_offset
+    }
+    
+    /// Streams the planned blobs to tape/file in the order they come.
+    /// `on_written` receives the location of each blob once it is written.
+    /// Returns the number of blobs written and the hashes of those skipped because their
+    /// file vanished or changed since it was hashed.
+    pub fn write_blobs<I, F>(&mut self, blobs: I, mut on_written: F) -> Result<BlobsWritten>
+    where
+        I: IntoIterator<Item = Result<PlannedBlob>>,
+        F: FnMut(&PlannedBlob, BlobLocation) -> Result<()>,
+    {
+        let mut count = 0;
+        let mut skipped = Vec::new();
         
         // Get the writer based on output mode
-        let writer: Box<dyn Write> = match &mut self.output {
+        let writer: Box<dyn Write + Send> = match &mut self.output {
             TapeOutput::RustLtfs(child) => {
-                Box::new(child.stdin.take().expect("Failed to get rustltfs stdin"))
+                Box::new(BlockWriter::new(child.stdin.take().expect("Failed to get rustltfs stdin"), self.block_size))
             }
             TapeOutput::TarFile(file) => {
-                Box::new(file.try_clone()?)
+                Box::new(BlockWriter::new(file.try_clone()?, self.block_size))
             }
         };
         
-        let mut tar_builder = Builder::new(writer);
+        let sink = match &self.staging {
+            Some(staging) => Sink::Staged(StagingWriter::new(staging, writer)?),
+            None => Sink::Direct(writer),
+        };
+        let mut tar_builder = Builder::new(CountingWriter { inner: sink, written: 0 });
+        let base_offset = self.current_offset;
         
-        for (path, hash) in &plan.new_files {
+        for blob in blobs {
+            let blob = blob?;
+            if let Some(reason) = self.stop.reason() {
+                tracing::warn!("Stopping the tape write after {} blobs ({})", count, reason);
+                break;
+            }
+            let (path, hash) = (&blob.path, &blob.hash);
             // Record the current offset before writing
-            let offset = self.current_offset;
-            
-            // Read the file content
-            let content = std::fs::read(path)?;
-            let size = content.len() as u64;
+            let offset = base_offset + tar_builder.get_ref().written;
             
-            // Create a tar header
-            let mut header = tar::Header::new_gnu();
-            header.set_size(size);
-            header.set_mode(0o644);
-            header.set_cksum();
+            // Stream the file content; sparse files only contribute their data extents
+            let mut attempts = self.retry.for_file();
+            // Nothing of the entry is in the stream yet, so a file that vanished or cannot be
+            // opened since it was hashed is left out instead of failing the run
+            let opened = attempts.run(Operation::Open, path, || std::fs::File::open(path))
+                .map_err(|e| (Operation::Open, e))
+                .and_then(|file| match attempts.run(Operation::Stat, path, || file.metadata()) {
+                    Ok(metadata) => Ok((file, metadata.len())),
+                    Err(e) => Err((Operation::Stat, e)),
+                });
+            let (mut file, size) = match opened {
+                Ok(opened) => opened,
+                Err((operation, e)) => {
+                    self.errors.record(path, operation, &e);
+                    skipped.push(*hash);
+                    continue;
+                }
+            };
+            // A file that changed size since it was hashed no longer holds the planned content
+            if size != blob.size {
+                self.errors.record(path, Operation::Stat, &changed_since_hashed(format!("size changed from {} to {} bytes", blob.size, size)));
+                skipped.push(*hash);
+                continue;
+            }
+            let extents = sparse::data_extents(&file, size).unwrap_or_else(|e| {
+                tracing::debug!("Cannot map holes of {:?}: {}", path, e);
+                None
+            });
             
             // Use "original_filename_hash" as tar entry name for content-addressable storage
             let filename = path.file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("unnamed");
+                .map(|n| n.to_string_lossy())
+                .unwrap_or("unnamed".into());
             let hash_str = hex::encode(hash);
             let tar_entry_name = format!("{}_{}", filename, &hash_str[..16]); // Use first 16 chars of hash
             
-            tar_builder.append_data(&mut header, &tar_entry_name, content.as_slice())?;
+            if let Some(extents) = &extents {
+                // The extent map travels in a PAX header right before the data
+                let records = sparse::pax_records(extents, size);
+                let mut pax_header = tar::Header::new_ustar();
+                pax_header.set_entry_type(tar::EntryType::XHeader);
+                pax_header.set_size(records.len() as u64);
+                pax_header.set_mode(0o644);
+                tar_builder.append_data(&mut pax_header, format!("PaxHeaders/{}", &hash_str[..16]), records.as_slice())?;
+            }
             
-            // Calculate new offset (tar adds 512-byte headers and rounds to 512-byte blocks)
-            let header_size = 512u64;
-            let data_blocks = (size + 511) / 512;  // Round up
-            self.current_offset = offset + header_size + (data_blocks * 512);
+            // Create a tar header
+            let stored_size = extents.as_deref().map(sparse::data_size).unwrap_or(size);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(stored_size);
+            header.set_mode(0o644);
+            header.set_cksum();
+            
+            // Exactly `stored_size` bytes must follow the header even if the file shrank meanwhile,
+            // so a short file is zero-padded. The bytes are hashed as they go: content that is not
+            // the planned one stays in the archive but is never recorded under the planned hash.
+            let dense = [sparse::Extent { offset: 0, length: size }];
+            let layout = extents.as_deref().unwrap_or(&dense);
+            let data: Box<dyn Read + '_> = match &extents {
+                Some(extents) => Box::new(sparse::ExtentReader::new(&mut file, extents)),
+                None => Box::new(&mut file),
+            };
+            let data = RetryReader::new(data, &mut attempts, path);
+            let data = data.take(stored_size).chain(std::io::repeat(0)).take(stored_size);
+            let mut data = sparse::HashingReader::new(data, layout, size);
+            tar_builder.append_data(&mut header, &tar_entry_name, &mut data)?;
+            self.current_offset = base_offset + tar_builder.get_ref().written;
+            if data.finish().finalize().as_bytes() != hash {
+                self.errors.record(path, Operation::Read, &changed_since_hashed("content changed".into()));
+                skipped.push(*hash);
+                continue;
+            }
             
             // Record blob location
-            blob_locations.insert(*hash, BlobLocation {
+            on_written(&blob, BlobLocation {
                 tape_id: self.tape_id,
                 offset,
-            });
+                block_size: self.block_size,
+            })?;
+            self.progress.blob_written(size);
+            count += 1;
         }
         
-        // Finish the tar archive
-        tar_builder.finish()?;
+        // Finish the tar archive and pad it to a full block, like `tar -b`
+        let mut counter = tar_builder.into_inner()?;
+        let padding = (self.block_size - counter.written % self.block_size) % self.block_size;
+        std::io::copy(&mut std::io::repeat(0).take(padding), &mut counter)?;
+        self.current_offset = base_offset + counter.written;
+        match counter.inner {
+            Sink::Direct(mut writer) => writer.flush()?,
+            Sink::Staged(staging) => {
+                let stats = staging.finish()?;
+                tracing::info!(
+                    "Staging buffer: {} MiB staged, peak {} MiB, drive paused {} times ({:.1}s without data), source waited {:.1}s for room",
+                    stats.bytes / (1024 * 1024),
+                    stats.peak / (1024 * 1024),
+                    stats.drive_stops,
+                    stats.drive_starved.as_secs_f64(),
+                    stats.source_waited.as_secs_f64()
+                );
+            }
+        }
         
-        Ok(blob_locations)
+        Ok(BlobsWritten { count, skipped })
     }
     
     /// Finish writing and clean up
@@ -126,3 +391,102 @@ impl TapeWriter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the size of every write it receives
+    struct WriteSizes(Vec<usize>);
+
+    impl Write for WriteSizes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_block_writer_emits_fixed_blocks() -> Result<()> {
+        let mut writer = BlockWriter::new(WriteSizes(Vec::new()), 4096);
+        for len in [1, 511, 4096, 3000, 10_000, 2780] {
+            writer.write_all(&vec![7u8; len])?;
+        }
+        // 20_388 bytes: four full blocks, the partial rest waits for padding
+        assert_eq!(writer.inner.0, vec![4096; 4]);
+        writer.write_all(&[0u8; 5 * 4096 - 20_388])?;
+        assert_eq!(writer.inner.0, vec![4096; 5]);
+
+        let location = BlobLocation { tape_id: 1, offset: 3 * 4096 + 1536, block_size: 4096 };
+        assert_eq!(location.block_position(), Some((3, 1536)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate_matches_stream() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let mut blobs = Vec::new();
+        for (i, size) in [0usize, 100, 512, 70_000].into_iter().enumerate() {
+            let path = dir.path().join(format!("file{}", i));
+            let content = vec![i as u8; size];
+            std::fs::write(&path, &content)?;
+            blobs.push(Ok(PlannedBlob { path, hash: crate::object::hash_blob(&content), size: size as u64 }));
+        }
+        let tar_path = dir.path().join("out.tar");
+        let mut writer = TapeWriter::new_tar_file(tar_path.to_str().unwrap(), 1)?.with_block_size(16 * 1024);
+        assert_eq!(writer.write_blobs(blobs, |_, _| Ok(()))?.count, 4);
+        writer.finish()?;
+
+        let estimate = estimate_stream_size([0, 100, 512, 70_000], 16 * 1024);
+        assert_eq!(std::fs::metadata(&tar_path)?.len(), estimate);
+        Ok(())
+    }
+
+    #[test]
+    fn test_vanished_and_changed_files_are_skipped() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let mut blobs = Vec::new();
+        let mut hashes = Vec::new();
+        for i in 0..4u8 {
+            let path = dir.path().join(format!("file{}", i));
+            let content = vec![i; 1000];
+            std::fs::write(&path, &content)?;
+            hashes.push(crate::object::hash_blob(&content));
+            blobs.push(Ok(PlannedBlob { path, hash: hashes[i as usize], size: 1000 }));
+        }
+        // Changed after they were hashed: gone, resized, and edited in place
+        std::fs::remove_file(dir.path().join("file1"))?;
+        std::fs::write(dir.path().join("file2"), vec![2u8; 900])?;
+        std::fs::write(dir.path().join("file3"), vec![7u8; 1000])?;
+
+        let tar_path = dir.path().join("out.tar");
+        let errors = ErrorCollector::new();
+        let mut writer = TapeWriter::new_tar_file(tar_path.to_str().unwrap(), 1)?.with_errors(errors.clone());
+        let mut stored = Vec::new();
+        let written = writer.write_blobs(blobs, |blob, _| {
+            stored.push(blob.hash);
+            Ok(())
+        })?;
+        writer.finish()?;
+
+        assert_eq!(written.count, 1);
+        assert_eq!(written.skipped, hashes[1..].to_vec());
+        assert_eq!(stored, vec![hashes[0]]);
+        let errors: Vec<_> = errors.take().into_iter().map(|e| (e.path, e.operation, e.kind)).collect();
+        assert_eq!(errors, vec![
+            (dir.path().join("file1"), Operation::Open, std::io::ErrorKind::NotFound),
+            (dir.path().join("file2"), Operation::Stat, std::io::ErrorKind::InvalidData),
+            (dir.path().join("file3"), Operation::Read, std::io::ErrorKind::InvalidData),
+        ]);
+
+        // The archive stays valid; the edited file's data is in it but recorded nowhere
+        let mut archive = tar::Archive::new(std::fs::File::open(&tar_path)?);
+        assert_eq!(archive.entries()?.count(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::path::Path;
+use anyhow::Result;
+use tracing::debug;
+use crate::models::{ExtendedAttributes, Xattr};
+
+/// ACL attributes that some file systems do not report through `listxattr`
+/// (CIFS only exposes the NT security descriptor when asked for it by name).
+#[cfg(unix)]
+const ACL_ATTRIBUTES: &[&str] = &[
+    "system.posix_acl_access",
+    "system.posix_acl_default",
+    "system.cifs_acl",
+];
+
+/// Reads all extended attributes and ACLs of `path` without following symlinks.
+/// Returns None if the object has none.
+#[cfg(unix)]
+pub fn read_xattrs(path: &Path) -> Result<Option<ExtendedAttributes>> {
+    let mut attrs: Vec<Xattr> = Vec::new();
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if is_unsupported(&e) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    for name in names {
+        if let Some(value) = xattr::get(path, &name)? {
+            attrs.push(Xattr { name: name.to_string_lossy().to_string(), value });
+        }
+    }
+
+    for name in ACL_ATTRIBUTES {
+        if attrs.iter().any(|a| a.name == *name) {
+            continue;
+        }
+        match xattr::get(path, name) {
+            Ok(Some(value)) => attrs.push(Xattr { name: name.to_string(), value }),
+            Ok(None) => {}
+            Err(e) => debug!("Cannot read {} of {:?}: {}", name, path, e),
+        }
+    }
+
+    if attrs.is_empty() {
+        return Ok(None);
+    }
+    attrs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Some(ExtendedAttributes { attrs }))
+}
+
+#[cfg(not(unix))]
+pub fn read_xattrs(_path: &Path) -> Result<Option<ExtendedAttributes>> {
+    Ok(None)
+}
+
+/// Reapplies extended attributes and ACLs. Attributes the caller may not set
+/// (`trusted.*`, `security.*` without privileges, ACLs on a file system without
+/// ACL support) are skipped with a warning instead of failing the restore.
+#[cfg(unix)]
+pub fn apply_xattrs(path: &Path, xattrs: &ExtendedAttributes) -> Result<()> {
+    for attr in &xattrs.attrs {
+        if let Err(e) = xattr::set(path, &attr.name, &attr.value) {
+            tracing::warn!("Cannot restore {} on {:?}: {}", attr.name, path, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply_xattrs(path: &Path, _xattrs: &ExtendedAttributes) -> Result<()> {
+    debug!("Extended attributes are not supported on this platform, skipping {:?}", path);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_unsupported(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP) || e.kind() == std::io::ErrorKind::Unsupported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_xattr_roundtrip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let source = temp_dir.path().join("source.txt");
+        let target = temp_dir.path().join("target.txt");
+        std::fs::write(&source, "content")?;
+        std::fs::write(&target, "content")?;
+
+        if xattr::set(&source, "user.rumba.b", b"2").is_err() {
+            // File system without user xattr support
+            return Ok(());
+        }
+        xattr::set(&source, "user.rumba.a", b"1")?;
+
+        let attrs = read_xattrs(&source)?.expect("xattrs not captured");
+        let names: Vec<_> = attrs.attrs.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["user.rumba.a", "user.rumba.b"]);
+
+        apply_xattrs(&target, &attrs)?;
+        assert_eq!(read_xattrs(&target)?, Some(attrs));
+
+        Ok(())
+    }
+}